@@ -0,0 +1,57 @@
+//! Replays a workload recorded with `RecordingTransport` against the mock transport.
+//!
+//! Set `TONLIB_BENCH_FIXTURES` to the path of the recorded fixtures
+
+use std::sync::Arc;
+use std::time::Duration;
+
+use criterion::{criterion_group, criterion_main, Criterion};
+use tonlib::{
+    run_workload, Config, Fixtures, LastBlock, LiteServerConfig, LoadConfig, ReplayContext, ReplayTransport, TonlibClient, Workload,
+};
+
+fn replay_workload(c: &mut Criterion) {
+    let path = match std::env::var("TONLIB_BENCH_FIXTURES") {
+        Ok(path) => path,
+        Err(_) => {
+            eprintln!("TONLIB_BENCH_FIXTURES is not set, skipping");
+            return;
+        }
+    };
+
+    let fixtures = Fixtures::load(path).unwrap();
+    let workload = Workload::from_fixtures(&fixtures).unwrap();
+
+    let runtime = tokio::runtime::Runtime::new().unwrap();
+    let client = runtime.block_on(async {
+        let config = Config {
+            servers: vec![LiteServerConfig {
+                // Address and key are not used by the mock transport
+                address: "127.0.0.1:3031".parse().unwrap(),
+                key: "uNRRL+6enQjuiZ/s6Z+vO7yxUUR7uxdfzIy+RxkECrc=".to_owned(),
+            }],
+            ..Default::default()
+        };
+        let last_block = Arc::new(LastBlock::new(&config.last_block_threshold));
+        let context = ReplayContext::new(&fixtures).unwrap();
+        TonlibClient::<ReplayTransport>::with_transport(&config, last_block, context)
+            .await
+            .unwrap()
+    });
+
+    let mut group = c.benchmark_group("replay");
+    group.measurement_time(Duration::from_secs(10));
+    for concurrency in [1, 4, 16] {
+        let config = LoadConfig {
+            concurrency,
+            iterations: 1,
+        };
+        group.bench_function(format!("concurrency_{}", concurrency), |b| {
+            b.to_async(&runtime).iter(|| run_workload(&client, &workload, config))
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(benches, replay_workload);
+criterion_main!(benches);
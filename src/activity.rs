@@ -0,0 +1,152 @@
+use std::collections::VecDeque;
+use std::time::Duration;
+
+use anyhow::Result;
+use ton_block::Transaction;
+use ton_types::UInt256;
+
+use crate::export::{transaction_rows, Direction};
+
+/// Sliding window analytics of the account transactions
+#[derive(Debug, Clone)]
+pub struct AccountActivity {
+    window: Duration,
+    /// Transaction time and the account balance change, ordered by time
+    entries: VecDeque<(u32, i128)>,
+}
+
+/// Account activity over the window
+#[derive(Debug, Copy, Clone, Default, PartialEq)]
+pub struct ActivitySummary {
+    pub transactions: usize,
+    pub transactions_per_hour: f64,
+    /// Incoming value minus outgoing value and fees
+    pub net_value_flow: i128,
+}
+
+impl AccountActivity {
+    pub fn new(window: Duration) -> Self {
+        Self {
+            window,
+            entries: VecDeque::new(),
+        }
+    }
+
+    pub fn window(&self) -> Duration {
+        self.window
+    }
+
+    /// Adds transaction to the window. Transactions are expected to be recorded in order
+    pub fn record(&mut self, hash: &UInt256, transaction: &Transaction) -> Result<()> {
+        let delta = transaction_rows(hash, transaction)?
+            .into_iter()
+            .map(|row| match row.direction {
+                Direction::In => row.value as i128 - row.fees as i128,
+                Direction::Out => -(row.value as i128) - row.fees as i128,
+            })
+            .sum();
+        self.push(transaction.now, delta);
+        Ok(())
+    }
+
+    /// Computes activity over the window ending at `now` (unix time in seconds)
+    pub fn summary(&self, now: u32) -> ActivitySummary {
+        let since = self.window_start(now);
+
+        let (transactions, net_value_flow) = self
+            .entries
+            .iter()
+            .filter(|(time, _)| *time >= since && *time <= now)
+            .fold((0, 0), |(count, flow), (_, delta)| (count + 1, flow + delta));
+
+        let hours = self.window.as_secs_f64() / 3600.0;
+        let transactions_per_hour = if hours > 0.0 { transactions as f64 / hours } else { 0.0 };
+
+        ActivitySummary {
+            transactions,
+            transactions_per_hour,
+            net_value_flow,
+        }
+    }
+
+    fn push(&mut self, time: u32, delta: i128) {
+        self.entries.push_back((time, delta));
+
+        let since = self.window_start(time);
+        while matches!(self.entries.front(), Some((time, _)) if *time < since) {
+            self.entries.pop_front();
+        }
+    }
+
+    fn window_start(&self, now: u32) -> u32 {
+        now.saturating_sub(self.window.as_secs() as u32)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use ton_block::{AccountStatus, CurrencyCollection, ExternalInboundMessageHeader, InternalMessageHeader, Message, MsgAddressInt};
+
+    use super::*;
+
+    fn address(byte: u8) -> MsgAddressInt {
+        MsgAddressInt::with_standart(None, 0, UInt256::from([byte; 32]).into()).unwrap()
+    }
+
+    fn internal_message(src: u8, dst: u8, value: u64) -> Message {
+        Message::with_int_header(InternalMessageHeader::with_addresses_and_bounce(
+            address(src),
+            address(dst),
+            CurrencyCollection::with_grams(value),
+            true,
+        ))
+    }
+
+    fn make_transaction(now: u32, in_msg: &Message, out_msgs: &[Message]) -> Transaction {
+        let mut transaction = Transaction::with_address_and_status(UInt256::from([2; 32]).into(), AccountStatus::AccStateActive);
+        transaction.set_now(now);
+        transaction.set_total_fees(CurrencyCollection::with_grams(5));
+        transaction.write_in_msg(Some(in_msg)).unwrap();
+        for message in out_msgs {
+            transaction.add_out_message(message).unwrap();
+        }
+        transaction
+    }
+
+    #[test]
+    fn sliding_window() {
+        let mut activity = AccountActivity::new(Duration::from_secs(7200));
+        activity.push(1000, 500);
+        activity.push(5000, -200);
+        activity.push(9000, 100);
+
+        assert_eq!(activity.entries.len(), 2);
+
+        let summary = activity.summary(9000);
+        assert_eq!(summary.transactions, 2);
+        assert_eq!(summary.transactions_per_hour, 1.0);
+        assert_eq!(summary.net_value_flow, -100);
+
+        assert_eq!(activity.summary(20000), ActivitySummary::default());
+    }
+
+    #[test]
+    fn recorded_transactions() {
+        let mut activity = AccountActivity::new(Duration::from_secs(3600));
+
+        let transfer = make_transaction(1000, &internal_message(1, 2, 100), &[internal_message(2, 3, 30)]);
+        activity.record(&UInt256::default(), &transfer).unwrap();
+
+        let external = make_transaction(2000, &Message::with_ext_in_header(ExternalInboundMessageHeader::default()), &[]);
+        activity.record(&UInt256::default(), &external).unwrap();
+
+        // Fees are paid once per transaction
+        let summary = activity.summary(2000);
+        assert_eq!(summary.transactions, 2);
+        assert_eq!(summary.net_value_flow, 100 - 30 - 5 - 5);
+
+        let summary = activity.summary(4700);
+        assert_eq!(summary.transactions, 1);
+        assert_eq!(summary.net_value_flow, -5);
+    }
+}
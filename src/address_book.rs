@@ -0,0 +1,138 @@
+use std::collections::BTreeMap;
+use std::path::Path;
+use std::str::FromStr;
+
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+use ton_block::MsgAddressInt;
+
+use crate::errors::TonlibError;
+
+/// Human readable labels of the addresses, used to annotate the output of the tools
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(transparent)]
+pub struct AddressBook {
+    /// Labels by raw addresses (`workchain:hex`)
+    labels: BTreeMap<String, String>,
+}
+
+impl AddressBook {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Address book with the masterchain system contracts
+    pub fn with_well_known() -> Self {
+        let mut book = Self::new();
+        for (address, label) in WELL_KNOWN {
+            book.labels.insert(address.to_string(), label.to_string());
+        }
+        book
+    }
+
+    /// Reads address book from the JSON file.
+    ///
+    /// Addresses are converted to the raw form, so that the edited files match the lookups.
+    /// Fails on invalid addresses
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Self> {
+        let file = std::fs::File::open(path)?;
+        let labels: BTreeMap<String, String> = serde_json::from_reader(std::io::BufReader::new(file))?;
+
+        let labels = labels
+            .into_iter()
+            .map(|(address, label)| {
+                let address = MsgAddressInt::from_str(address.trim()).map_err(|_| TonlibError::InvalidAddress)?;
+                Ok((address.to_string(), label))
+            })
+            .collect::<Result<_>>()?;
+        Ok(Self { labels })
+    }
+
+    /// Writes address book to the JSON file
+    pub fn save<P: AsRef<Path>>(&self, path: P) -> Result<()> {
+        let file = std::fs::File::create(path)?;
+        serde_json::to_writer_pretty(file, self)?;
+        Ok(())
+    }
+
+    pub fn len(&self) -> usize {
+        self.labels.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.labels.is_empty()
+    }
+
+    /// Sets label of the address. Returns the previous one
+    pub fn insert<L: Into<String>>(&mut self, address: &MsgAddressInt, label: L) -> Option<String> {
+        self.labels.insert(address.to_string(), label.into())
+    }
+
+    pub fn remove(&mut self, address: &MsgAddressInt) -> Option<String> {
+        self.labels.remove(&address.to_string())
+    }
+
+    pub fn label(&self, address: &MsgAddressInt) -> Option<&str> {
+        self.labels.get(&address.to_string()).map(String::as_str)
+    }
+
+    /// Formats address with its label, e.g. `Elector (-1:3333...)`
+    pub fn annotate(&self, address: &MsgAddressInt) -> String {
+        match self.label(address) {
+            Some(label) => format!("{} ({})", label, address),
+            None => address.to_string(),
+        }
+    }
+
+    /// Iterates over labeled addresses in the raw form
+    pub fn iter(&self) -> impl Iterator<Item = (&str, &str)> {
+        self.labels.iter().map(|(address, label)| (address.as_str(), label.as_str()))
+    }
+}
+
+const WELL_KNOWN: [(&str, &str); 3] = [
+    ("-1:3333333333333333333333333333333333333333333333333333333333333333", "Elector"),
+    ("-1:5555555555555555555555555555555555555555555555555555555555555555", "Config"),
+    ("-1:0000000000000000000000000000000000000000000000000000000000000000", "Minter"),
+];
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn labels_roundtrip() {
+        let elector = MsgAddressInt::from_str(WELL_KNOWN[0].0).unwrap();
+        let wallet = MsgAddressInt::from_str("0:1111111111111111111111111111111111111111111111111111111111111111").unwrap();
+
+        let mut book = AddressBook::with_well_known();
+        assert_eq!(book.label(&elector), Some("Elector"));
+        assert_eq!(book.insert(&wallet, "my hot wallet"), None);
+
+        let file = tempfile::NamedTempFile::new().unwrap();
+        book.save(file.path()).unwrap();
+        let book = AddressBook::load(file.path()).unwrap();
+
+        assert_eq!(book.len(), 4);
+        assert_eq!(book.annotate(&wallet), format!("my hot wallet ({})", wallet));
+        let unknown = MsgAddressInt::from_str("0:2222222222222222222222222222222222222222222222222222222222222222").unwrap();
+        assert_eq!(book.annotate(&unknown), unknown.to_string());
+    }
+
+    #[test]
+    fn normalized_on_load() {
+        let file = tempfile::NamedTempFile::new().unwrap();
+        std::fs::write(
+            file.path(),
+            r#"{" 0:AAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAA": "cold wallet"}"#,
+        )
+        .unwrap();
+        let book = AddressBook::load(file.path()).unwrap();
+
+        let wallet = MsgAddressInt::from_str("0:aaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaa").unwrap();
+        assert_eq!(book.label(&wallet), Some("cold wallet"));
+
+        std::fs::write(file.path(), r#"{"not an address": "label"}"#).unwrap();
+        assert!(AddressBook::load(file.path()).is_err());
+    }
+}
@@ -0,0 +1,193 @@
+use std::collections::{HashSet, VecDeque};
+
+use anyhow::Result;
+use futures::StreamExt;
+use ton_api::ton::ton_node::blockidext::BlockIdExt;
+use ton_block::{Block, Deserializable, HashmapAugType, Transaction};
+use ton_types::UInt256;
+
+use crate::block_stream::{BlockEvent, BlockStream, BlockStreamConfig};
+use crate::errors::*;
+use crate::transport::AdnlTransport;
+use crate::{AsStdAddr, TonlibClient};
+
+/// Transactions of the watched accounts in the block
+#[derive(Debug, Clone)]
+pub struct AccountTransactions {
+    pub block_id: BlockIdExt,
+    pub workchain: i32,
+    pub account: UInt256,
+    /// Transaction hashes and transactions in the order of logical time
+    pub transactions: Vec<(UInt256, Transaction)>,
+}
+
+#[derive(Debug, Clone)]
+pub enum AddressSetEvent {
+    Transactions(AccountTransactions),
+    /// Same as `BlockEvent::Final`
+    Final {
+        seqno: u32,
+    },
+    /// Same as `BlockEvent::Reorg`
+    Reorg {
+        from_seqno: u32,
+    },
+}
+
+/// Watches a large set of accounts by scanning streamed blocks instead of polling each account.
+///
+/// Streamed blocks already contain all transactions, so only account blocks of the watched
+/// accounts are parsed. `liteServer.listBlockTransactions` is not used: the stream downloads
+/// full shard blocks anyway to follow splits and merges, so listing the transactions and fetching
+/// the matching ones would only add round trips.
+///
+/// Accounts can be added or removed between the calls of `next`
+pub struct AddressSetWatcher<'a> {
+    stream: BlockStream<'a>,
+    accounts: HashSet<(i32, UInt256)>,
+    pending: VecDeque<AddressSetEvent>,
+}
+
+impl<'a> AddressSetWatcher<'a> {
+    /// Starts scanning from the masterchain block with the given seqno
+    pub fn new<A>(client: &'a TonlibClient<A>, seqno: u32, config: BlockStreamConfig) -> Self
+    where
+        A: AdnlTransport,
+    {
+        Self {
+            stream: BlockStream::new(client, seqno, config),
+            accounts: HashSet::new(),
+            pending: VecDeque::new(),
+        }
+    }
+
+    /// Returns false if the account was already watched
+    pub fn insert<T: AsStdAddr>(&mut self, account: &T) -> bool {
+        self.accounts.insert((account.workchain_id(), account.address()))
+    }
+
+    /// Returns false if the account was not watched
+    pub fn remove<T: AsStdAddr>(&mut self, account: &T) -> bool {
+        self.accounts.remove(&(account.workchain_id(), account.address()))
+    }
+
+    pub fn len(&self) -> usize {
+        self.accounts.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.accounts.is_empty()
+    }
+
+    /// Waits for the next event. Blocks without transactions of the watched accounts are skipped.
+    ///
+    /// Returns `None` after the block stream has failed
+    pub async fn next(&mut self) -> Option<Result<AddressSetEvent>> {
+        loop {
+            if let Some(event) = self.pending.pop_front() {
+                return Some(Ok(event));
+            }
+
+            match self.stream.next().await? {
+                Ok(BlockEvent::Block { id, block }) => match self.scan_block(id, &block) {
+                    Ok(events) => self.pending = events,
+                    Err(e) => return Some(Err(e.into())),
+                },
+                Ok(BlockEvent::Final { seqno }) => return Some(Ok(AddressSetEvent::Final { seqno })),
+                Ok(BlockEvent::Reorg { from_seqno }) => return Some(Ok(AddressSetEvent::Reorg { from_seqno })),
+                Err(e) => return Some(Err(e)),
+            }
+        }
+    }
+
+    fn scan_block(&self, block_id: BlockIdExt, block: &Block) -> TonlibResult<VecDeque<AddressSetEvent>> {
+        let workchain = block_id.workchain;
+
+        let mut events = VecDeque::new();
+        for (account, transactions) in filter_block_transactions(block, |account| self.accounts.contains(&(workchain, *account)))? {
+            events.push_back(AddressSetEvent::Transactions(AccountTransactions {
+                block_id: block_id.clone(),
+                workchain,
+                account,
+                transactions,
+            }));
+        }
+        Ok(events)
+    }
+}
+
+/// Parses transactions of the accounts accepted by the filter.
+///
+/// Transactions are ordered by logical time, their hashes are taken from the block cells
+pub fn filter_block_transactions<F>(block: &Block, mut filter: F) -> TonlibResult<Vec<(UInt256, Vec<(UInt256, Transaction)>)>>
+where
+    F: FnMut(&UInt256) -> bool,
+{
+    let account_blocks = block
+        .read_extra()
+        .and_then(|extra| extra.read_account_blocks())
+        .map_err(|_| TonlibError::InvalidBlock)?;
+
+    let mut result = Vec::new();
+    account_blocks
+        .iterate_with_keys(|account: UInt256, account_block: ton_block::AccountBlock| {
+            if !filter(&account) {
+                return Ok(true);
+            }
+
+            // Transaction cell is the last reference of the dictionary leaf
+            let mut transactions = Vec::new();
+            account_block.transactions().iterate_slices(|_, value| {
+                let cell = value.reference(value.remaining_references().saturating_sub(1))?;
+                transactions.push((cell.repr_hash(), Transaction::construct_from_cell(cell)?));
+                Ok(true)
+            })?;
+            result.push((account, transactions));
+            Ok(true)
+        })
+        .map_err(|_| TonlibError::InvalidBlock)?;
+
+    Ok(result)
+}
+
+#[cfg(test)]
+mod tests {
+    use ton_block::{AccountStatus, BlockExtra, Serializable, ShardAccountBlocks};
+
+    use super::*;
+
+    fn make_transaction(account: u8, lt: u64) -> (Transaction, ton_types::Cell) {
+        let mut transaction = Transaction::with_address_and_status(UInt256::from([account; 32]).into(), AccountStatus::AccStateActive);
+        transaction.set_logical_time(lt);
+        let cell = transaction.serialize().unwrap();
+        (transaction, cell)
+    }
+
+    #[test]
+    fn watched_transactions() {
+        let transactions = [make_transaction(1, 20), make_transaction(2, 15), make_transaction(1, 10)];
+
+        let mut account_blocks = ShardAccountBlocks::default();
+        for (transaction, cell) in &transactions {
+            account_blocks.add_serialized_transaction(transaction, cell).unwrap();
+        }
+        let mut extra = BlockExtra::default();
+        extra.write_account_blocks(&account_blocks).unwrap();
+        let mut block = Block::default();
+        block.write_extra(&extra).unwrap();
+
+        let watched = UInt256::from([1; 32]);
+        let result = filter_block_transactions(&block, |account| account == &watched).unwrap();
+        assert_eq!(result.len(), 1);
+
+        let (account, found) = &result[0];
+        assert_eq!(account, &watched);
+        assert_eq!(
+            found.iter().map(|(hash, transaction)| (*hash, transaction.lt)).collect::<Vec<_>>(),
+            vec![(transactions[2].1.repr_hash(), 10), (transactions[0].1.repr_hash(), 20)]
+        );
+
+        assert!(filter_block_transactions(&block, |_| false).unwrap().is_empty());
+        assert!(filter_block_transactions(&Block::default(), |_| true).unwrap().is_empty());
+    }
+}
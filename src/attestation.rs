@@ -0,0 +1,117 @@
+use std::convert::TryFrom;
+
+use ed25519_dalek::{Keypair, PublicKey, Signature, Signer, Verifier};
+use ton_api::ton::ton_node::blockidext::BlockIdExt;
+use ton_types::UInt256;
+
+use crate::errors::*;
+
+/// Signs read results with the service key
+pub struct Attestor {
+    keypair: Keypair,
+}
+
+impl Attestor {
+    pub fn new(keypair: Keypair) -> Self {
+        Self { keypair }
+    }
+
+    pub fn public_key(&self) -> PublicKey {
+        self.keypair.public
+    }
+
+    /// Signs the record fields. The signature of the record is ignored
+    pub fn sign(&self, record: &mut AttestedAccountState) {
+        record.signature = self.keypair.sign(&record.signed_data()).to_bytes().to_vec();
+    }
+}
+
+/// Verified account balance at the block, signed by the service key
+#[derive(Debug, Clone)]
+pub struct AttestedAccountState {
+    pub workchain: i32,
+    pub address: UInt256,
+    /// Masterchain block of the query
+    pub block_id: BlockIdExt,
+    /// Shard block with the account state
+    pub shard_block_id: BlockIdExt,
+    pub gen_utime: u32,
+    pub last_trans_lt: u64,
+    /// Balance in nanotons, zero for the non-existing account
+    pub balance: u128,
+    /// Ed25519 signature of the fields above
+    pub signature: Vec<u8>,
+}
+
+impl AttestedAccountState {
+    /// Checks the signature of the record
+    pub fn verify(&self, public_key: &PublicKey) -> TonlibResult<()> {
+        let signature = Signature::try_from(self.signature.as_slice()).map_err(|_| TonlibError::InvalidSignature)?;
+        public_key
+            .verify(&self.signed_data(), &signature)
+            .map_err(|_| TonlibError::InvalidSignature)
+    }
+
+    /// Fixed layout of the signed fields with the domain prefix
+    fn signed_data(&self) -> Vec<u8> {
+        let mut data = Vec::with_capacity(256);
+        data.extend_from_slice(SIGNATURE_DOMAIN);
+        data.extend_from_slice(&self.workchain.to_be_bytes());
+        data.extend_from_slice(self.address.as_slice());
+        write_block_id(&mut data, &self.block_id);
+        write_block_id(&mut data, &self.shard_block_id);
+        data.extend_from_slice(&self.gen_utime.to_be_bytes());
+        data.extend_from_slice(&self.last_trans_lt.to_be_bytes());
+        data.extend_from_slice(&self.balance.to_be_bytes());
+        data
+    }
+}
+
+const SIGNATURE_DOMAIN: &[u8] = b"tonlib.attestedAccountState";
+
+fn write_block_id(data: &mut Vec<u8>, id: &BlockIdExt) {
+    data.extend_from_slice(&id.workchain.to_be_bytes());
+    data.extend_from_slice(&id.shard.to_be_bytes());
+    data.extend_from_slice(&id.seqno.to_be_bytes());
+    data.extend_from_slice(&id.root_hash.0);
+    data.extend_from_slice(&id.file_hash.0);
+}
+
+#[cfg(test)]
+mod tests {
+    use ton_api::ton;
+
+    use super::*;
+
+    #[test]
+    fn sign_and_verify() {
+        let secret = ed25519_dalek::SecretKey::from_bytes(&[7; 32]).unwrap();
+        let public = PublicKey::from(&secret);
+        let attestor = Attestor::new(Keypair { secret, public });
+
+        let block_id = BlockIdExt {
+            workchain: -1,
+            shard: ton_block::SHARD_FULL as i64,
+            seqno: 100,
+            root_hash: ton::int256([1; 32]),
+            file_hash: ton::int256([2; 32]),
+        };
+        let mut record = AttestedAccountState {
+            workchain: 0,
+            address: UInt256::from([3; 32]),
+            block_id: block_id.clone(),
+            shard_block_id: block_id,
+            gen_utime: 1_600_000_000,
+            last_trans_lt: 123,
+            balance: 1_000_000_000,
+            signature: Vec::new(),
+        };
+        assert!(record.verify(&attestor.public_key()).is_err());
+
+        attestor.sign(&mut record);
+        assert!(record.verify(&attestor.public_key()).is_ok());
+
+        record.balance += 1;
+        assert!(record.verify(&attestor.public_key()).is_err());
+    }
+}
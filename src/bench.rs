@@ -0,0 +1,119 @@
+//! Load generator which replays a recorded query workload,
+//! e.g. to size the connection pool or to compare transports
+
+use std::time::{Duration, Instant};
+
+use anyhow::Result;
+use futures::StreamExt;
+use ton_api::ton;
+
+use crate::replay::Fixtures;
+use crate::stats::LatencySummary;
+use crate::transport::AdnlTransport;
+use crate::TonlibClient;
+
+/// Raw lite server queries in the order they are sent
+pub struct Workload {
+    requests: Vec<ton::TLObject>,
+}
+
+impl Workload {
+    /// Uses requests recorded by `RecordingTransport`
+    pub fn from_fixtures(fixtures: &Fixtures) -> Result<Self> {
+        Ok(Self {
+            requests: fixtures.requests()?,
+        })
+    }
+
+    pub fn len(&self) -> usize {
+        self.requests.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.requests.is_empty()
+    }
+}
+
+#[derive(Debug, Copy, Clone)]
+pub struct LoadConfig {
+    /// Number of the queries in flight
+    pub concurrency: usize,
+    /// Number of the workload passes
+    pub iterations: usize,
+}
+
+impl Default for LoadConfig {
+    fn default() -> Self {
+        Self {
+            concurrency: 1,
+            iterations: 1,
+        }
+    }
+}
+
+#[derive(Debug, Copy, Clone, Default)]
+pub struct LoadReport {
+    /// Number of the sent queries
+    pub queries: usize,
+    /// Queries failed on the transport level. Lite server errors are counted as responses
+    pub errors: usize,
+    pub elapsed: Duration,
+    /// Latency of the successful queries, including the connection checkout
+    pub latency: LatencySummary,
+}
+
+impl LoadReport {
+    /// Successful queries per second
+    pub fn throughput(&self) -> f64 {
+        let secs = self.elapsed.as_secs_f64();
+        if secs > 0.0 {
+            (self.queries - self.errors) as f64 / secs
+        } else {
+            0.0
+        }
+    }
+}
+
+/// Sends the workload through the client connection pool and measures latencies.
+///
+/// Queries bypass the client bookkeeping: failures are not reported to the circuit breaker,
+/// and the traffic is not counted in `Stats`, so the load doesn't affect the server health
+pub async fn run_workload<A>(client: &TonlibClient<A>, workload: &Workload, config: LoadConfig) -> Result<LoadReport>
+where
+    A: AdnlTransport,
+{
+    let requests = (0..config.iterations).flat_map(|_| workload.requests.iter());
+
+    let started_at = Instant::now();
+    let results = futures::stream::iter(requests)
+        .map(|request| async move {
+            let started_at = Instant::now();
+            let connection = client.acquire_connection().await?;
+            let _permit = connection.acquire_permit().await?;
+            connection.send_query(request).await?;
+            Ok::<_, anyhow::Error>(started_at.elapsed())
+        })
+        .buffer_unordered(std::cmp::max(config.concurrency, 1))
+        .collect::<Vec<_>>()
+        .await;
+    let elapsed = started_at.elapsed();
+
+    let mut samples = Vec::with_capacity(results.len());
+    let mut errors = 0;
+    for result in results {
+        match result {
+            Ok(latency) => samples.push(latency),
+            Err(e) => {
+                log::debug!("Workload query failed: {:?}", e);
+                errors += 1;
+            }
+        }
+    }
+
+    Ok(LoadReport {
+        queries: samples.len() + errors,
+        errors,
+        elapsed,
+        latency: LatencySummary::from_samples(&mut samples),
+    })
+}
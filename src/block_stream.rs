@@ -0,0 +1,507 @@
+use std::collections::{HashSet, VecDeque};
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use std::time::Duration;
+
+use anyhow::Result;
+use futures::stream::BoxStream;
+use futures::{Stream, StreamExt};
+use ton_api::ton;
+use ton_api::ton::ton_node::blockidext::BlockIdExt;
+use ton_block::{Block, ExtBlkRef, ShardIdent};
+use ton_types::UInt256;
+
+use crate::errors::*;
+use crate::transport::AdnlTransport;
+use crate::TonlibClient;
+
+#[derive(Debug, Copy, Clone)]
+pub struct BlockStreamConfig {
+    /// Number of masterchain blocks downloaded in advance
+    pub lookahead: usize,
+    /// Interval of the last block polling when the stream reaches it
+    pub poll_interval: Duration,
+    /// Number of the following masterchain blocks after which the block is reported as final.
+    ///
+    /// Masterchain blocks are final once they are signed by validators, so by default any
+    /// divergence is treated as an error instead of a reorg
+    pub finality_depth: u32,
+}
+
+impl Default for BlockStreamConfig {
+    fn default() -> Self {
+        Self {
+            lookahead: 4,
+            poll_interval: Duration::from_secs(1),
+            finality_depth: 0,
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub enum BlockEvent {
+    /// Masterchain or shard block
+    Block { id: BlockIdExt, block: Block },
+    /// Masterchain block with this seqno and all blocks committed by it are final
+    Final { seqno: u32 },
+    /// Blocks committed by the masterchain blocks starting from this seqno were replaced
+    /// and will be streamed again
+    Reorg { from_seqno: u32 },
+}
+
+/// Yields masterchain blocks starting from the given seqno together with all new shard blocks
+/// referenced by them.
+///
+/// Shard blocks go before the masterchain block which commits them, parents before children,
+/// so splits and merges are followed. Up to `lookahead` masterchain blocks are downloaded in advance,
+/// nothing is downloaded while the consumer doesn't poll the stream.
+///
+/// The stream waits for new blocks when it reaches the last one and ends after the first error.
+/// It can be restarted from the seqno of the last final masterchain block plus one
+pub struct BlockStream<'a> {
+    inner: BoxStream<'a, Result<BlockEvent>>,
+}
+
+impl<'a> BlockStream<'a> {
+    pub fn new<A>(client: &'a TonlibClient<A>, seqno: u32, config: BlockStreamConfig) -> Self
+    where
+        A: AdnlTransport,
+    {
+        let state = StreamState {
+            client,
+            config,
+            masterchain: masterchain_blocks(client, seqno, config),
+            prev_tops: None,
+            history: VecDeque::new(),
+            last_final: None,
+            pending: VecDeque::new(),
+            failed: false,
+        };
+
+        let inner = futures::stream::unfold(state, |mut state| async move {
+            if let Some(event) = state.pending.pop_front() {
+                return Some((Ok(event), state));
+            }
+            if state.failed {
+                return None;
+            }
+
+            let result = match state.masterchain.next().await? {
+                Ok((id, block)) => state.process(id, block).await,
+                Err(e) => Err(e),
+            };
+
+            match result {
+                Ok(()) => {
+                    let event = state.pending.pop_front()?;
+                    Some((Ok(event), state))
+                }
+                Err(e) => {
+                    state.failed = true;
+                    Some((Err(e), state))
+                }
+            }
+        })
+        .boxed();
+
+        Self { inner }
+    }
+}
+
+impl Stream for BlockStream<'_> {
+    type Item = Result<BlockEvent>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        self.inner.as_mut().poll_next(cx)
+    }
+}
+
+/// Workchain, shard and seqno of the block
+type BlockKey = (i32, u64, i32);
+
+struct StreamState<'a, A: AdnlTransport> {
+    client: &'a TonlibClient<A>,
+    config: BlockStreamConfig,
+    masterchain: BoxStream<'a, Result<(BlockIdExt, Block)>>,
+    /// Top shard blocks of the previous masterchain block
+    prev_tops: Option<HashSet<BlockKey>>,
+    /// Seqnos and root hashes of the non-final masterchain blocks and the last final one
+    history: VecDeque<(u32, UInt256)>,
+    last_final: Option<u32>,
+    pending: VecDeque<BlockEvent>,
+    failed: bool,
+}
+
+impl<'a, A> StreamState<'a, A>
+where
+    A: AdnlTransport,
+{
+    async fn process(&mut self, id: BlockIdExt, block: Block) -> Result<()> {
+        let seqno = id.seqno as u32;
+        let tops = shard_tops(&block)?;
+
+        let prev_tops = match self.prev_tops.take() {
+            Some(prev_tops) => prev_tops,
+            None if seqno > 0 => {
+                let (prev_id, prev_block) = get_masterchain_block(self.client, seqno - 1, self.config.poll_interval).await?;
+                if self.history.is_empty() {
+                    self.history.push_back((seqno - 1, UInt256::from(prev_id.root_hash.0)));
+                }
+                shard_tops(&prev_block)?.iter().map(block_key).collect()
+            }
+            None => HashSet::new(),
+        };
+
+        let diverged = match self.history.back() {
+            Some((prev_seqno, prev_hash)) => *prev_seqno + 1 == seqno && prev_block_hash(&block)? != *prev_hash,
+            None => false,
+        };
+        if diverged {
+            return self.rewind(seqno - 1);
+        }
+
+        for (id, block) in new_shard_blocks(self.client, &tops, &prev_tops).await? {
+            self.pending.push_back(BlockEvent::Block { id, block });
+        }
+
+        self.history.push_back((seqno, UInt256::from(id.root_hash.0)));
+        self.pending.push_back(BlockEvent::Block { id, block });
+        self.prev_tops = Some(tops.iter().map(block_key).collect());
+
+        let final_seqno = seqno.checked_sub(self.config.finality_depth);
+        if let Some(final_seqno) = final_seqno.filter(|final_seqno| self.last_final < Some(*final_seqno)) {
+            self.last_final = Some(final_seqno);
+            self.pending.push_back(BlockEvent::Final { seqno: final_seqno });
+            while matches!(self.history.front(), Some((seqno, _)) if *seqno < final_seqno) {
+                self.history.pop_front();
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Restarts the stream from the diverged masterchain block
+    fn rewind(&mut self, from_seqno: u32) -> Result<()> {
+        // The first entry of the history is final
+        if self.history.len() < 2 {
+            return Err(TonlibError::InvalidBlock.into());
+        }
+        self.history.pop_back();
+
+        log::warn!("Masterchain diverged, restarting from the block {}", from_seqno);
+        self.masterchain = masterchain_blocks(self.client, from_seqno, self.config);
+        self.prev_tops = None;
+        self.pending.push_back(BlockEvent::Reorg { from_seqno });
+        Ok(())
+    }
+}
+
+fn masterchain_blocks<A>(client: &TonlibClient<A>, seqno: u32, config: BlockStreamConfig) -> BoxStream<'_, Result<(BlockIdExt, Block)>>
+where
+    A: AdnlTransport,
+{
+    futures::stream::iter(seqno..)
+        .map(move |seqno| get_masterchain_block(client, seqno, config.poll_interval))
+        .buffered(std::cmp::max(config.lookahead, 1))
+        .boxed()
+}
+
+async fn get_masterchain_block<A>(client: &TonlibClient<A>, seqno: u32, poll_interval: Duration) -> Result<(BlockIdExt, Block)>
+where
+    A: AdnlTransport,
+{
+    let last_block_id = loop {
+        let last_block_id = client.last_block.get_last_block(&client.pool).await?;
+        if last_block_id.seqno as u32 >= seqno {
+            break last_block_id;
+        }
+        tokio::time::sleep(poll_interval).await;
+    };
+
+    let id = if last_block_id.seqno as u32 == seqno {
+        last_block_id
+    } else {
+        client
+            .lookup_block(ton::ton_node::blockid::BlockId {
+                workchain: ton_block::MASTERCHAIN_ID,
+                shard: ton_block::SHARD_FULL as i64,
+                seqno: seqno as i32,
+            })
+            .await?
+    };
+
+    let block = client.get_block(&id).await?;
+    Ok((id, block))
+}
+
+/// Walks back from the top shard blocks until the blocks of the previous masterchain block
+async fn new_shard_blocks<A>(
+    client: &TonlibClient<A>,
+    tops: &[BlockIdExt],
+    prev_tops: &HashSet<BlockKey>,
+) -> Result<Vec<(BlockIdExt, Block)>>
+where
+    A: AdnlTransport,
+{
+    let mut stack = tops.to_vec();
+    let mut visited = HashSet::new();
+    let mut blocks = Vec::new();
+
+    while let Some(id) = stack.pop() {
+        let key = block_key(&id);
+        // Zero seqno is the zero state of the workchain
+        if id.seqno == 0 || prev_tops.contains(&key) || !visited.insert(key) {
+            continue;
+        }
+
+        let block = client.get_block(&id).await?;
+        stack.extend(prev_blocks(&block)?);
+        blocks.push((id, block));
+    }
+
+    // Seqno of the block after split or merge is greater than seqnos of its parents
+    blocks.sort_by_key(|(id, _)| (id.workchain, id.seqno, id.shard as u64));
+    Ok(blocks)
+}
+
+fn shard_tops(block: &Block) -> TonlibResult<Vec<BlockIdExt>> {
+    let extra = block
+        .read_extra()
+        .and_then(|extra| extra.read_custom())
+        .map_err(|_| TonlibError::InvalidBlock)?
+        .ok_or(TonlibError::InvalidBlock)?;
+
+    let mut tops = Vec::new();
+    extra
+        .shards()
+        .iterate_shards(|shard, descr| {
+            tops.push(BlockIdExt {
+                workchain: shard.workchain_id(),
+                shard: shard.shard_prefix_with_tag() as i64,
+                seqno: descr.seq_no as i32,
+                root_hash: ton::int256(descr.root_hash.into()),
+                file_hash: ton::int256(descr.file_hash.into()),
+            });
+            Ok(true)
+        })
+        .map_err(|_| TonlibError::InvalidBlock)?;
+    Ok(tops)
+}
+
+/// Root hash of the previous masterchain block
+fn prev_block_hash(block: &Block) -> TonlibResult<UInt256> {
+    let prev1 = block
+        .read_info()
+        .and_then(|info| info.read_prev_ref())
+        .and_then(|prev_ref| prev_ref.prev1())
+        .map_err(|_| TonlibError::InvalidBlock)?;
+    Ok(prev1.root_hash)
+}
+
+fn prev_blocks(block: &Block) -> TonlibResult<Vec<BlockIdExt>> {
+    let info = block.read_info().map_err(|_| TonlibError::InvalidBlock)?;
+    let shard = info.shard();
+
+    let prev_ref = info.read_prev_ref().map_err(|_| TonlibError::InvalidBlock)?;
+    let prev1 = prev_ref.prev1().map_err(|_| TonlibError::InvalidBlock)?;
+    let prev2 = prev_ref.prev2().map_err(|_| TonlibError::InvalidBlock)?;
+
+    Ok(match prev2 {
+        // After merge
+        Some(prev2) => {
+            let (left, right) = shard.split().map_err(|_| TonlibError::InvalidBlock)?;
+            vec![make_block_id(&left, prev1), make_block_id(&right, prev2)]
+        }
+        None if info.after_split() => {
+            let parent = shard.merge().map_err(|_| TonlibError::InvalidBlock)?;
+            vec![make_block_id(&parent, prev1)]
+        }
+        None => vec![make_block_id(shard, prev1)],
+    })
+}
+
+fn make_block_id(shard: &ShardIdent, block_ref: ExtBlkRef) -> BlockIdExt {
+    BlockIdExt {
+        workchain: shard.workchain_id(),
+        shard: shard.shard_prefix_with_tag() as i64,
+        seqno: block_ref.seq_no as i32,
+        root_hash: ton::int256(block_ref.root_hash.into()),
+        file_hash: ton::int256(block_ref.file_hash.into()),
+    }
+}
+
+fn block_key(id: &BlockIdExt) -> BlockKey {
+    (id.workchain, id.shard as u64, id.seqno)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use ton_api::BoxedSerialize;
+    use ton_block::{BlkPrevInfo, BlockExtra, BlockInfo, McBlockExtra, Serializable};
+
+    use super::*;
+    use crate::replay::{Fixtures, ReplayContext, ReplayTransport};
+    use crate::{Config, LastBlock, LiteServerConfig};
+
+    /// Returns id and BOC of the masterchain block. Blocks with different `end_lt` of the previous one are forks
+    fn make_block(seqno: i32, prev: Option<&BlockIdExt>, end_lt: u64) -> (BlockIdExt, Vec<u8>) {
+        let mut info = BlockInfo::default();
+        if let Some(prev) = prev {
+            let prev = ExtBlkRef {
+                end_lt,
+                seq_no: prev.seqno as u32,
+                root_hash: prev.root_hash.0.into(),
+                file_hash: prev.file_hash.0.into(),
+            };
+            info.set_prev_stuff(false, &BlkPrevInfo::Block { prev }).unwrap();
+        }
+
+        let mut extra = BlockExtra::default();
+        extra.write_custom(Some(&McBlockExtra::default())).unwrap();
+
+        let mut block = Block::default();
+        block.write_info(&info).unwrap();
+        block.write_extra(&extra).unwrap();
+
+        let root = block.serialize().unwrap();
+        let id = BlockIdExt {
+            workchain: ton_block::MASTERCHAIN_ID,
+            shard: ton_block::SHARD_FULL as i64,
+            seqno,
+            root_hash: ton::int256(root.repr_hash().into()),
+            file_hash: ton::int256([0; 32]),
+        };
+        (id, ton_types::serialize_toc(&root).unwrap())
+    }
+
+    fn record<T: ton_api::Function>(fixtures: &Fixtures, query: &T, response: ton::TLObject) {
+        let query = ton::rpc::lite_server::Query {
+            data: query.boxed_serialized_bytes().unwrap().into(),
+        };
+        fixtures.record(&ton::TLObject::new(query), &response).unwrap();
+    }
+
+    fn record_block(fixtures: &Fixtures, id: &BlockIdExt, data: Vec<u8>) {
+        let lookup = ton::rpc::lite_server::LookupBlock {
+            mode: 0x1,
+            id: ton::ton_node::blockid::BlockId {
+                workchain: id.workchain,
+                shard: id.shard,
+                seqno: id.seqno,
+            },
+            lt: None,
+            utime: None,
+        };
+        let header = ton::lite_server::blockheader::BlockHeader {
+            id: id.clone(),
+            mode: 0,
+            header_proof: ton::bytes(Vec::new()),
+        };
+        record(
+            fixtures,
+            &lookup,
+            ton::TLObject::new(ton::lite_server::BlockHeader::LiteServer_BlockHeader(header)),
+        );
+
+        let block = ton::lite_server::blockdata::BlockData {
+            id: id.clone(),
+            data: ton::bytes(data),
+        };
+        record(
+            fixtures,
+            &ton::rpc::lite_server::GetBlock { id: id.clone() },
+            ton::TLObject::new(ton::lite_server::BlockData::LiteServer_BlockData(block)),
+        );
+    }
+
+    fn record_last_block(fixtures: &Fixtures, id: &BlockIdExt) {
+        let info = ton::lite_server::masterchaininfoext::MasterchainInfoExt {
+            mode: 0,
+            version: 0x101,
+            capabilities: 7,
+            last: id.clone(),
+            last_utime: 0,
+            now: 0,
+            state_root_hash: ton::int256([0; 32]),
+            init: ton::ton_node::zerostateidext::ZeroStateIdExt {
+                workchain: ton_block::MASTERCHAIN_ID,
+                root_hash: ton::int256([0; 32]),
+                file_hash: ton::int256([0; 32]),
+            },
+        };
+        record(
+            fixtures,
+            &ton::rpc::lite_server::GetMasterchainInfoExt { mode: 0 },
+            ton::TLObject::new(ton::lite_server::MasterchainInfoExt::LiteServer_MasterchainInfoExt(info)),
+        );
+    }
+
+    fn describe(event: BlockEvent) -> String {
+        match event {
+            BlockEvent::Block { id, .. } => format!("block {} {}", id.seqno, hex::encode(&id.root_hash.0)),
+            BlockEvent::Final { seqno } => format!("final {}", seqno),
+            BlockEvent::Reorg { from_seqno } => format!("reorg {}", from_seqno),
+        }
+    }
+
+    /// Synthetic masterchain blocks 0..=3, the first lookup of the block 1 returns its fork
+    #[tokio::test]
+    async fn reorg_and_final_blocks() {
+        let (id0, block0) = make_block(0, None, 0);
+        let (fork_id, fork) = make_block(1, Some(&id0), 0);
+        let (id1, block1) = make_block(1, Some(&id0), 1);
+        let (id2, block2) = make_block(2, Some(&id1), 0);
+        let (id3, block3) = make_block(3, Some(&id2), 0);
+
+        let fixtures = Fixtures::default();
+        record_last_block(&fixtures, &id3);
+        record_block(&fixtures, &id0, block0);
+        record_block(&fixtures, &fork_id, fork);
+        record_block(&fixtures, &id1, block1);
+        record_block(&fixtures, &id2, block2);
+        record_block(&fixtures, &id3, block3);
+
+        let config = Config {
+            servers: vec![LiteServerConfig {
+                address: "127.0.0.1:3031".parse().unwrap(),
+                key: "uNRRL+6enQjuiZ/s6Z+vO7yxUUR7uxdfzIy+RxkECrc=".to_owned(),
+            }],
+            ..Default::default()
+        };
+        let last_block = Arc::new(LastBlock::new(&config.last_block_threshold));
+        let client = TonlibClient::<ReplayTransport>::with_transport(&config, last_block, ReplayContext::new(&fixtures).unwrap())
+            .await
+            .unwrap();
+
+        let stream = BlockStream::new(
+            &client,
+            1,
+            BlockStreamConfig {
+                finality_depth: 2,
+                ..Default::default()
+            },
+        );
+        let events = stream.take(7).map(|event| describe(event.unwrap())).collect::<Vec<_>>().await;
+
+        let block = |id: &BlockIdExt| {
+            describe(BlockEvent::Block {
+                id: id.clone(),
+                block: Block::default(),
+            })
+        };
+        assert_eq!(
+            events,
+            vec![
+                block(&fork_id),
+                "reorg 1".to_owned(),
+                block(&id1),
+                block(&id2),
+                "final 0".to_owned(),
+                block(&id3),
+                "final 1".to_owned(),
+            ]
+        );
+    }
+}
@@ -1,50 +1,311 @@
+use std::time::{Duration, Instant};
+
 use bb8::{Pool, PooledConnection};
+use futures::future::Either;
 use ton_api::ton;
 
 use super::errors::*;
-use crate::pool::AdnlManageConnection;
+use crate::pool::{AdnlConnection, AdnlManageConnection};
+use crate::transport::AdnlTransport;
+
+/// Code of `liteServer.error` returned when the server has no requested block yet
+pub(crate) const ERR_NOT_READY: i32 = 651;
 
-pub async fn query<T>(connection: &mut PooledConnection<'_, AdnlManageConnection>, query: &T) -> TonlibResult<QueryReply<T::Reply>>
+pub async fn query<A, T>(connection: &AdnlConnection<A>, query: &T) -> TonlibResult<QueryReply<T::Reply>>
 where
+    A: AdnlTransport,
     T: ton_api::Function,
 {
-    const MAX_RETIRES: usize = 3;
-    const RETRY_INTERVAL: u64 = 100; // Milliseconds
+    query_with_prefix(connection, None, query).await
+}
 
-    const ERR_NOT_READY: i32 = 651;
+/// Executes query with optional `liteServer.waitMasterchainSeqno` prefix,
+/// so that the server answers only after it reaches the specified masterchain block
+#[cfg_attr(
+    feature = "tracing",
+    tracing::instrument(
+        skip_all,
+        fields(
+            server = %connection.server_address(),
+            query = query_name::<T>(),
+            retries = 0,
+            latency_ms = tracing::field::Empty
+        )
+    )
+)]
+pub async fn query_with_prefix<A, T>(
+    connection: &AdnlConnection<A>,
+    prefix: Option<&ton::rpc::lite_server::WaitMasterchainSeqno>,
+    query: &T,
+) -> TonlibResult<QueryReply<T::Reply>>
+where
+    A: AdnlTransport,
+    T: ton_api::Function,
+{
+    let retry_policy = connection.retry_policy();
 
-    let query_bytes = query.boxed_serialized_bytes().map_err(|_| TonlibError::FailedToSerialize)?;
+    let mut query_bytes = match prefix {
+        Some(prefix) => serialize_function(prefix)?,
+        None => Vec::new(),
+    };
+    query_bytes.extend(serialize_function(query)?);
 
+    let query_name = query_name::<T>();
+    let bytes_sent = query_bytes.len();
     let query = ton::TLObject::new(ton::rpc::lite_server::Query { data: query_bytes.into() });
 
     let mut retries = 0;
     loop {
-        let response = connection.query(&query).await.map_err(|_| TonlibError::ConnectionError)?;
+        let permit = connection.acquire_permit().await?;
+
+        let started_at = Instant::now();
+        let response = connection.send_query(&query).await;
+        drop(permit);
+
+        let response = response.map_err(|e| {
+            log::warn!("query failed: {:?}", e);
+            // Broken connection will be dropped when returned to the pool
+            connection.report_failure();
+            TonlibError::ConnectionError
+        })?;
+        let (response, bytes_received) = response;
+        connection.report_traffic(query_name, bytes_sent, bytes_received);
+        // Prefixed queries wait for the block, so their time doesn't reflect latency
+        if prefix.is_none() {
+            let latency = started_at.elapsed();
+            connection.report_latency(latency);
+            #[cfg(feature = "tracing")]
+            tracing::Span::current().record("latency_ms", &(latency.as_millis() as u64));
+            #[cfg(feature = "metrics")]
+            crate::telemetry::query_duration(query_name, latency);
+        }
 
         match response.downcast::<T::Reply>() {
             Ok(reply) => return Ok(QueryReply::Data(reply)),
             Err(error) => match error.downcast::<ton::lite_server::Error>() {
                 Ok(error) if error.code() == &ERR_NOT_READY => {
-                    if retries < MAX_RETIRES {
-                        tokio::time::sleep(std::time::Duration::from_millis(RETRY_INTERVAL)).await;
+                    connection.report_not_ready();
+                    if retry_policy.retry_not_ready && retries < retry_policy.max_retries {
+                        tokio::time::sleep(retry_policy.backoff(retries)).await;
                         retries += 1;
+                        #[cfg(feature = "tracing")]
+                        tracing::Span::current().record("retries", &retries);
                         continue;
                     } else {
                         return Ok(QueryReply::NotReady);
                     }
                 }
-                Ok(error) => return Err(TonlibError::LiteServer(error)),
+                Ok(error) => {
+                    connection.report_lite_server_error(*error.code());
+                    return Err(TonlibError::LiteServer(error));
+                }
                 Err(_) => return Err(TonlibError::Unknown),
             },
         }
     }
 }
 
-pub async fn acquire_connection(pool: &Pool<AdnlManageConnection>) -> TonlibResult<PooledConnection<'_, AdnlManageConnection>> {
-    pool.get().await.map_err(|e| {
+/// Executes query using the session outside of the pool, without retries and accounting
+pub async fn query_session<A, T>(client: &A, query: &T) -> TonlibResult<T::Reply>
+where
+    A: AdnlTransport,
+    T: ton_api::Function,
+{
+    let query = ton::TLObject::new(ton::rpc::lite_server::Query {
+        data: serialize_function(query)?.into(),
+    });
+    let response = client.query(&query).await.map_err(|e| {
+        log::warn!("query failed: {:?}", e);
+        TonlibError::ConnectionError
+    })?;
+
+    match response.downcast::<T::Reply>() {
+        Ok(reply) => Ok(reply),
+        Err(error) => match error.downcast::<ton::lite_server::Error>() {
+            Ok(error) => Err(TonlibError::LiteServer(error)),
+            Err(_) => Err(TonlibError::Unknown),
+        },
+    }
+}
+
+/// Returns the name of the query type without the module path
+fn query_name<T>() -> &'static str {
+    let name = std::any::type_name::<T>();
+    name.rsplit("::").next().unwrap_or(name)
+}
+
+fn serialize_function<T>(function: &T) -> TonlibResult<Vec<u8>>
+where
+    T: ton_api::Function,
+{
+    function.boxed_serialized_bytes().map_err(|_| TonlibError::FailedToSerialize)
+}
+
+/// Executes idempotent query. Retries it using a fresh connection on transport error
+/// according to the retry policy
+pub async fn query_with_reconnect<A, T>(pool: &Pool<AdnlManageConnection<A>>, query: &T) -> TonlibResult<QueryReply<T::Reply>>
+where
+    A: AdnlTransport,
+    T: ton_api::Function,
+{
+    query_with_reconnect_prefixed(pool, None, query).await
+}
+
+/// Same as `query_with_reconnect`, but with optional `liteServer.waitMasterchainSeqno` prefix
+pub async fn query_with_reconnect_prefixed<A, T>(
+    pool: &Pool<AdnlManageConnection<A>>,
+    prefix: Option<&ton::rpc::lite_server::WaitMasterchainSeqno>,
+    query: &T,
+) -> TonlibResult<QueryReply<T::Reply>>
+where
+    A: AdnlTransport,
+    T: ton_api::Function,
+{
+    query_with_reconnect_using(pool, None, prefix, query).await
+}
+
+/// Same as `query_with_reconnect_prefixed`, but the first attempt uses the given connection
+/// if specified. Retries use fresh connections
+#[cfg_attr(feature = "tracing", tracing::instrument(skip_all, fields(retries = 0)))]
+pub async fn query_with_reconnect_using<A, T>(
+    pool: &Pool<AdnlManageConnection<A>>,
+    connection: Option<&AdnlConnection<A>>,
+    prefix: Option<&ton::rpc::lite_server::WaitMasterchainSeqno>,
+    query: &T,
+) -> TonlibResult<QueryReply<T::Reply>>
+where
+    A: AdnlTransport,
+    T: ton_api::Function,
+{
+    let mut first = connection.cloned();
+    let mut retries = 0;
+    loop {
+        let connection = match first.take() {
+            Some(connection) => connection,
+            None => acquire_connection(pool).await?,
+        };
+        let retry_policy = connection.retry_policy();
+
+        match query_with_prefix(&connection, prefix, query).await {
+            Err(TonlibError::ConnectionError) if retry_policy.retry_connection_errors && retries < retry_policy.max_retries => {
+                log::debug!("Retrying query with a fresh connection");
+                tokio::time::sleep(retry_policy.backoff(retries)).await;
+                retries += 1;
+                #[cfg(feature = "tracing")]
+                tracing::Span::current().record("retries", &retries);
+            }
+            result => return result,
+        }
+    }
+}
+
+/// Executes read query. If there is no answer after `delay`, sends the same query
+/// using a connection to another server. The first successful answer wins
+pub async fn query_hedged<A, T>(
+    pool: &Pool<AdnlManageConnection<A>>,
+    prefix: Option<&ton::rpc::lite_server::WaitMasterchainSeqno>,
+    query: &T,
+    delay: Duration,
+) -> TonlibResult<QueryReply<T::Reply>>
+where
+    A: AdnlTransport,
+    T: ton_api::Function,
+{
+    let connection = get_connection(pool).await?;
+    query_hedged_using(pool, &connection, prefix, query, delay).await
+}
+
+/// Same as `query_hedged`, but the first query is sent using the given connection
+#[cfg_attr(feature = "tracing", tracing::instrument(skip_all))]
+pub async fn query_hedged_using<A, T>(
+    pool: &Pool<AdnlManageConnection<A>>,
+    connection: &AdnlConnection<A>,
+    prefix: Option<&ton::rpc::lite_server::WaitMasterchainSeqno>,
+    query: &T,
+    delay: Duration,
+) -> TonlibResult<QueryReply<T::Reply>>
+where
+    A: AdnlTransport,
+    T: ton_api::Function,
+{
+    let first = query_with_prefix(connection, prefix, query);
+    futures::pin_mut!(first);
+
+    if let Ok(result) = tokio::time::timeout(delay, &mut first).await {
+        return result;
+    }
+
+    let connection = match get_connection_excluding(pool, connection.server_id()).await? {
+        Some(connection) => connection,
+        None => {
+            log::debug!("No connections to other servers for the hedged query");
+            return first.await;
+        }
+    };
+
+    log::debug!("Sending hedged query");
+    let second = query_with_prefix(&connection, prefix, query);
+    futures::pin_mut!(second);
+
+    match futures::future::select(first, second).await {
+        Either::Left((Ok(result), _)) | Either::Right((Ok(result), _)) => Ok(result),
+        Either::Left((Err(_), second)) => second.await,
+        Either::Right((Err(_), first)) => first.await,
+    }
+}
+
+/// Returns a handle to the pooled connection.
+///
+/// The connection is returned to the pool right away. ADNL answers are matched
+/// with queries by query id, so the same session is shared by concurrent queries.
+/// The number of queries in flight is limited by `RateLimitConfig::max_concurrent_queries`
+#[cfg_attr(feature = "tracing", tracing::instrument(skip_all, fields(server = tracing::field::Empty)))]
+pub async fn acquire_connection<A>(pool: &Pool<AdnlManageConnection<A>>) -> TonlibResult<AdnlConnection<A>>
+where
+    A: AdnlTransport,
+{
+    let connection = get_connection(pool).await?;
+    #[cfg(feature = "tracing")]
+    tracing::Span::current().record("server", &tracing::field::display(connection.server_address()));
+    Ok(AdnlConnection::clone(&connection))
+}
+
+/// Checks out idle connections until the one to another server is found.
+///
+/// Returns `None` if there are no idle connections to other servers
+async fn get_connection_excluding<A>(
+    pool: &Pool<AdnlManageConnection<A>>,
+    server_id: usize,
+) -> TonlibResult<Option<PooledConnection<'_, AdnlManageConnection<A>>>>
+where
+    A: AdnlTransport,
+{
+    // Checked out connections are held, so that the next checkout returns another one
+    let mut skipped = Vec::new();
+    while pool.state().idle_connections > 0 {
+        let connection = get_connection(pool).await?;
+        if connection.server_id() != server_id {
+            return Ok(Some(connection));
+        }
+        skipped.push(connection);
+    }
+    Ok(None)
+}
+
+async fn get_connection<A>(pool: &Pool<AdnlManageConnection<A>>) -> TonlibResult<PooledConnection<'_, AdnlManageConnection<A>>>
+where
+    A: AdnlTransport,
+{
+    let started_at = Instant::now();
+    let connection = pool.get().await.map_err(|e| {
         log::error!("connection error: {:#?}", e);
         TonlibError::ConnectionError
-    })
+    })?;
+    let duration = started_at.elapsed();
+    connection.report_checkout(duration);
+    #[cfg(feature = "metrics")]
+    crate::telemetry::checkout(duration, pool.state());
+    Ok(connection)
 }
 
 pub enum QueryReply<T> {
@@ -0,0 +1,122 @@
+use std::collections::VecDeque;
+use std::time::Duration;
+
+use anyhow::Result;
+use futures::{StreamExt, TryStreamExt};
+use tiny_adnl::AdnlTcpClient;
+use ton_block::Transaction;
+use ton_types::UInt256;
+
+use crate::activity::AccountActivity;
+use crate::transport::AdnlTransport;
+use crate::{AsStdAddr, TonlibClient};
+
+/// Delivers new account transactions from the oldest to the newest.
+///
+/// The cursor advances only when the transaction is acknowledged, so the unacknowledged one
+/// is delivered again. Persisting the cursor after each `ack` and restarting from it
+/// gives at-least-once delivery across restarts
+pub struct TransactionConsumer<'a, T, A: AdnlTransport = AdnlTcpClient> {
+    client: &'a TonlibClient<A>,
+    account: &'a T,
+    cursor: u64,
+    /// Oldest unacknowledged transactions, at most `MAX_PENDING`
+    pending: VecDeque<(UInt256, Transaction)>,
+    /// Logical time and hash of the newest transaction of each newer page, the oldest page is the last
+    pages: Vec<(u64, UInt256)>,
+    activity: AccountActivity,
+}
+
+/// Default window of the acknowledged transactions analytics
+const DEFAULT_ACTIVITY_WINDOW: Duration = Duration::from_secs(3600);
+
+/// Max number of the transactions loaded at once
+const MAX_PENDING: usize = 256;
+
+impl<'a, T, A> TransactionConsumer<'a, T, A>
+where
+    T: AsStdAddr,
+    A: AdnlTransport,
+{
+    /// Creates consumer of the transactions with logical time greater than `cursor`
+    pub fn new(client: &'a TonlibClient<A>, account: &'a T, cursor: u64) -> Self {
+        Self {
+            client,
+            account,
+            cursor,
+            pending: VecDeque::new(),
+            pages: Vec::new(),
+            activity: AccountActivity::new(DEFAULT_ACTIVITY_WINDOW),
+        }
+    }
+
+    /// Sets the window of the activity analytics. Already recorded transactions are dropped
+    pub fn with_activity_window(mut self, window: Duration) -> Self {
+        self.activity = AccountActivity::new(window);
+        self
+    }
+
+    /// Analytics of the acknowledged transactions
+    pub fn activity(&self) -> &AccountActivity {
+        &self.activity
+    }
+
+    /// Logical time of the last acknowledged transaction
+    pub fn cursor(&self) -> u64 {
+        self.cursor
+    }
+
+    /// Returns the oldest unacknowledged transaction, or `None` if there are no new ones.
+    ///
+    /// The history is linked backwards, so it is walked from the last transaction down to the cursor.
+    /// Only the oldest page is kept in memory, the newer pages are loaded again by their
+    /// first transaction after the older ones are acknowledged
+    pub async fn next(&mut self) -> Result<Option<&(UInt256, Transaction)>> {
+        if self.pending.is_empty() {
+            let mut page = match self.pages.pop() {
+                Some(start) => {
+                    self.client
+                        .account_transactions_from(self.account, Some(start), self.cursor)
+                        .take(MAX_PENDING)
+                        .try_collect::<Vec<_>>()
+                        .await?
+                }
+                None => self.walk_history().await?,
+            };
+            page.reverse();
+            self.pending = page.into();
+        }
+        Ok(self.pending.front())
+    }
+
+    /// Walks the history since the cursor, remembering the starts of the pages.
+    /// Returns the oldest page
+    async fn walk_history(&mut self) -> Result<Vec<(UInt256, Transaction)>> {
+        // The stream borrows only the client, so the pages can be updated while walking
+        let (client, account) = (self.client, self.account);
+        let transactions = client.account_transactions_from(account, None, self.cursor);
+        futures::pin_mut!(transactions);
+
+        let mut page = Vec::with_capacity(MAX_PENDING);
+        while let Some(item) = transactions.try_next().await? {
+            if page.len() == MAX_PENDING {
+                let (hash, transaction) = &page[0];
+                self.pages.push((transaction.lt, *hash));
+                page.clear();
+            }
+            page.push(item);
+        }
+        Ok(page)
+    }
+
+    /// Acknowledges the transaction returned by `next` and returns the new cursor
+    pub fn ack(&mut self) -> u64 {
+        if let Some((hash, transaction)) = self.pending.pop_front() {
+            if let Err(e) = self.activity.record(&hash, &transaction) {
+                log::warn!("Failed to record account activity: {:?}", e);
+            }
+            self.cursor = transaction.lt;
+        }
+        self.cursor
+    }
+}
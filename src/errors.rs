@@ -6,6 +6,10 @@ pub enum TonlibError {
     InvalidAddress,
     #[error("account not found")]
     AccountNotFound,
+    #[error("account is not active")]
+    AccountNotActive,
+    #[error("Invalid account data")]
+    InvalidAccountData,
     #[error("Connection error")]
     ConnectionError,
     #[error("Failed to serialize message")]
@@ -16,10 +20,34 @@ pub enum TonlibError {
     InvalidAccountStateProof,
     #[error("Invalid block")]
     InvalidBlock,
+    #[error("Invalid block proof")]
+    InvalidBlockProof,
+    #[error("Invalid transaction")]
+    InvalidTransaction,
     #[error("Unknown")]
     Unknown,
     #[error("Not ready")]
     NotReady,
+    #[error("Timeout")]
+    Timeout,
+    #[error("Rate limit exceeded")]
+    RateLimited,
+    #[error("Invalid BOC")]
+    InvalidBoc,
+    #[error("Invalid shard state")]
+    InvalidShardState,
+    #[error("Invalid signature")]
+    InvalidSignature,
+    #[error("Invalid stack")]
+    InvalidStack,
+    #[error("Get method failed with exit code {0}")]
+    ExecutionFailed(i32),
+    #[error("Node is out of sync. Last block lag: {lag:?}")]
+    NodeOutOfSync { lag: std::time::Duration },
+    #[error("Zero state mismatch on server {address}")]
+    ZeroStateMismatch { address: std::net::SocketAddrV4 },
+    #[error("Query is not supported in the current trust mode")]
+    ProofsRequired,
 }
 
 pub type TonlibResult<T> = Result<T, TonlibError>;
@@ -0,0 +1,273 @@
+use std::io::Write;
+
+use anyhow::Result;
+use futures::StreamExt;
+use ton_block::{Message, MsgAddressInt, Transaction};
+use ton_types::UInt256;
+
+use crate::{AsStdAddr, TonlibClient};
+
+/// Normalized account history entry
+#[derive(Debug, Clone)]
+pub struct HistoryRow {
+    pub lt: u64,
+    pub hash: UInt256,
+    pub time: u32,
+    pub direction: Direction,
+    pub counterparty: Option<MsgAddressInt>,
+    pub value: u128,
+    pub fees: u128,
+}
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum Direction {
+    In,
+    Out,
+}
+
+impl Direction {
+    fn as_str(&self) -> &'static str {
+        match self {
+            Self::In => "in",
+            Self::Out => "out",
+        }
+    }
+}
+
+/// Splits transaction into rows: one for the inbound message and one for each outbound internal message.
+///
+/// Transaction fees are attributed to the first row
+pub fn transaction_rows(hash: &UInt256, transaction: &Transaction) -> Result<Vec<HistoryRow>> {
+    let mut rows = Vec::new();
+
+    let mut make_row = |direction: Direction, counterparty: Option<MsgAddressInt>, value: u128| {
+        let fees = if rows.is_empty() { transaction.total_fees().grams.0 } else { 0 };
+        rows.push(HistoryRow {
+            lt: transaction.lt,
+            hash: *hash,
+            time: transaction.now,
+            direction,
+            counterparty,
+            value,
+            fees,
+        });
+    };
+
+    let in_msg = transaction.read_in_msg().map_err(anyhow::Error::msg)?;
+    match &in_msg {
+        Some(message) if message.is_internal() => make_row(Direction::In, message.src(), message_value(message)),
+        _ => make_row(Direction::In, None, 0),
+    }
+
+    transaction
+        .iterate_out_msgs(|message| {
+            if message.is_internal() {
+                make_row(Direction::Out, message.dst(), message_value(&message));
+            }
+            Ok(true)
+        })
+        .map_err(anyhow::Error::msg)?;
+
+    Ok(rows)
+}
+
+/// Value movement between two accounts
+#[derive(Debug, Clone)]
+pub struct Transfer {
+    pub from: Option<MsgAddressInt>,
+    pub to: Option<MsgAddressInt>,
+    pub value: u128,
+    pub fee: u128,
+    pub kind: TransferKind,
+}
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum TransferKind {
+    Incoming,
+    Outgoing,
+    /// Outgoing message of the account returned back
+    BouncedIncoming,
+    /// Incoming message returned to the sender by the account
+    BouncedOutgoing,
+}
+
+/// Converts transaction into transfers of its internal messages.
+///
+/// Aborted transactions don't send messages except for bounces,
+/// so only the transfers which actually happened are returned. Fees are attributed to the first transfer
+pub fn normalize_transaction(transaction: &Transaction) -> Result<Vec<Transfer>> {
+    let mut transfers = Vec::new();
+
+    let mut push = |message: &Message, incoming: bool| {
+        let bounced = message.int_header().map(|header| header.bounced).unwrap_or_default();
+        let kind = match (incoming, bounced) {
+            (true, false) => TransferKind::Incoming,
+            (true, true) => TransferKind::BouncedIncoming,
+            (false, false) => TransferKind::Outgoing,
+            (false, true) => TransferKind::BouncedOutgoing,
+        };
+
+        let fee = if transfers.is_empty() {
+            transaction.total_fees().grams.0
+        } else {
+            0
+        };
+        transfers.push(Transfer {
+            from: message.src(),
+            to: message.dst(),
+            value: message_value(message),
+            fee,
+            kind,
+        });
+    };
+
+    if let Some(message) = transaction.read_in_msg().map_err(anyhow::Error::msg)? {
+        if message.is_internal() {
+            push(&message, true);
+        }
+    }
+
+    transaction
+        .iterate_out_msgs(|message| {
+            if message.is_internal() {
+                push(&message, false);
+            }
+            Ok(true)
+        })
+        .map_err(anyhow::Error::msg)?;
+
+    Ok(transfers)
+}
+
+/// Walks the whole account history and writes it as CSV. Returns the number of written rows
+pub async fn export_account_history_csv<T, W>(client: &TonlibClient, account: &T, mut writer: W) -> Result<usize>
+where
+    T: AsStdAddr,
+    W: Write,
+{
+    writeln!(writer, "lt,hash,time,direction,counterparty,value,fees")?;
+
+    let transactions = client.account_transactions(account, 0);
+    futures::pin_mut!(transactions);
+
+    let mut count = 0;
+    while let Some(item) = transactions.next().await {
+        let (hash, transaction) = item?;
+        for row in transaction_rows(&hash, &transaction)? {
+            writeln!(
+                writer,
+                "{},{},{},{},{},{},{}",
+                row.lt,
+                row.hash.to_hex_string(),
+                row.time,
+                row.direction.as_str(),
+                row.counterparty.map(|address| address.to_string()).unwrap_or_default(),
+                row.value,
+                row.fees
+            )?;
+            count += 1;
+        }
+    }
+
+    writer.flush()?;
+    Ok(count)
+}
+
+fn message_value(message: &Message) -> u128 {
+    message.get_value().map(|value| value.grams.0).unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+    use ton_block::{
+        AccountStatus, CurrencyCollection, ExternalInboundMessageHeader, InternalMessageHeader, TransactionDescr, TransactionDescrOrdinary,
+    };
+
+    use super::*;
+
+    fn address(byte: u8) -> MsgAddressInt {
+        MsgAddressInt::with_standart(None, 0, UInt256::from([byte; 32]).into()).unwrap()
+    }
+
+    fn internal_message(src: u8, dst: u8, value: u64, bounced: bool) -> Message {
+        let mut header =
+            InternalMessageHeader::with_addresses_and_bounce(address(src), address(dst), CurrencyCollection::with_grams(value), true);
+        header.bounced = bounced;
+        Message::with_int_header(header)
+    }
+
+    fn make_transaction(in_msg: &Message, out_msgs: &[Message], aborted: bool) -> Transaction {
+        let mut transaction = Transaction::with_address_and_status(UInt256::from([2; 32]).into(), AccountStatus::AccStateActive);
+        transaction.set_logical_time(1000);
+        transaction.set_now(1_600_000_000);
+        transaction.set_total_fees(CurrencyCollection::with_grams(5));
+        transaction.write_in_msg(Some(in_msg)).unwrap();
+        for message in out_msgs {
+            transaction.add_out_message(message).unwrap();
+        }
+        transaction
+            .write_description(&TransactionDescr::Ordinary(TransactionDescrOrdinary {
+                aborted,
+                ..Default::default()
+            }))
+            .unwrap();
+        transaction
+    }
+
+    #[test]
+    fn incoming_transfer() {
+        let transaction = make_transaction(&internal_message(1, 2, 100, false), &[], false);
+
+        let transfers = normalize_transaction(&transaction).unwrap();
+        assert_eq!(transfers.len(), 1);
+        assert_eq!(transfers[0].kind, TransferKind::Incoming);
+        assert_eq!(transfers[0].from, Some(address(1)));
+        assert_eq!(transfers[0].value, 100);
+        assert_eq!(transfers[0].fee, 5);
+
+        let rows = transaction_rows(&UInt256::default(), &transaction).unwrap();
+        assert_eq!(rows.len(), 1);
+        assert_eq!(rows[0].direction, Direction::In);
+        assert_eq!(rows[0].counterparty, Some(address(1)));
+        assert_eq!(rows[0].lt, 1000);
+    }
+
+    #[test]
+    fn aborted_with_bounce() {
+        let transaction = make_transaction(&internal_message(1, 2, 100, false), &[internal_message(2, 1, 90, true)], true);
+
+        let transfers = normalize_transaction(&transaction).unwrap();
+        assert_eq!(transfers.len(), 2);
+        assert_eq!(transfers[0].kind, TransferKind::Incoming);
+        assert_eq!(transfers[1].kind, TransferKind::BouncedOutgoing);
+        assert_eq!(transfers[1].to, Some(address(1)));
+        assert_eq!(transfers[1].value, 90);
+        assert_eq!(transfers[1].fee, 0);
+    }
+
+    #[test]
+    fn returned_bounce() {
+        let transaction = make_transaction(&internal_message(1, 2, 50, true), &[], false);
+
+        let transfers = normalize_transaction(&transaction).unwrap();
+        assert_eq!(transfers.len(), 1);
+        assert_eq!(transfers[0].kind, TransferKind::BouncedIncoming);
+    }
+
+    #[test]
+    fn external_inbound() {
+        let in_msg = Message::with_ext_in_header(ExternalInboundMessageHeader::default());
+        let transaction = make_transaction(&in_msg, &[internal_message(2, 3, 70, false)], false);
+
+        let transfers = normalize_transaction(&transaction).unwrap();
+        assert_eq!(transfers.len(), 1);
+        assert_eq!(transfers[0].kind, TransferKind::Outgoing);
+        assert_eq!(transfers[0].value, 70);
+        assert_eq!(transfers[0].fee, 5);
+
+        let rows = transaction_rows(&UInt256::default(), &transaction).unwrap();
+        assert_eq!(rows.len(), 2);
+        assert_eq!((rows[0].direction, rows[0].value, rows[0].fees), (Direction::In, 0, 5));
+        assert_eq!((rows[1].direction, rows[1].value, rows[1].fees), (Direction::Out, 70, 0));
+    }
+}
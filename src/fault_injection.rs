@@ -0,0 +1,105 @@
+//! Deterministic fault injection for testing retry and failover handling
+
+use std::collections::VecDeque;
+use std::time::Duration;
+
+use anyhow::Result;
+use ton_api::ton;
+
+use crate::connection::ERR_NOT_READY;
+
+/// Queue of faults applied to the subsequent queries in order
+#[derive(Debug, Default)]
+pub struct FaultInjector {
+    faults: parking_lot::Mutex<VecDeque<Fault>>,
+    default_fault: parking_lot::Mutex<Option<Fault>>,
+}
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum Fault {
+    /// Delays the query
+    Delay(Duration),
+    /// Replies with `liteServer.error` with the "not ready" code instead of sending the query
+    NotReady,
+    /// Fails the query with a transport error, so the connection is dropped
+    DropConnection,
+}
+
+impl FaultInjector {
+    /// Adds fault for the next query
+    pub fn push(&self, fault: Fault) {
+        self.faults.lock().push_back(fault);
+    }
+
+    /// Sets fault which is applied when the queue is empty
+    pub fn set_default(&self, fault: Option<Fault>) {
+        *self.default_fault.lock() = fault;
+    }
+
+    pub fn clear(&self) {
+        self.faults.lock().clear();
+        *self.default_fault.lock() = None;
+    }
+
+    /// Returns the response which replaces the server answer
+    pub(crate) async fn apply(&self) -> Option<Result<ton::TLObject>> {
+        let fault = match self.faults.lock().pop_front() {
+            Some(fault) => fault,
+            None => (*self.default_fault.lock())?,
+        };
+
+        match fault {
+            Fault::Delay(duration) => {
+                tokio::time::sleep(duration).await;
+                None
+            }
+            Fault::NotReady => Some(Ok(ton::TLObject::new(ton::lite_server::Error::LiteServer_Error(
+                ton::lite_server::error::Error {
+                    code: ERR_NOT_READY,
+                    message: "injected".to_owned(),
+                },
+            )))),
+            Fault::DropConnection => Some(Err(anyhow::anyhow!("Injected connection drop"))),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Instant;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn faults_applied_in_order() {
+        let injector = FaultInjector::default();
+        injector.push(Fault::Delay(Duration::from_millis(10)));
+        injector.push(Fault::NotReady);
+        injector.push(Fault::DropConnection);
+
+        let started_at = Instant::now();
+        assert!(injector.apply().await.is_none());
+        assert!(started_at.elapsed() >= Duration::from_millis(10));
+
+        let response = injector.apply().await.unwrap().unwrap();
+        let error = response.downcast::<ton::lite_server::Error>().unwrap();
+        assert_eq!(error.code(), &ERR_NOT_READY);
+
+        assert!(matches!(injector.apply().await, Some(Err(_))));
+        assert!(injector.apply().await.is_none());
+    }
+
+    #[tokio::test]
+    async fn default_fault() {
+        let injector = FaultInjector::default();
+        injector.set_default(Some(Fault::DropConnection));
+        injector.push(Fault::Delay(Duration::from_millis(0)));
+
+        assert!(injector.apply().await.is_none());
+        assert!(matches!(injector.apply().await, Some(Err(_))));
+        assert!(matches!(injector.apply().await, Some(Err(_))));
+
+        injector.clear();
+        assert!(injector.apply().await.is_none());
+    }
+}
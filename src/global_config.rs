@@ -0,0 +1,261 @@
+use std::net::{Ipv4Addr, SocketAddrV4};
+
+use anyhow::Result;
+use serde::Deserialize;
+use ton_api::ton;
+
+/// Standard TON global config (only fields used by the client)
+#[derive(Debug, Clone, Deserialize)]
+pub struct GlobalConfig {
+    pub liteservers: Vec<LiteServerDescription>,
+    #[serde(default)]
+    pub validator: Option<ValidatorConfig>,
+}
+
+impl GlobalConfig {
+    pub fn from_slice(data: &[u8]) -> Result<Self> {
+        Ok(serde_json::from_slice(data)?)
+    }
+
+    pub fn lite_servers(&self) -> Vec<crate::LiteServerConfig> {
+        self.liteservers
+            .iter()
+            .map(|server| crate::LiteServerConfig {
+                address: server.address().into(),
+                key: server.id.key.clone(),
+            })
+            .collect()
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct LiteServerDescription {
+    pub ip: i32,
+    pub port: u16,
+    pub id: LiteServerKey,
+}
+
+impl LiteServerDescription {
+    pub fn address(&self) -> SocketAddrV4 {
+        SocketAddrV4::new(Ipv4Addr::from(self.ip as u32), self.port)
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct LiteServerKey {
+    /// Base64 encoded ed25519 public key
+    pub key: String,
+}
+
+/// Trust anchors of the network
+#[derive(Debug, Clone, Deserialize)]
+pub struct ValidatorConfig {
+    pub zero_state: BlockIdConfig,
+    #[serde(default)]
+    pub init_block: Option<BlockIdConfig>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct BlockIdConfig {
+    pub workchain: i32,
+    pub shard: i64,
+    pub seqno: i32,
+    /// Base64 encoded hash
+    pub root_hash: String,
+    /// Base64 encoded hash
+    pub file_hash: String,
+}
+
+impl BlockIdConfig {
+    pub fn to_block_id(&self) -> Result<ton::ton_node::blockidext::BlockIdExt> {
+        Ok(ton::ton_node::blockidext::BlockIdExt {
+            workchain: self.workchain,
+            shard: self.shard,
+            seqno: self.seqno,
+            root_hash: ton::int256(parse_hash(&self.root_hash)?),
+            file_hash: ton::int256(parse_hash(&self.file_hash)?),
+        })
+    }
+}
+
+fn parse_hash(hash: &str) -> Result<[u8; 32]> {
+    let hash = base64::decode(hash)?;
+    if hash.len() != 32 {
+        return Err(anyhow::anyhow!("Invalid hash length"));
+    }
+
+    let mut result = [0; 32];
+    result.copy_from_slice(&hash);
+    Ok(result)
+}
+
+/// Downloads global config and verifies its sha256 hash if specified
+#[cfg(feature = "fetch")]
+pub async fn fetch_global_config(url: &str, expected_hash: Option<&[u8; 32]>) -> Result<GlobalConfig> {
+    use sha2::Digest;
+
+    let data = reqwest::get(url).await?.error_for_status()?.bytes().await?;
+
+    if let Some(expected_hash) = expected_hash {
+        if sha2::Sha256::digest(&data).as_slice() != expected_hash {
+            return Err(anyhow::anyhow!("Global config hash mismatch"));
+        }
+    }
+
+    GlobalConfig::from_slice(&data)
+}
+
+/// Additional check of the downloaded global config, e.g. against the list of allowed servers
+pub type GlobalConfigVerifier = Box<dyn Fn(&GlobalConfig) -> Result<()> + Send + Sync>;
+
+/// Periodically downloads global config and applies it to the client.
+///
+/// The config is rejected if its zero state differs from the configured one or if the `verifier` fails.
+/// The task stops when the client is dropped
+#[cfg(feature = "fetch")]
+pub fn spawn_global_config_refresh(
+    client: &std::sync::Arc<crate::TonlibClient>,
+    url: String,
+    interval: std::time::Duration,
+    verifier: Option<GlobalConfigVerifier>,
+) -> tokio::task::JoinHandle<()> {
+    let client = std::sync::Arc::downgrade(client);
+
+    tokio::spawn(async move {
+        loop {
+            tokio::time::sleep(interval).await;
+
+            let global_config = match fetch_global_config(&url, None).await {
+                Ok(global_config) => global_config,
+                Err(e) => {
+                    log::warn!("Failed to fetch global config: {:?}", e);
+                    continue;
+                }
+            };
+
+            if let Some(verifier) = &verifier {
+                if let Err(e) = verifier(&global_config) {
+                    log::warn!("Rejected global config: {:?}", e);
+                    continue;
+                }
+            }
+
+            let client = match client.upgrade() {
+                Some(client) => client,
+                None => return,
+            };
+
+            if let Err(e) = client.update_from_global_config(&global_config).await {
+                log::warn!("Failed to apply global config: {:?}", e);
+            }
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use super::*;
+    use crate::{Config, Fixtures, LastBlock, ReplayContext, ReplayTransport, TonlibClient, TonlibError};
+
+    fn make_global_config(zero_state_root_hash: &str) -> GlobalConfig {
+        GlobalConfig::from_slice(
+            format!(
+                r#"{{
+                    "liteservers": [
+                        {{ "ip": 908566172, "port": 7742, "id": {{ "key": "peJTw/arlRfssgTuf9BMypJzqOi7SXEqSPSWiEw2U1M=" }} }}
+                    ],
+                    "validator": {{
+                        "zero_state": {{
+                            "workchain": -1,
+                            "shard": -9223372036854775808,
+                            "seqno": 0,
+                            "root_hash": "{}",
+                            "file_hash": "XplPz01CXAps5qeSWUtxcyBfdAo5zVb1N979KLSKD24="
+                        }}
+                    }}
+                }}"#,
+                zero_state_root_hash
+            )
+            .as_bytes(),
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn parse_global_config() {
+        let global_config = GlobalConfig::from_slice(
+            br#"{
+                "@type": "config.global",
+                "liteservers": [
+                    {
+                        "ip": -2018135749,
+                        "port": 53312,
+                        "id": {
+                            "@type": "pub.ed25519",
+                            "key": "aF91CuUHuuOv9rm2W5+O/4h38M3sRm40DtSdRxQhmtQ="
+                        }
+                    },
+                    {
+                        "ip": 908566172,
+                        "port": 7742,
+                        "id": {
+                            "@type": "pub.ed25519",
+                            "key": "peJTw/arlRfssgTuf9BMypJzqOi7SXEqSPSWiEw2U1M="
+                        }
+                    }
+                ],
+                "validator": {
+                    "@type": "validator.config.global",
+                    "zero_state": {
+                        "workchain": -1,
+                        "shard": -9223372036854775808,
+                        "seqno": 0,
+                        "root_hash": "F6OpKZKqvqeFp6CQmFomXNMfMj2EnaUSOXN+Mh+wVWk=",
+                        "file_hash": "XplPz01CXAps5qeSWUtxcyBfdAo5zVb1N979KLSKD24="
+                    }
+                }
+            }"#,
+        )
+        .unwrap();
+
+        assert_eq!(global_config.liteservers.len(), 2);
+        assert_eq!(global_config.liteservers[0].address(), "135.181.177.59:53312".parse().unwrap());
+        assert_eq!(global_config.liteservers[1].address(), "54.39.158.156:7742".parse().unwrap());
+        assert_eq!(global_config.liteservers[1].id.key, "peJTw/arlRfssgTuf9BMypJzqOi7SXEqSPSWiEw2U1M=");
+
+        let zero_state = global_config.validator.unwrap().zero_state.to_block_id().unwrap();
+        assert_eq!(zero_state.workchain, -1);
+        assert_eq!(zero_state.seqno, 0);
+    }
+
+    #[tokio::test]
+    async fn reject_other_zero_state() {
+        let global_config = make_global_config("F6OpKZKqvqeFp6CQmFomXNMfMj2EnaUSOXN+Mh+wVWk=");
+        let config = Config {
+            servers: vec![crate::LiteServerConfig {
+                address: "127.0.0.1:3031".parse().unwrap(),
+                key: "uNRRL+6enQjuiZ/s6Z+vO7yxUUR7uxdfzIy+RxkECrc=".to_owned(),
+            }],
+            zero_state: Some(global_config.validator.as_ref().unwrap().zero_state.to_block_id().unwrap()),
+            ..Default::default()
+        };
+        let last_block = Arc::new(LastBlock::new(&config.last_block_threshold));
+        let fixtures = Fixtures::default();
+        let client = TonlibClient::<ReplayTransport>::with_transport(&config, last_block, ReplayContext::new(&fixtures).unwrap())
+            .await
+            .unwrap();
+
+        let other = make_global_config("AAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAA=");
+        let error = client.update_from_global_config(&other).await.unwrap_err();
+        assert!(matches!(
+            error.downcast_ref::<TonlibError>(),
+            Some(TonlibError::ZeroStateMismatch { address }) if *address == other.liteservers[0].address()
+        ));
+        assert_eq!(client.servers.addresses(), vec!["127.0.0.1:3031".parse().unwrap()]);
+
+        client.update_from_global_config(&global_config).await.unwrap();
+        assert_eq!(client.servers.addresses(), vec![global_config.liteservers[0].address()]);
+    }
+}
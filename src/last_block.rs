@@ -1,103 +1,524 @@
 use std::collections::VecDeque;
-use std::sync::atomic::{AtomicBool, Ordering};
-use std::time::{Duration, Instant};
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 
-use bb8::PooledConnection;
+use bb8::Pool;
 use ton_api::ton;
 use ton_api::ton::ton_node::blockidext::BlockIdExt;
 
 use crate::connection::*;
 use crate::errors::*;
 use crate::pool::AdnlManageConnection;
+use crate::transport::AdnlTransport;
 
+/// Tracks the last masterchain block.
+///
+/// Can be shared between clients of the same network to avoid redundant polling
 pub struct LastBlock {
-    state: parking_lot::RwLock<LastBlockState>,
+    shared: Arc<SharedState>,
     threshold: Duration,
-    in_process: AtomicBool,
+    max_block_age: Option<Duration>,
+    /// Capacity of the recent blocks buffer
+    recent_block_count: usize,
+    stale_while_revalidate: bool,
+    /// Age after which the expired block is not returned while revalidating
+    max_stale_age: Duration,
+    /// Number of the running background refresh tasks
+    refreshers: AtomicUsize,
+    /// Longest interval of the background refresh tasks in milliseconds
+    refresh_interval: AtomicU64,
+    /// Keeps the channel open when there are no subscribers
+    new_blocks_rx: tokio::sync::watch::Receiver<Option<BlockIdExt>>,
 }
 
 impl LastBlock {
     pub fn new(threshold: &Duration) -> Self {
+        let (new_blocks, new_blocks_rx) = tokio::sync::watch::channel(None);
         Self {
-            state: parking_lot::RwLock::new(LastBlockState::new()),
+            shared: Arc::new(SharedState {
+                state: parking_lot::RwLock::new(LastBlockState::new()),
+                in_process: AtomicBool::new(false),
+                new_blocks,
+            }),
             threshold: *threshold,
-            in_process: AtomicBool::new(false),
+            max_block_age: None,
+            recent_block_count: DEFAULT_RECENT_BLOCK_COUNT,
+            stale_while_revalidate: false,
+            max_stale_age: DEFAULT_MAX_STALE_AGE,
+            refreshers: AtomicUsize::new(0),
+            refresh_interval: AtomicU64::new(0),
+            new_blocks_rx,
         }
     }
 
+    /// Makes `get_last_block` return the expired block immediately and refresh it in background
+    pub fn with_stale_while_revalidate(mut self, enabled: bool) -> Self {
+        self.stale_while_revalidate = enabled;
+        self
+    }
+
+    /// Sets the age after which the expired block is requested synchronously
+    /// even if `stale_while_revalidate` is enabled
+    pub fn with_max_stale_age(mut self, max_stale_age: Duration) -> Self {
+        self.max_stale_age = max_stale_age;
+        self
+    }
+
+    /// Makes `get_last_block` fail with `NodeOutOfSync` error if the last block
+    /// was generated earlier than `max_block_age` ago
+    pub fn with_max_block_age(mut self, max_block_age: Option<Duration>) -> Self {
+        self.max_block_age = max_block_age;
+        self
+    }
+
+    /// Sets the number of the recent masterchain blocks to keep. At least one block is kept
+    pub fn with_recent_block_count(mut self, count: usize) -> Self {
+        self.recent_block_count = std::cmp::max(count, 1);
+        self
+    }
+
+    /// Returns receiver of the newest observed masterchain block.
+    ///
+    /// Intermediate blocks are skipped if the receiver lags behind
+    pub fn subscribe(&self) -> tokio::sync::watch::Receiver<Option<BlockIdExt>> {
+        self.new_blocks_rx.clone()
+    }
+
     pub async fn last_cached_blocks(&self) -> impl Iterator<Item = BlockIdExt> {
-        self.state.read().blocks.clone().into_iter()
+        self.recent_blocks().into_iter().map(|block| block.id)
+    }
+
+    /// Seqno of the newest observed masterchain block. Doesn't request the server
+    pub fn latest_seqno(&self) -> Option<u32> {
+        self.shared.state.read().blocks.front().map(|block| block.id.seqno as u32)
+    }
+
+    /// Returns the recently observed masterchain blocks from the newest to the oldest
+    pub fn recent_blocks(&self) -> Vec<RecentBlock> {
+        self.shared.state.read().blocks.iter().cloned().collect()
     }
 
-    pub async fn get_last_block(
-        &self,
-        connection: &mut PooledConnection<'_, AdnlManageConnection>,
-    ) -> TonlibResult<ton::ton_node::blockidext::BlockIdExt> {
-        let now = {
-            let state = self.state.read();
+    #[cfg_attr(feature = "tracing", tracing::instrument(skip_all))]
+    pub async fn get_last_block<A>(&self, pool: &Pool<AdnlManageConnection<A>>) -> TonlibResult<ton::ton_node::blockidext::BlockIdExt>
+    where
+        A: AdnlTransport,
+    {
+        let (now, in_process) = {
+            let state = self.shared.state.read();
 
             let now = Instant::now();
 
             match &state.id {
                 Some((result, last)) => {
-                    if now.duration_since(*last) < self.threshold
-                        || self
-                            .in_process
-                            .compare_exchange(false, true, Ordering::Acquire, Ordering::Relaxed)
-                            .is_err()
-                    {
-                        return result.clone();
+                    // Successful result is kept up to date by the background task,
+                    // unless the task is stuck and the block is much older than the refresh interval
+                    let refreshed_in_background = result.is_ok()
+                        && self.refreshers.load(Ordering::Acquire) > 0
+                        && now.duration_since(*last) < self.background_refresh_max_age();
+                    if refreshed_in_background || now.duration_since(*last) < self.threshold {
+                        return self.check_block_age(result.clone(), &state);
                     }
-                    now
+
+                    let in_process = match self.begin_update() {
+                        Some(in_process) => in_process,
+                        None => return self.check_block_age(result.clone(), &state),
+                    };
+                    // Too old block is requested synchronously, as it was probably unused for a long time
+                    if self.stale_while_revalidate && result.is_ok() && now.duration_since(*last) < self.max_stale_age {
+                        self.spawn_revalidate(pool.clone(), now, in_process);
+                        return self.check_block_age(result.clone(), &state);
+                    }
+                    (now, Some(in_process))
                 }
-                None => now,
+                None => (now, None),
             }
         };
 
         log::debug!("Getting mc block");
 
-        let id = query(connection, &ton::rpc::lite_server::GetMasterchainInfo)
-            .await
-            .and_then(QueryReply::try_into_data)
-            .map(|result| result.only().last);
+        let fetched = match fetch_last_block(pool).await {
+            Err(TonlibError::ConnectionError) => fetch_last_block(pool).await,
+            result => result,
+        };
 
         log::debug!("Got mc block");
 
-        let mut state = self.state.write();
+        let id = self.publish(fetched, now);
+        drop(in_process);
 
-        state.id = Some((id.clone(), now));
+        self.check_block_age(id, &self.shared.state.read())
+    }
 
-        if let Ok(new_id) = &id {
-            match state.blocks.front() {
-                Some(latest_id) if new_id.seqno > latest_id.seqno => {
-                    if state.blocks.len() >= MAX_ENQUEUED_BLOCKS {
-                        state.blocks.pop_back();
-                    }
-                    state.blocks.push_front(new_id.clone());
+    /// Requests the last block from the server regardless of the threshold
+    pub async fn get_fresh_last_block<A>(&self, pool: &Pool<AdnlManageConnection<A>>) -> TonlibResult<BlockIdExt>
+    where
+        A: AdnlTransport,
+    {
+        let id = self.publish(fetch_last_block(pool).await, Instant::now());
+        self.check_block_age(id, &self.shared.state.read())
+    }
+
+    /// Acquires `in_process` flag. Returns `None` if the block is already being requested
+    fn begin_update(&self) -> Option<InProcessGuard> {
+        self.shared
+            .in_process
+            .compare_exchange(false, true, Ordering::Acquire, Ordering::Relaxed)
+            .ok()
+            .map(|_| InProcessGuard(self.shared.clone()))
+    }
+
+    /// Refreshes the last block in background. The flag is released when the task is finished
+    fn spawn_revalidate<A>(&self, pool: Pool<AdnlManageConnection<A>>, now: Instant, in_process: InProcessGuard)
+    where
+        A: AdnlTransport,
+    {
+        let recent_block_count = self.recent_block_count;
+        tokio::spawn(async move {
+            // Expired block is kept on error, so the next call will try again
+            match fetch_last_block(&pool).await {
+                Ok(fetched) => {
+                    let _ = in_process.0.publish(Ok(fetched), now, recent_block_count);
                 }
-                None => state.blocks.push_front(new_id.clone()),
-                _ => {}
+                Err(e) => log::warn!("Failed to revalidate mc block: {:?}", e),
+            }
+        });
+    }
+
+    /// Age after which the block refreshed in background is requested on demand
+    fn background_refresh_max_age(&self) -> Duration {
+        let interval = Duration::from_millis(self.refresh_interval.load(Ordering::Acquire));
+        std::cmp::max(interval * BACKGROUND_REFRESH_TOLERANCE, self.threshold)
+    }
+
+    /// Server time minus local time in seconds, measured with the last block request
+    pub fn clock_skew(&self) -> Option<i64> {
+        self.shared.state.read().clock_skew
+    }
+
+    /// Compares generation time of the latest block with the server time
+    fn check_block_age(&self, id: TonlibResult<BlockIdExt>, state: &LastBlockState) -> TonlibResult<BlockIdExt> {
+        let (id, max_block_age) = match (id, self.max_block_age) {
+            (Ok(id), Some(max_block_age)) => (id, max_block_age),
+            (id, _) => return id,
+        };
+
+        let now = unix_time() + state.clock_skew.unwrap_or_default();
+        let gen_utime = state.blocks.front().map(|block| block.gen_utime).unwrap_or_default();
+        let lag = Duration::from_secs(std::cmp::max(now - gen_utime as i64, 0) as u64);
+        if lag > max_block_age {
+            Err(TonlibError::NodeOutOfSync { lag })
+        } else {
+            Ok(id)
+        }
+    }
+
+    /// Requests the last block and publishes it if succeeded
+    async fn refresh<A>(&self, pool: &Pool<AdnlManageConnection<A>>)
+    where
+        A: AdnlTransport,
+    {
+        let now = Instant::now();
+        if let Err(e) = self.publish(fetch_last_block(pool).await, now) {
+            log::warn!("Failed to refresh mc block: {:?}", e);
+        }
+    }
+
+    fn publish(&self, fetched: TonlibResult<FetchedBlock>, now: Instant) -> TonlibResult<BlockIdExt> {
+        self.shared.publish(fetched, now, self.recent_block_count)
+    }
+}
+
+/// Part of the tracker which is updated by the background revalidation
+struct SharedState {
+    state: parking_lot::RwLock<LastBlockState>,
+    in_process: AtomicBool,
+    new_blocks: tokio::sync::watch::Sender<Option<BlockIdExt>>,
+}
+
+impl SharedState {
+    /// Keeps the highest block received from the servers, so a lagging server
+    /// doesn't make the client use the stale one
+    fn publish(&self, fetched: TonlibResult<FetchedBlock>, now: Instant, recent_block_count: usize) -> TonlibResult<BlockIdExt> {
+        let mut state = self.state.write();
+
+        let fetched = match fetched {
+            Ok(fetched) => fetched,
+            Err(e) => {
+                state.id = Some((Err(e.clone()), now));
+                return Err(e);
+            }
+        };
+
+        let new_id = fetched.id;
+        state.id = Some((Ok(new_id.clone()), now));
+        state.clock_skew = Some(fetched.clock_skew);
+
+        if let Some(latest) = state.blocks.front() {
+            if new_id.seqno < latest.id.seqno {
+                let latest_id = latest.id.clone();
+                log::debug!("Got stale mc block {}, using {}", new_id.seqno, latest_id.seqno);
+                state.id = Some((Ok(latest_id.clone()), now));
+                return Ok(latest_id);
+            } else if new_id.seqno == latest.id.seqno {
+                return Ok(new_id);
             }
         }
 
-        self.in_process.store(false, Ordering::Release);
+        if state.blocks.len() >= recent_block_count {
+            state.blocks.pop_back();
+        }
+        state.blocks.push_front(RecentBlock {
+            id: new_id.clone(),
+            gen_utime: fetched.gen_utime,
+        });
+
+        // Receiver is stored in the tracker, so the channel is never closed
+        let _ = self.new_blocks.send(Some(new_id.clone()));
+        Ok(new_id)
+    }
+}
+
+/// Releases `in_process` flag when the request is finished or cancelled,
+/// e.g. by a timeout or when the hedged query loses
+struct InProcessGuard(Arc<SharedState>);
+
+impl Drop for InProcessGuard {
+    fn drop(&mut self) {
+        self.0.in_process.store(false, Ordering::Release);
+    }
+}
+
+/// Periodically refreshes the last block, so that `get_last_block` returns it without waiting
+pub fn spawn_last_block_refresh<A>(
+    last_block: Arc<LastBlock>,
+    pool: Pool<AdnlManageConnection<A>>,
+    interval: Duration,
+) -> futures::future::AbortHandle
+where
+    A: AdnlTransport,
+{
+    last_block.refreshers.fetch_add(1, Ordering::AcqRel);
+    last_block.refresh_interval.fetch_max(interval.as_millis() as u64, Ordering::AcqRel);
+    let guard = RefresherGuard(last_block);
+
+    let (task, handle) = futures::future::abortable(async move {
+        loop {
+            guard.0.refresh(&pool).await;
+            tokio::time::sleep(interval).await;
+        }
+    });
+
+    tokio::spawn(task);
+    handle
+}
+
+/// Switches the last block back to the lazy refresh when the task is stopped
+struct RefresherGuard(Arc<LastBlock>);
 
-        id
+impl Drop for RefresherGuard {
+    fn drop(&mut self) {
+        self.0.refreshers.fetch_sub(1, Ordering::AcqRel);
     }
 }
 
+struct FetchedBlock {
+    id: BlockIdExt,
+    gen_utime: u32,
+    /// Server time minus local time in seconds
+    clock_skew: i64,
+}
+
+/// Requests the last masterchain block with its generation time and the server time
+/// and reports its seqno to detect stale servers
+async fn fetch_last_block<A>(pool: &Pool<AdnlManageConnection<A>>) -> TonlibResult<FetchedBlock>
+where
+    A: AdnlTransport,
+{
+    let connection = acquire_connection(pool).await?;
+    let info = query(&connection, &ton::rpc::lite_server::GetMasterchainInfoExt { mode: 0 })
+        .await?
+        .try_into_data()?
+        .only();
+
+    connection.report_last_seqno(info.last.seqno as u32);
+    Ok(FetchedBlock {
+        id: info.last,
+        gen_utime: info.last_utime as u32,
+        clock_skew: info.now as i64 - unix_time(),
+    })
+}
+
+fn unix_time() -> i64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_secs() as i64
+}
+
+/// Masterchain block observed by the client
+#[derive(Debug, Clone)]
+pub struct RecentBlock {
+    pub id: BlockIdExt,
+    /// Generation time of the block
+    pub gen_utime: u32,
+}
+
 struct LastBlockState {
     id: Option<(TonlibResult<BlockIdExt>, Instant)>,
-    blocks: VecDeque<BlockIdExt>,
+    blocks: VecDeque<RecentBlock>,
+    clock_skew: Option<i64>,
 }
 
 impl LastBlockState {
     fn new() -> Self {
         Self {
             id: None,
-            blocks: VecDeque::with_capacity(MAX_ENQUEUED_BLOCKS),
+            blocks: VecDeque::with_capacity(DEFAULT_RECENT_BLOCK_COUNT),
+            clock_skew: None,
         }
     }
 }
 
-const MAX_ENQUEUED_BLOCKS: usize = 5;
+const DEFAULT_RECENT_BLOCK_COUNT: usize = 5;
+
+const DEFAULT_MAX_STALE_AGE: Duration = Duration::from_secs(30);
+
+/// Number of the missed background refreshes after which the block is requested on demand
+const BACKGROUND_REFRESH_TOLERANCE: u32 = 3;
+
+#[cfg(test)]
+mod tests {
+    use std::str::FromStr;
+
+    use ton_block::MsgAddressInt;
+
+    use super::*;
+    use crate::replay::{Fixtures, ReplayContext, ReplayTransport};
+    use crate::{Config, LiteServerConfig, TonlibClient};
+
+    /// Synthetic responses with the masterchain blocks 1000 and 1001
+    async fn make_client(last_block: LastBlock) -> TonlibClient<ReplayTransport> {
+        let fixtures = Fixtures::load(concat!(env!("CARGO_MANIFEST_DIR"), "/fixtures/last_block.json")).unwrap();
+
+        let config = Config {
+            servers: vec![LiteServerConfig {
+                address: "127.0.0.1:3031".parse().unwrap(),
+                key: "uNRRL+6enQjuiZ/s6Z+vO7yxUUR7uxdfzIy+RxkECrc=".to_owned(),
+            }],
+            ..Default::default()
+        };
+        TonlibClient::<ReplayTransport>::with_transport(&config, Arc::new(last_block), ReplayContext::new(&fixtures).unwrap())
+            .await
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn revalidate_stale_block() {
+        let client = make_client(LastBlock::new(&Duration::from_secs(0)).with_stale_while_revalidate(true)).await;
+        let last_block = &client.last_block;
+
+        assert_eq!(last_block.get_last_block(&client.pool).await.unwrap().seqno, 1000);
+
+        // Expired block is returned immediately and refreshed in background
+        assert_eq!(last_block.get_last_block(&client.pool).await.unwrap().seqno, 1000);
+        for _ in 0..100 {
+            if !last_block.shared.in_process.load(Ordering::Acquire) {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        assert!(!last_block.shared.in_process.load(Ordering::Acquire));
+        assert_eq!(last_block.latest_seqno(), Some(1001));
+    }
+
+    #[tokio::test]
+    async fn fetch_too_stale_block() {
+        let client = make_client(
+            LastBlock::new(&Duration::from_secs(0))
+                .with_stale_while_revalidate(true)
+                .with_max_stale_age(Duration::from_secs(0)),
+        )
+        .await;
+        let last_block = &client.last_block;
+
+        assert_eq!(last_block.get_last_block(&client.pool).await.unwrap().seqno, 1000);
+        assert_eq!(last_block.get_last_block(&client.pool).await.unwrap().seqno, 1001);
+    }
+
+    #[tokio::test]
+    async fn out_of_sync_error() {
+        let client = make_client(LastBlock::new(&Duration::from_secs(0)).with_max_block_age(Some(Duration::from_secs(0)))).await;
+
+        let account = MsgAddressInt::from_str("0:7777777777777777777777777777777777777777777777777777777777777777").unwrap();
+        let error = client.get_account_state(&account).await.unwrap_err();
+        assert!(matches!(
+            error.downcast_ref::<crate::TonlibError>(),
+            Some(crate::TonlibError::NodeOutOfSync { .. })
+        ));
+    }
+
+    /// Answers the first query with an error and never answers the next ones
+    struct SilentTransport {
+        queries: Arc<AtomicUsize>,
+    }
+
+    #[async_trait::async_trait]
+    impl AdnlTransport for SilentTransport {
+        type Context = Arc<AtomicUsize>;
+
+        async fn connect(_: tiny_adnl::AdnlTcpClientConfig, context: &Self::Context) -> anyhow::Result<Arc<Self>> {
+            Ok(Arc::new(Self { queries: context.clone() }))
+        }
+
+        async fn query(&self, _: &ton::TLObject) -> anyhow::Result<ton::TLObject> {
+            if self.queries.fetch_add(1, Ordering::AcqRel) == 0 {
+                return Ok(ton::TLObject::new(ton::lite_server::Error::LiteServer_Error(
+                    ton::lite_server::error::Error {
+                        code: 1,
+                        message: String::new(),
+                    },
+                )));
+            }
+            futures::future::pending().await
+        }
+
+        async fn ping(&self, _: Duration) -> anyhow::Result<()> {
+            Ok(())
+        }
+
+        fn is_broken(&self) -> bool {
+            false
+        }
+
+        fn mark_broken(&self) {}
+    }
+
+    #[tokio::test]
+    async fn cancelled_request() {
+        let config = Config {
+            servers: vec![LiteServerConfig {
+                address: "127.0.0.1:3031".parse().unwrap(),
+                key: "uNRRL+6enQjuiZ/s6Z+vO7yxUUR7uxdfzIy+RxkECrc=".to_owned(),
+            }],
+            ..Default::default()
+        };
+        let queries = Arc::new(AtomicUsize::new(0));
+        let last_block = Arc::new(LastBlock::new(&Duration::from_secs(0)));
+        let client = TonlibClient::<SilentTransport>::with_transport(&config, last_block, queries.clone())
+            .await
+            .unwrap();
+        let last_block = &client.last_block;
+
+        assert!(last_block.get_last_block(&client.pool).await.is_err());
+
+        let timeout = Duration::from_millis(50);
+        assert!(tokio::time::timeout(timeout, last_block.get_last_block(&client.pool))
+            .await
+            .is_err());
+        assert!(!last_block.shared.in_process.load(Ordering::Acquire));
+
+        // The cancelled request doesn't make the next call return the old error
+        assert!(tokio::time::timeout(timeout, last_block.get_last_block(&client.pool))
+            .await
+            .is_err());
+        assert_eq!(queries.load(Ordering::Acquire), 3);
+    }
+}
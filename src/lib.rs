@@ -1,55 +1,1361 @@
+mod activity;
+mod address_book;
+mod address_watcher;
+mod attestation;
+#[cfg(feature = "bench")]
+mod bench;
+mod block_stream;
 mod connection;
+mod consumer;
 mod errors;
+pub mod export;
+#[cfg(feature = "fault-injection")]
+pub mod fault_injection;
+mod global_config;
 mod last_block;
+pub mod ops;
 mod pool;
+mod proof;
+mod rate_limit;
+mod registry;
+mod replay;
+mod retry;
+mod stack;
+mod stats;
+#[cfg(feature = "metrics")]
+mod telemetry;
+mod transport;
 pub mod utils;
 
-use std::convert::TryFrom;
-use std::net::SocketAddrV4;
-use std::time::Duration;
+use std::collections::VecDeque;
+use std::net::{Ipv4Addr, SocketAddrV4};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
 
 use anyhow::Result;
-use bb8::{Pool, PooledConnection};
-use tiny_adnl::AdnlTcpClientConfig;
+use bb8::Pool;
+use futures::Stream;
+use tiny_adnl::AdnlTcpClient;
 use ton_api::ton;
-use ton_block::{AccountStuff, Deserializable, MsgAddrStd, MsgAddressInt, Transaction};
+use ton_block::{AccountStuff, CurrencyCollection, Deserializable, Grams, MsgAddrStd, MsgAddressInt, Transaction};
 use ton_types::UInt256;
 
+pub use crate::activity::{AccountActivity, ActivitySummary};
+pub use crate::address_book::AddressBook;
+pub use crate::address_watcher::{filter_block_transactions, AccountTransactions, AddressSetEvent, AddressSetWatcher};
+pub use crate::attestation::{AttestedAccountState, Attestor};
+#[cfg(feature = "bench")]
+pub use crate::bench::{run_workload, LoadConfig, LoadReport, Workload};
+pub use crate::block_stream::{BlockEvent, BlockStream, BlockStreamConfig};
 use crate::connection::*;
+pub use crate::consumer::TransactionConsumer;
 use crate::errors::*;
-use crate::last_block::*;
+pub use crate::errors::{TonlibError, TonlibResult};
+pub use crate::global_config::*;
+use crate::last_block::spawn_last_block_refresh;
+pub use crate::last_block::{LastBlock, RecentBlock};
 use crate::pool::*;
+pub use crate::pool::{CircuitBreakerConfig, CircuitState, ServerHealth};
+pub use crate::proof::{verify_proof_bundle, ShardBlockLink, ShardBlockProof, TransactionProof};
+use crate::rate_limit::RateLimiter;
+pub use crate::rate_limit::{QueryPermit, RateLimit, RateLimitConfig, RateLimitMode};
+pub use crate::registry::{ClientRegistry, Network};
+pub use crate::replay::{Fixtures, RecordingContext, RecordingTransport, ReplayContext, ReplayTransport};
+pub use crate::retry::RetryPolicy;
+pub use crate::stack::{StackBuilder, StackReader, StackValue};
+use crate::stats::ConnectionStats;
+pub use crate::stats::{ErrorCounters, LatencySummary, Stats, Traffic};
+pub use crate::transport::AdnlTransport;
 
-pub struct TonlibClient {
-    pool: Pool<AdnlManageConnection>,
-    last_block: LastBlock,
+/// Lite client. The transport can be replaced, e.g. with a mock in tests
+pub struct TonlibClient<A: AdnlTransport = AdnlTcpClient> {
+    pool: Pool<AdnlManageConnection<A>>,
+    /// Pool of archival servers, used for the old history
+    archive_pool: Option<Pool<AdnlManageConnection<A>>>,
+    last_block: Arc<LastBlock>,
+    servers: Arc<ServerSet>,
+    archive_servers: Option<Arc<ServerSet>>,
+    /// Spare connections to the regular servers, if `Config::warm_standby` is enabled
+    standby: Option<Arc<Standby<A>>>,
+    stats: Arc<ConnectionStats>,
+    trust_mode: TrustMode,
+    hedge_delay: Option<Duration>,
+    wait_masterchain_seqno_timeout: Option<Duration>,
+    min_idle_connection_count: u32,
+    zero_state: Option<ton::ton_node::blockidext::BlockIdExt>,
+    context: Arc<A::Context>,
+    background_tasks: Vec<futures::future::AbortHandle>,
+}
+
+impl<A: AdnlTransport> Drop for TonlibClient<A> {
+    fn drop(&mut self) {
+        for task in &self.background_tasks {
+            task.abort();
+        }
+    }
 }
 
 impl TonlibClient {
     pub async fn new(config: &Config) -> Result<Self> {
-        let builder = Pool::builder();
-        let pool = builder
-            .max_size(config.max_connection_count)
-            .min_idle(config.min_idle_connection_count)
-            .max_lifetime(None)
-            .build(AdnlManageConnection::new(config)?)
+        let last_block = LastBlock::new(&config.last_block_threshold)
+            .with_max_block_age(config.max_block_age)
+            .with_recent_block_count(config.recent_block_count)
+            .with_stale_while_revalidate(config.stale_while_revalidate)
+            .with_max_stale_age(config.max_stale_age);
+        Self::with_last_block(config, Arc::new(last_block)).await
+    }
+
+    /// Creates client which uses the shared masterchain block tracker.
+    ///
+    /// `last_block_threshold`, `max_block_age`, `recent_block_count`, `stale_while_revalidate` and `max_stale_age`
+    /// from the config are ignored
+    pub async fn with_last_block(config: &Config, last_block: Arc<LastBlock>) -> Result<Self> {
+        Self::with_transport(config, last_block, ()).await
+    }
+
+    /// Returns crate version and build metadata
+    pub fn version_info() -> VersionInfo {
+        let mut features = Vec::new();
+        if cfg!(feature = "bench") {
+            features.push("bench");
+        }
+        if cfg!(feature = "fetch") {
+            features.push("fetch");
+        }
+        if cfg!(feature = "fault-injection") {
+            features.push("fault-injection");
+        }
+        if cfg!(feature = "metrics") {
+            features.push("metrics");
+        }
+        if cfg!(feature = "tracing") {
+            features.push("tracing");
+        }
+
+        VersionInfo {
+            version: env!("CARGO_PKG_VERSION"),
+            features,
+            ton_api_scheme: TON_API_SCHEME,
+        }
+    }
+}
+
+impl<A: AdnlTransport> TonlibClient<A> {
+    /// Same as `with_last_block`, but with the custom transport
+    #[cfg_attr(feature = "tracing", tracing::instrument(skip_all))]
+    pub async fn with_transport(config: &Config, last_block: Arc<LastBlock>, context: A::Context) -> Result<Self> {
+        let context = Arc::new(context);
+        let stats = Arc::new(ConnectionStats::default());
+
+        let servers = Arc::new(ServerSet::new(&config.servers, config).await?);
+        let rate_limiter = Arc::new(RateLimiter::new(config.rate_limit)?);
+
+        let standby = config.warm_standby.then(|| Arc::new(Standby::default()));
+        let pool = build_pool(
+            servers.clone(),
+            config,
+            stats.clone(),
+            rate_limiter.clone(),
+            context.clone(),
+            standby.clone(),
+        )
+        .await?;
+
+        let (archive_servers, archive_pool) = if config.archival_servers.is_empty() {
+            (None, None)
+        } else {
+            let archive_servers = Arc::new(ServerSet::new(&config.archival_servers, config).await?);
+            let archive_standby = config.warm_standby.then(|| Arc::new(Standby::default()));
+            let archive_pool = build_pool(
+                archive_servers.clone(),
+                config,
+                stats.clone(),
+                rate_limiter,
+                context.clone(),
+                archive_standby,
+            )
             .await?;
+            (Some(archive_servers), Some(archive_pool))
+        };
+
+        let mut background_tasks = Vec::new();
+        if let Some(interval) = config.keepalive_interval {
+            background_tasks.push(spawn_keepalive(pool.clone(), interval));
+            if let Some(archive_pool) = &archive_pool {
+                background_tasks.push(spawn_keepalive(archive_pool.clone(), interval));
+            }
+        }
+        if let Some(interval) = config.last_block_refresh_interval {
+            background_tasks.push(spawn_last_block_refresh(last_block.clone(), pool.clone(), interval));
+        }
 
         Ok(Self {
             pool,
-            last_block: LastBlock::new(&config.last_block_threshold),
+            archive_pool,
+            last_block,
+            servers,
+            archive_servers,
+            standby,
+            stats,
+            trust_mode: config.trust_mode,
+            hedge_delay: config.hedge_delay,
+            wait_masterchain_seqno_timeout: config.wait_masterchain_seqno_timeout,
+            min_idle_connection_count: config.min_idle_connection_count.unwrap_or_default(),
+            zero_state: config.zero_state.clone(),
+            context,
+            background_tasks,
+        })
+    }
+
+    /// Establishes `min_idle_connection_count` connections (at least one), resolves the last block
+    /// and checks the zero state if configured.
+    ///
+    /// Fails if no server is reachable
+    #[cfg_attr(feature = "tracing", tracing::instrument(skip_all))]
+    pub async fn warm_up(&self) -> Result<()> {
+        let count = std::cmp::max(self.min_idle_connection_count, 1);
+
+        // Connections are held simultaneously, so that each of them is established
+        let connections = futures::future::join_all((0..count).map(|_| self.pool.get())).await;
+        let established = connections.iter().filter(|connection| connection.is_ok()).count();
+        drop(connections);
+
+        log::debug!("Warmed up {} of {} connections", established, count);
+        if established == 0 {
+            return Err(TonlibError::ConnectionError.into());
+        }
+
+        self.last_block.get_last_block(&self.pool).await?;
+        self.check_zero_state().await
+    }
+
+    /// Checks that each configured server belongs to the network with the configured zero state.
+    ///
+    /// Every server is queried using a separate session. Unreachable servers are skipped
+    #[cfg_attr(feature = "tracing", tracing::instrument(skip_all))]
+    pub async fn check_zero_state(&self) -> Result<()> {
+        let expected = match &self.zero_state {
+            Some(zero_state) => zero_state,
+            None => return Ok(()),
+        };
+
+        let query = ton::rpc::lite_server::LookupBlock {
+            mode: 0x1,
+            id: ton::ton_node::blockid::BlockId {
+                workchain: expected.workchain,
+                shard: expected.shard,
+                seqno: expected.seqno,
+            },
+            lt: None,
+            utime: None,
+        };
+
+        let configs = self
+            .servers
+            .configs()
+            .into_iter()
+            .chain(self.archive_servers.iter().flat_map(|servers| servers.configs()));
+        for config in configs {
+            let address = config.server_address;
+            let zero_state = match A::connect(config, &self.context).await {
+                Ok(client) => query_session(client.as_ref(), &query).await?.only().id,
+                Err(e) => {
+                    log::warn!("Failed to check zero state of {}: {:?}", address, e);
+                    continue;
+                }
+            };
+
+            if &zero_state != expected {
+                return Err(TonlibError::ZeroStateMismatch { address }.into());
+            }
+        }
+        Ok(())
+    }
+
+    /// Replaces lite servers with the ones from the global config.
+    ///
+    /// If the zero state is configured, the global config must declare the same one.
+    /// Otherwise the servers are kept and `ZeroStateMismatch` is returned for the first server of the config
+    pub async fn update_from_global_config(&self, global_config: &GlobalConfig) -> Result<()> {
+        if let Some(expected) = &self.zero_state {
+            let zero_state = match &global_config.validator {
+                Some(validator) => Some(validator.zero_state.to_block_id()?),
+                None => None,
+            };
+
+            if zero_state.as_ref() != Some(expected) {
+                let address = global_config
+                    .liteservers
+                    .first()
+                    .map(LiteServerDescription::address)
+                    .unwrap_or_else(|| SocketAddrV4::new(Ipv4Addr::UNSPECIFIED, 0));
+                return Err(TonlibError::ZeroStateMismatch { address }.into());
+            }
+        }
+
+        self.update_servers(&global_config.lite_servers()).await
+    }
+
+    /// Replaces the list of lite servers.
+    ///
+    /// Connections to the retained servers are kept. Connections to the removed ones are drained:
+    /// in-flight queries are not interrupted, and the connections are closed when returned to the pool
+    pub async fn update_servers(&self, servers: &[LiteServerConfig]) -> Result<()> {
+        if self.servers.update(servers).await? {
+            log::info!("Switched to the servers {:?}", self.servers.addresses());
+            if let Some(standby) = &self.standby {
+                standby.retain_servers(&self.servers);
+            }
+        }
+        Ok(())
+    }
+
+    /// Returns receiver of the newest masterchain block observed by the client.
+    ///
+    /// Blocks are observed when the last block is refreshed, so `Config::last_block_refresh_interval`
+    /// should be set to receive them without other queries
+    pub fn subscribe_masterchain_blocks(&self) -> tokio::sync::watch::Receiver<Option<ton::ton_node::blockidext::BlockIdExt>> {
+        self.last_block.subscribe()
+    }
+
+    /// Returns the recently observed masterchain blocks from the newest to the oldest
+    pub fn recent_blocks(&self) -> Vec<RecentBlock> {
+        self.last_block.recent_blocks()
+    }
+
+    /// Checks which features are available with the connected servers, so that the application
+    /// can adapt instead of discovering failures at call time.
+    ///
+    /// Every regular server except for the ones with the open circuit is queried using a separate session,
+    /// a feature is reported only if all answered servers support it
+    #[cfg_attr(feature = "tracing", tracing::instrument(skip_all))]
+    pub async fn capabilities(&self) -> Capabilities {
+        let versions = futures::future::join_all(self.servers.available_configs().into_iter().map(|config| async move {
+            let address = config.server_address;
+            let result = match A::connect(config, &self.context).await {
+                Ok(client) => query_session(client.as_ref(), &ton::rpc::lite_server::GetVersion).await,
+                Err(_) => Err(TonlibError::ConnectionError),
+            };
+            match result {
+                Ok(version) => Some(version.only().capabilities),
+                Err(e) => {
+                    log::warn!("Failed to get version of {}: {:?}", address, e);
+                    None
+                }
+            }
+        }))
+        .await;
+        let server_capabilities = versions.into_iter().flatten().reduce(|all, capabilities| all & capabilities);
+
+        let archive_data = self.archive_servers.as_ref().map_or(false, |servers| {
+            servers.health().iter().any(|server| server.circuit != CircuitState::Open)
+        });
+
+        match server_capabilities {
+            Some(capabilities) => Capabilities {
+                connected: true,
+                proof_verification: self.trust_mode != TrustMode::TrustServer && capabilities & CAPABILITY_PROOF_CHAINS != 0,
+                archive_data,
+                run_get_method: capabilities & CAPABILITY_RUN_SMC_METHOD != 0,
+                server_capabilities: Some(capabilities),
+            },
+            None => Capabilities {
+                archive_data,
+                ..Default::default()
+            },
+        }
+    }
+
+    /// Returns masterchain block tracker, which can be shared with other clients
+    pub fn last_block(&self) -> &Arc<LastBlock> {
+        &self.last_block
+    }
+
+    /// Returns pool state, latency percentiles and error counters of the pooled connections and servers health
+    pub fn stats(&self) -> Stats {
+        let mut servers = self.servers.health();
+        if let Some(archive_servers) = &self.archive_servers {
+            servers.extend(archive_servers.health());
+        }
+
+        let mut pool_state = self.pool.state();
+        if let Some(archive_pool) = &self.archive_pool {
+            let archive_state = archive_pool.state();
+            pool_state.connections += archive_state.connections;
+            pool_state.idle_connections += archive_state.idle_connections;
+        }
+
+        Stats {
+            connections: pool_state.connections,
+            idle_connections: pool_state.idle_connections,
+            servers,
+            clock_skew: self.last_block.clock_skew(),
+            ..self.stats.snapshot()
+        }
+    }
+
+    #[cfg_attr(feature = "tracing", tracing::instrument(skip_all))]
+    pub async fn get_account_state<T>(&self, account: &T) -> Result<(AccountStats, AccountState)>
+    where
+        T: AsStdAddr,
+    {
+        self.get_account_state_with_options(account, &Default::default()).await
+    }
+
+    /// Same as `get_account_state`, but allows to bypass the last block cache or to specify the block
+    #[cfg_attr(feature = "tracing", tracing::instrument(skip_all))]
+    pub async fn get_account_state_with_options<T>(&self, account: &T, options: &QueryOptions) -> Result<(AccountStats, AccountState)>
+    where
+        T: AsStdAddr,
+    {
+        let response = self.query_account_state(account, ALL_CACHED_BLOCKS, options).await?;
+        Ok(parse_account_state(account, &response, self.trust_mode)?)
+    }
+
+    /// Returns account state, reading it from at most `max_blocks_back` previous blocks
+    /// if the server is not ready to answer at the last one.
+    ///
+    /// Fails with `NotReady` error if `max_blocks_back` is 0. Only the recent cached blocks are used
+    #[cfg_attr(feature = "tracing", tracing::instrument(skip_all))]
+    pub async fn get_account_state_with_fallback_depth<T>(
+        &self,
+        account: &T,
+        max_blocks_back: usize,
+    ) -> Result<(AccountStats, AccountState)>
+    where
+        T: AsStdAddr,
+    {
+        let response = self.query_account_state(account, max_blocks_back, &Default::default()).await?;
+        Ok(parse_account_state(account, &response, self.trust_mode)?)
+    }
+
+    /// Returns account state and proofs exactly as they were received from the server
+    #[cfg_attr(feature = "tracing", tracing::instrument(skip_all))]
+    pub async fn get_raw_account_state<T>(&self, account: &T) -> Result<RawAccountState>
+    where
+        T: AsStdAddr,
+    {
+        self.get_raw_account_state_with_options(account, &Default::default()).await
+    }
+
+    /// Same as `get_raw_account_state`, but allows to bypass the last block cache or to specify the block
+    #[cfg_attr(feature = "tracing", tracing::instrument(skip_all))]
+    pub async fn get_raw_account_state_with_options<T>(&self, account: &T, options: &QueryOptions) -> Result<RawAccountState>
+    where
+        T: AsStdAddr,
+    {
+        let response = self.query_account_state(account, ALL_CACHED_BLOCKS, options).await?;
+        Ok(RawAccountState {
+            block_id: response.id,
+            shard_block_id: response.shardblk,
+            shard_proof: response.shard_proof.0,
+            proof: response.proof.0,
+            state: response.state.0,
+        })
+    }
+
+    /// Returns the verified account balance at the last block, signed by the attestor.
+    ///
+    /// The state is checked in the trustless mode, so the shard block is proven to be referenced
+    /// by the masterchain block. Fails if the client trusts the server, because there is nothing to attest
+    #[cfg_attr(feature = "tracing", tracing::instrument(skip_all))]
+    pub async fn get_attested_account_state<T>(&self, account: &T, attestor: &Attestor) -> Result<AttestedAccountState>
+    where
+        T: AsStdAddr,
+    {
+        if self.trust_mode == TrustMode::TrustServer {
+            return Err(TonlibError::ProofsRequired.into());
+        }
+
+        let response = self.query_account_state(account, ALL_CACHED_BLOCKS, &Default::default()).await?;
+        let (stats, _) = parse_account_state(account, &response, TrustMode::Trustless)?;
+
+        let balance = if response.state.0.is_empty() {
+            0
+        } else {
+            ton_block::Account::construct_from_bytes(&response.state.0)
+                .map_err(|_| TonlibError::InvalidAccountData)?
+                .balance()
+                .map(|balance| balance.grams.0)
+                .unwrap_or_default()
+        };
+
+        let mut record = AttestedAccountState {
+            workchain: account.workchain_id(),
+            address: account.address(),
+            block_id: response.id,
+            shard_block_id: response.shardblk,
+            gen_utime: stats.gen_utime,
+            last_trans_lt: stats.last_trans_lt,
+            balance,
+            signature: Vec::new(),
+        };
+        attestor.sign(&mut record);
+        Ok(record)
+    }
+
+    /// Returns account stats if the account exists. Account state itself is not parsed
+    #[cfg_attr(feature = "tracing", tracing::instrument(skip_all))]
+    pub async fn account_exists<T>(&self, account: &T) -> Result<Option<AccountStats>>
+    where
+        T: AsStdAddr,
+    {
+        self.account_exists_with_options(account, &Default::default()).await
+    }
+
+    /// Same as `account_exists`, but allows to bypass the last block cache or to specify the block
+    #[cfg_attr(feature = "tracing", tracing::instrument(skip_all))]
+    pub async fn account_exists_with_options<T>(&self, account: &T, options: &QueryOptions) -> Result<Option<AccountStats>>
+    where
+        T: AsStdAddr,
+    {
+        let response = self.query_account_state(account, ALL_CACHED_BLOCKS, options).await?;
+        let (stats, account_hash) = parse_account_stats(account, &response, self.trust_mode)?;
+        Ok(account_hash.map(|_| stats))
+    }
+
+    /// Returns account balance including extra currencies
+    #[cfg_attr(feature = "tracing", tracing::instrument(skip_all))]
+    pub async fn get_balance<T>(&self, account: &T) -> Result<CurrencyCollection>
+    where
+        T: AsStdAddr,
+    {
+        Ok(match self.get_account_state(account).await?.1 {
+            AccountState::Active(info) => info.storage.balance,
+            AccountState::Uninit { balance } | AccountState::Frozen { balance, .. } => balance,
+            AccountState::NotExists => CurrencyCollection::default(),
         })
     }
 
-    pub async fn get_account_state<T>(&self, account: &T) -> Result<(AccountStats, AccountStuff)>
+    /// Returns seqno of the standard wallet
+    #[cfg_attr(feature = "tracing", tracing::instrument(skip_all))]
+    pub async fn get_wallet_seqno<T>(&self, account: &T) -> Result<u32>
+    where
+        T: AsStdAddr,
+    {
+        match self.get_account_state(account).await?.1 {
+            AccountState::Active(info) => Ok(parse_wallet_seqno(&info)?),
+            _ => Err(TonlibError::AccountNotActive.into()),
+        }
+    }
+
+    /// Fetches states of all accounts at the last block using a single connection.
+    ///
+    /// States the server is not ready to return at the last block are read from the previous cached blocks
+    #[cfg_attr(feature = "tracing", tracing::instrument(skip_all))]
+    pub async fn get_account_states<T>(&self, accounts: &[T]) -> Result<Vec<(AccountStats, AccountState)>>
+    where
+        T: AsStdAddr,
+    {
+        let result = self
+            .query_account_states(accounts)
+            .await?
+            .into_iter()
+            .collect::<TonlibResult<Vec<_>>>()?;
+        Ok(result)
+    }
+
+    /// Same as `get_account_states`, but doesn't fail the whole call if some accounts could not be fetched
+    #[cfg_attr(feature = "tracing", tracing::instrument(skip_all))]
+    pub async fn get_account_states_partial<T>(&self, accounts: &[T]) -> Result<PartialResult<(AccountStats, AccountState)>>
+    where
+        T: AsStdAddr,
+    {
+        let mut result = PartialResult {
+            items: Vec::with_capacity(accounts.len()),
+            errors: Vec::new(),
+        };
+
+        for (i, item) in self.query_account_states(accounts).await?.into_iter().enumerate() {
+            match item {
+                Ok(item) => result.items.push((i, item)),
+                Err(e) => result.errors.push((i, e.into())),
+            }
+        }
+
+        Ok(result)
+    }
+
+    #[cfg_attr(feature = "tracing", tracing::instrument(skip_all))]
+    pub async fn get_transactions<T>(&self, account: &T, count: u8, lt: u64, hash: UInt256) -> Result<Vec<(UInt256, Transaction)>>
+    where
+        T: AsStdAddr,
+    {
+        Ok(self
+            .get_transactions_with_blocks(account, count, lt, hash)
+            .await?
+            .into_iter()
+            .map(|(_, hash, transaction)| (hash, transaction))
+            .collect())
+    }
+
+    /// Returns account transactions from the newest to the oldest.
+    ///
+    /// Pages through the history until the genesis or the transaction with logical time
+    /// less than or equal to `stop_lt`
+    pub fn account_transactions<'a, T>(&'a self, account: &'a T, stop_lt: u64) -> impl Stream<Item = Result<(UInt256, Transaction)>> + 'a
+    where
+        T: AsStdAddr,
+    {
+        self.account_transactions_from(account, None, stop_lt)
+    }
+
+    /// Same as `account_transactions`, but starts from the transaction with the given
+    /// logical time and hash instead of the last one
+    pub(crate) fn account_transactions_from<'a, T>(
+        &'a self,
+        account: &'a T,
+        start: Option<(u64, UInt256)>,
+        stop_lt: u64,
+    ) -> impl Stream<Item = Result<(UInt256, Transaction)>> + 'a
     where
         T: AsStdAddr,
     {
-        use ton_block::HashmapAugType;
+        enum Cursor {
+            Start,
+            Next(u64, UInt256),
+            Done,
+        }
+
+        let cursor = match start {
+            Some((lt, hash)) => Cursor::Next(lt, hash),
+            None => Cursor::Start,
+        };
+        let state = (cursor, VecDeque::new(), u64::MAX);
+
+        futures::stream::unfold(state, move |(mut cursor, mut buffer, mut last_lt)| async move {
+            loop {
+                if let Some(item) = buffer.pop_front() {
+                    return Some((Ok(item), (cursor, buffer, last_lt)));
+                }
+
+                let (lt, hash) = match cursor {
+                    Cursor::Start => match self.get_account_state(account).await {
+                        Ok((stats, _)) => (stats.last_trans_lt, stats.last_trans_hash),
+                        Err(e) => return Some((Err(e), (Cursor::Done, buffer, last_lt))),
+                    },
+                    Cursor::Next(lt, hash) => (lt, hash),
+                    Cursor::Done => return None,
+                };
 
-        let mut connection = self.acquire_connection().await?;
-        let last_block_id = self.last_block.get_last_block(&mut connection).await?;
+                if lt <= stop_lt {
+                    return None;
+                }
+
+                let transactions = match self.get_transactions(account, MAX_TRANSACTIONS_PER_QUERY, lt, hash).await {
+                    Ok(transactions) => transactions,
+                    Err(e) => return Some((Err(e), (Cursor::Done, buffer, last_lt))),
+                };
+
+                cursor = Cursor::Done;
+                for (hash, transaction) in transactions {
+                    if transaction.lt <= stop_lt {
+                        cursor = Cursor::Done;
+                        break;
+                    }
+
+                    // Skip duplicates
+                    if transaction.lt >= last_lt {
+                        continue;
+                    }
+                    last_lt = transaction.lt;
+
+                    cursor = match transaction.prev_trans_lt {
+                        0 => Cursor::Done,
+                        prev_trans_lt => Cursor::Next(prev_trans_lt, transaction.prev_trans_hash),
+                    };
+                    buffer.push_back((hash, transaction));
+                }
+            }
+        })
+    }
+
+    /// Polls the account state and yields an event each time its code hash or data hash changes.
+    ///
+    /// The first received state is used as a baseline. Polling continues after errors
+    pub fn watch_account_code<'a, T>(&'a self, account: &'a T, poll_interval: Duration) -> impl Stream<Item = Result<CodeChange>> + 'a
+    where
+        T: AsStdAddr,
+    {
+        let state = (true, None::<AccountCodeState>);
 
+        futures::stream::unfold(state, move |(mut first, mut previous)| async move {
+            loop {
+                if !std::mem::take(&mut first) {
+                    tokio::time::sleep(poll_interval).await;
+                }
+
+                let (stats, state) = match self.get_account_state(account).await {
+                    Ok(state) => state,
+                    Err(e) => return Some((Err(e), (false, previous))),
+                };
+
+                let current = AccountCodeState::new(&state);
+                if let Some(previous) = previous.replace(current) {
+                    if previous != current {
+                        let change = CodeChange {
+                            previous,
+                            current,
+                            last_trans_lt: stats.last_trans_lt,
+                        };
+                        return Some((Ok(change), (false, Some(current))));
+                    }
+                }
+            }
+        })
+    }
+
+    /// Yields an event each time `last_trans_lt` of the account changes, with the new transactions attached.
+    ///
+    /// The account is checked only when a new masterchain block appears, but not more often than
+    /// once per `debounce`, so bursts of transactions are reported as a single event.
+    /// The first received state is used as a baseline. Polling continues after errors
+    pub fn watch_account<'a, T>(&'a self, account: &'a T, debounce: Duration) -> impl Stream<Item = Result<AccountStateChanged>> + 'a
+    where
+        T: AsStdAddr,
+    {
+        use futures::TryStreamExt;
+
+        // First iteration flag, last checked masterchain seqno and last known transaction lt
+        let state = (true, None::<u32>, None::<u64>);
+
+        futures::stream::unfold(state, move |(mut first, mut last_seqno, mut previous_lt)| async move {
+            loop {
+                if !std::mem::take(&mut first) {
+                    tokio::time::sleep(debounce).await;
+                }
+
+                let mc_block = match self.last_block.get_last_block(&self.pool).await {
+                    Ok(id) => id,
+                    Err(e) => return Some((Err(e.into()), (false, last_seqno, previous_lt))),
+                };
+                let mc_seqno = mc_block.seqno as u32;
+                if last_seqno == Some(mc_seqno) {
+                    continue;
+                }
+
+                let last_trans_lt = match self.get_account_state(account).await {
+                    Ok((stats, _)) => stats.last_trans_lt,
+                    Err(e) => return Some((Err(e), (false, last_seqno, previous_lt))),
+                };
+                last_seqno = Some(mc_seqno);
+
+                let previous = match previous_lt.replace(last_trans_lt) {
+                    Some(previous) if previous != last_trans_lt => previous,
+                    _ => continue,
+                };
+
+                let mut transactions = match self.account_transactions(account, previous).try_collect::<Vec<_>>().await {
+                    Ok(transactions) => transactions,
+                    // The change will be detected again on the next block
+                    Err(e) => return Some((Err(e), (false, None, Some(previous)))),
+                };
+                transactions.reverse();
+
+                // History could have been extended since the state was received
+                let last_trans_lt = transactions.last().map(|(_, transaction)| transaction.lt).unwrap_or(last_trans_lt);
+
+                let event = AccountStateChanged {
+                    mc_seqno,
+                    previous_lt: previous,
+                    last_trans_lt,
+                    transactions,
+                };
+                return Some((Ok(event), (false, last_seqno, Some(last_trans_lt))));
+            }
+        })
+    }
+
+    /// Searches account transaction by its hash, walking the account history backwards.
+    ///
+    /// At most `max_depth` transactions are checked. If logical time of the transaction is
+    /// known, it is requested directly
+    #[cfg_attr(feature = "tracing", tracing::instrument(skip_all))]
+    pub async fn get_transaction_by_hash<T>(
+        &self,
+        account: &T,
+        hash: &UInt256,
+        lt_hint: Option<u64>,
+        max_depth: usize,
+    ) -> Result<Option<(ton::ton_node::blockidext::BlockIdExt, Transaction)>>
+    where
+        T: AsStdAddr,
+    {
+        if let Some(lt) = lt_hint {
+            if let Ok(transactions) = self.get_transactions_with_blocks(account, 1, lt, *hash).await {
+                if let Some((block_id, transaction_hash, transaction)) = transactions.into_iter().next() {
+                    if &transaction_hash == hash {
+                        return Ok(Some((block_id, transaction)));
+                    }
+                }
+            }
+        }
+
+        let (stats, _) = self.get_account_state(account).await?;
+        let (mut lt, mut current_hash) = (stats.last_trans_lt, stats.last_trans_hash);
+
+        let mut depth = 0;
+        while lt > 0 && depth < max_depth {
+            let transactions = self
+                .get_transactions_with_blocks(account, MAX_TRANSACTIONS_PER_QUERY, lt, current_hash)
+                .await?;
+            if transactions.is_empty() {
+                break;
+            }
+
+            for (block_id, transaction_hash, transaction) in transactions {
+                if &transaction_hash == hash {
+                    return Ok(Some((block_id, transaction)));
+                }
+
+                if matches!(lt_hint, Some(lt_hint) if transaction.lt < lt_hint) || depth >= max_depth {
+                    return Ok(None);
+                }
+
+                lt = transaction.prev_trans_lt;
+                current_hash = transaction.prev_trans_hash;
+                depth += 1;
+            }
+        }
+
+        Ok(None)
+    }
+
+    /// Searches account transaction by its logical time.
+    ///
+    /// The first masterchain block at which the account has a newer transaction is found
+    /// with the exponential and then binary search over the account states, so only
+    /// the transactions of one block are paged through instead of the whole history
+    #[cfg_attr(feature = "tracing", tracing::instrument(skip_all))]
+    pub async fn find_transaction_by_lt<T>(&self, account: &T, target_lt: u64) -> Result<NearestTransactions>
+    where
+        T: AsStdAddr,
+    {
+        let (stats, _) = self.get_account_state(account).await?;
+        let mut cursor = (stats.last_trans_lt, stats.last_trans_hash);
+
+        if cursor.0 > target_lt {
+            // Account state at `hi` always has the transaction newer than the target
+            let mut hi = self.last_block.get_last_block(&self.pool).await?.seqno as u32;
+            let mut lo = None;
+            let mut step = 1u32;
+            while hi > 0 {
+                let seqno = hi.saturating_sub(step);
+                let stats = self.get_account_stats_at(account, seqno).await?;
+                if stats.last_trans_lt > target_lt {
+                    hi = seqno;
+                    cursor = (stats.last_trans_lt, stats.last_trans_hash);
+                    step = step.saturating_mul(2);
+                } else {
+                    lo = Some(seqno);
+                    break;
+                }
+            }
+
+            if let Some(mut lo) = lo {
+                while hi - lo > 1 {
+                    let seqno = lo + (hi - lo) / 2;
+                    let stats = self.get_account_stats_at(account, seqno).await?;
+                    if stats.last_trans_lt > target_lt {
+                        hi = seqno;
+                        cursor = (stats.last_trans_lt, stats.last_trans_hash);
+                    } else {
+                        lo = seqno;
+                    }
+                }
+            }
+        }
+
+        let mut result = NearestTransactions::default();
+        let (mut lt, mut hash) = cursor;
+        while lt > 0 {
+            let transactions = self.get_transactions(account, MAX_TRANSACTIONS_PER_QUERY, lt, hash).await?;
+            if transactions.is_empty() {
+                break;
+            }
+
+            for (transaction_hash, transaction) in transactions {
+                if transaction.lt <= target_lt {
+                    result.at_or_before = Some((transaction_hash, transaction));
+                    return Ok(result);
+                }
+
+                lt = transaction.prev_trans_lt;
+                hash = transaction.prev_trans_hash;
+                result.after = Some((transaction_hash, transaction));
+            }
+        }
+
+        Ok(result)
+    }
+
+    /// Runs get-method of the account at the last block on the lite server.
+    ///
+    /// The result is computed by the server and can't be checked without executing the method
+    /// locally, so it is only available with `TrustMode::TrustServer`. Fails with `ProofsRequired` otherwise
+    #[cfg_attr(feature = "tracing", tracing::instrument(skip_all))]
+    pub async fn run_get_method<T>(&self, account: &T, method: &str, stack: &StackBuilder) -> Result<StackReader>
+    where
+        T: AsStdAddr,
+    {
+        self.run_get_method_with_options(account, method, stack, &Default::default()).await
+    }
+
+    /// Same as `run_get_method`, but allows to bypass the last block cache or to specify the block
+    #[cfg_attr(feature = "tracing", tracing::instrument(skip_all))]
+    pub async fn run_get_method_with_options<T>(
+        &self,
+        account: &T,
+        method: &str,
+        stack: &StackBuilder,
+        options: &QueryOptions,
+    ) -> Result<StackReader>
+    where
+        T: AsStdAddr,
+    {
+        if self.trust_mode != TrustMode::TrustServer {
+            return Err(TonlibError::ProofsRequired.into());
+        }
+
+        let last_block_id = self.resolve_block(options).await?;
+
+        let params = ton_types::serialize_toc(&stack.build()?).map_err(anyhow::Error::msg)?;
+
+        let response = self
+            .read_query(&ton::rpc::lite_server::RunSmcMethod {
+                mode: RUN_METHOD_MODE_RESULT,
+                id: last_block_id,
+                account: ton::lite_server::accountid::AccountId {
+                    workchain: account.workchain_id(),
+                    id: ton::int256(account.address().into()),
+                },
+                method_id: method_id(method),
+                params: ton::bytes(params),
+            })
+            .await?
+            .try_into_data()?
+            .only();
+
+        if response.exit_code != 0 && response.exit_code != 1 {
+            return Err(TonlibError::ExecutionFailed(response.exit_code).into());
+        }
+
+        let result = response.result.ok_or(TonlibError::InvalidStack)?;
+        let stack = ton_types::deserialize_tree_of_cells(&mut std::io::Cursor::new(&result.0)).map_err(|_| TonlibError::InvalidStack)?;
+        Ok(StackReader::new(stack)?)
+    }
+
+    /// Searches block by its seqno.
+    ///
+    /// Masterchain blocks older than `ARCHIVE_SEQNO_DEPTH` are requested from the archival servers
+    #[cfg_attr(feature = "tracing", tracing::instrument(skip_all))]
+    pub async fn lookup_block(&self, id: ton::ton_node::blockid::BlockId) -> Result<ton::ton_node::blockidext::BlockIdExt> {
+        let query = ton::rpc::lite_server::LookupBlock {
+            mode: 0x1,
+            id: id.clone(),
+            lt: None,
+            utime: None,
+        };
+
+        let last_block_id = self.last_block.get_last_block(&self.pool).await?;
+        let is_old = id.workchain == ton_block::MASTERCHAIN_ID && last_block_id.seqno.saturating_sub(id.seqno) > ARCHIVE_SEQNO_DEPTH;
+
+        let response = match &self.archive_pool {
+            Some(archive_pool) if is_old => query_with_reconnect(archive_pool, &query).await?,
+            _ => self.query_with_archive_fallback(&query).await?,
+        };
+
+        Ok(response.try_into_data()?.only().id)
+    }
+
+    /// Returns the masterchain block with the greatest generation time not greater than `utime`
+    #[cfg_attr(feature = "tracing", tracing::instrument(skip_all))]
+    pub async fn lookup_block_by_utime(&self, utime: u32) -> Result<ton::ton_node::blockidext::BlockIdExt> {
+        let query = ton::rpc::lite_server::LookupBlock {
+            mode: 0x4,
+            id: ton::ton_node::blockid::BlockId {
+                workchain: ton_block::MASTERCHAIN_ID,
+                shard: ton_block::SHARD_FULL as i64,
+                seqno: 0,
+            },
+            lt: None,
+            utime: Some(utime as i32),
+        };
+
+        let response = self.query_with_archive_fallback(&query).await?;
+        Ok(response.try_into_data()?.only().id)
+    }
+
+    /// Returns transactions of the accounts from all blocks committed by the masterchain blocks
+    /// with the seqnos from the range, in the order of the blocks.
+    ///
+    /// Waits for the masterchain blocks which are not produced yet. Transactions of the blocks
+    /// replaced by a reorg are dropped and collected again.
+    ///
+    /// To filter a time range, take the seqnos of its bounds from `lookup_block_by_utime`.
+    /// Shard blocks are committed with a delay, so the result should be filtered by the transaction time
+    /// if exact bounds are required, and the range should be extended by a few blocks after the end
+    #[cfg_attr(feature = "tracing", tracing::instrument(skip_all))]
+    pub async fn filter_transactions_in_range<T>(&self, accounts: &[T], seqnos: std::ops::Range<u32>) -> Result<Vec<AccountTransactions>>
+    where
+        T: AsStdAddr,
+    {
+        use futures::TryStreamExt;
+
+        let accounts = accounts
+            .iter()
+            .map(|account| (account.workchain_id(), account.address()))
+            .collect::<std::collections::HashSet<_>>();
+
+        if seqnos.is_empty() || accounts.is_empty() {
+            return Ok(Vec::new());
+        }
+
+        let mut stream = BlockStream::new(self, seqnos.start, Default::default());
+
+        // Shard blocks go before the masterchain block which commits them.
+        // Collected items are paired with the seqno of the committing masterchain block
+        let mut pending = Vec::new();
+        let mut committed: Vec<(u32, AccountTransactions)> = Vec::new();
+        while let Some(event) = stream.try_next().await? {
+            let (block_id, block) = match event {
+                BlockEvent::Block { id, block } => (id, block),
+                BlockEvent::Reorg { from_seqno } => {
+                    pending.clear();
+                    committed.retain(|(seqno, _)| *seqno < from_seqno);
+                    continue;
+                }
+                BlockEvent::Final { .. } => continue,
+            };
+
+            let is_masterchain = block_id.workchain == ton_block::MASTERCHAIN_ID;
+            if is_masterchain && block_id.seqno as u32 >= seqnos.end {
+                break;
+            }
+
+            let workchain = block_id.workchain;
+            let matched = filter_block_transactions(&block, |account| accounts.contains(&(workchain, *account)))?;
+            pending.extend(matched.into_iter().map(|(account, transactions)| AccountTransactions {
+                block_id: block_id.clone(),
+                workchain,
+                account,
+                transactions,
+            }));
+
+            if is_masterchain {
+                let seqno = block_id.seqno as u32;
+                committed.extend(pending.drain(..).map(|item| (seqno, item)));
+            }
+        }
+
+        Ok(committed.into_iter().map(|(_, item)| item).collect())
+    }
+
+    /// Downloads the block and checks its root hash
+    #[cfg_attr(feature = "tracing", tracing::instrument(skip_all))]
+    pub async fn get_block(&self, id: &ton::ton_node::blockidext::BlockIdExt) -> Result<ton_block::Block> {
+        let response = self
+            .query_with_archive_fallback(&ton::rpc::lite_server::GetBlock { id: id.clone() })
+            .await?
+            .try_into_data()?
+            .only();
+
+        let root =
+            ton_types::deserialize_tree_of_cells(&mut std::io::Cursor::new(&response.data.0)).map_err(|_| TonlibError::InvalidBlock)?;
+        if root.repr_hash() != UInt256::from(id.root_hash.0) {
+            return Err(TonlibError::InvalidBlock.into());
+        }
+
+        Ok(ton_block::Block::construct_from(&mut root.into()).map_err(|_| TonlibError::InvalidBlock)?)
+    }
+
+    /// Downloads the block and parses its value flow
+    #[cfg_attr(feature = "tracing", tracing::instrument(skip_all))]
+    pub async fn get_block_value_flow(&self, id: &ton::ton_node::blockidext::BlockIdExt) -> Result<BlockValueFlow> {
+        let block = self.get_block(id).await?;
+        Ok(BlockValueFlow::new(&block)?)
+    }
+
+    /// Downloads masterchain blocks with the seqnos from the range, up to `parallelism` blocks at once.
+    ///
+    /// Blocks are yielded strictly in the order of seqnos
+    pub fn masterchain_blocks(
+        &self,
+        seqnos: std::ops::Range<u32>,
+        parallelism: usize,
+    ) -> impl Stream<Item = Result<(ton::ton_node::blockidext::BlockIdExt, ton_block::Block)>> + '_ {
+        use futures::StreamExt;
+
+        futures::stream::iter(seqnos)
+            .map(move |seqno| async move {
+                let id = self
+                    .lookup_block(ton::ton_node::blockid::BlockId {
+                        workchain: ton_block::MASTERCHAIN_ID,
+                        shard: ton_block::SHARD_FULL as i64,
+                        seqno: seqno as i32,
+                    })
+                    .await?;
+                let block = self.get_block(&id).await?;
+                Ok((id, block))
+            })
+            .buffered(std::cmp::max(parallelism, 1))
+    }
+
+    #[cfg_attr(feature = "tracing", tracing::instrument(skip_all))]
+    pub async fn get_shard_block_proof(&self, id: &ton::ton_node::blockidext::BlockIdExt) -> Result<ShardBlockProof> {
+        let response = self
+            .query_with_archive_fallback(&ton::rpc::lite_server::GetShardBlockProof { id: id.clone() })
+            .await?
+            .try_into_data()?;
+
+        Ok(proof::check_shard_block_proof(id, response.only(), self.trust_mode)?)
+    }
+
+    /// Finds the shard block which contains the account at the height of the masterchain block.
+    ///
+    /// The shard block is checked to be referenced by the masterchain block
+    #[cfg_attr(feature = "tracing", tracing::instrument(skip_all))]
+    pub async fn resolve_shard_block<T>(
+        &self,
+        mc_block: &ton::ton_node::blockidext::BlockIdExt,
+        account: &T,
+    ) -> Result<ton::ton_node::blockidext::BlockIdExt>
+    where
+        T: AsStdAddr,
+    {
+        if account.workchain_id() == ton_block::MASTERCHAIN_ID {
+            return Ok(mc_block.clone());
+        }
+
+        let account_prefix = utils::account_prefix(&account.address());
+        let response = self
+            .query_with_archive_fallback(&ton::rpc::lite_server::GetShardInfo {
+                id: mc_block.clone(),
+                workchain: account.workchain_id(),
+                // Leaf shard of the account, the server returns the one from the shard hashes which contains it
+                shard: (account_prefix | 1) as i64,
+                exact: false.into(),
+            })
+            .await?
+            .try_into_data()?
+            .only();
+
+        let shard_block = response.shardblk;
+        if shard_block.workchain != account.workchain_id() || !utils::shard_contains_account(shard_block.shard as u64, account_prefix) {
+            return Err(TonlibError::InvalidBlockProof.into());
+        }
+        proof::check_shard_proof(mc_block, &shard_block, &response.shard_proof.0)?;
+
+        Ok(shard_block)
+    }
+
+    /// Assembles proof of the transaction inclusion which can be verified offline
+    #[cfg_attr(feature = "tracing", tracing::instrument(skip_all))]
+    pub async fn get_transaction_proof<T>(
+        &self,
+        account: &T,
+        block_id: &ton::ton_node::blockidext::BlockIdExt,
+        lt: u64,
+    ) -> Result<TransactionProof>
+    where
+        T: AsStdAddr,
+    {
+        let response = self
+            .query_with_archive_fallback(&ton::rpc::lite_server::GetOneTransaction {
+                id: block_id.clone(),
+                account: ton::lite_server::accountid::AccountId {
+                    workchain: account.workchain_id(),
+                    id: ton::int256(account.address().into()),
+                },
+                lt: lt as i64,
+            })
+            .await?
+            .try_into_data()?
+            .only();
+
+        let proof = TransactionProof {
+            block_id: response.id,
+            transaction: response.transaction.0,
+            transaction_proof: response.proof.0,
+            shard_block_proof: self.get_shard_block_proof(block_id).await?,
+        };
+        proof.verify()?;
+        Ok(proof)
+    }
+
+    /// Returns the number of masterchain blocks which commit the block, including the first one.
+    ///
+    /// Shard block is committed by the masterchain block from its proof
+    #[cfg_attr(feature = "tracing", tracing::instrument(skip_all))]
+    pub async fn get_confirmations(&self, block_id: &ton::ton_node::blockidext::BlockIdExt) -> Result<u32> {
+        let commit_seqno = if block_id.workchain == ton_block::MASTERCHAIN_ID {
+            block_id.seqno
+        } else {
+            self.get_shard_block_proof(block_id).await?.masterchain_id.seqno
+        };
+
+        let last_block_id = self.last_block.get_last_block(&self.pool).await?;
+        Ok(confirmations(commit_seqno as u32, last_block_id.seqno as u32))
+    }
+
+    #[cfg_attr(feature = "tracing", tracing::instrument(skip_all))]
+    pub async fn send_message(&self, data: Vec<u8>) -> Result<SentMessage> {
+        let connection = self.acquire_connection().await?;
+        send_message(&connection, data).await
+    }
+
+    /// Sends the message and returns the account state at the masterchain block produced after sending
+    #[cfg_attr(feature = "tracing", tracing::instrument(skip_all))]
+    pub async fn send_and_get_state<T>(&self, data: Vec<u8>, account: &T) -> Result<(SentMessage, AccountStats, AccountState)>
+    where
+        T: AsStdAddr,
+    {
+        let connection = self.acquire_connection().await?;
+
+        let seqno = query(&connection, &ton::rpc::lite_server::GetMasterchainInfo)
+            .await?
+            .try_into_data()?
+            .only()
+            .last
+            .seqno;
+
+        let sent_message = send_message(&connection, data).await?;
+
+        let prefix = ton::rpc::lite_server::WaitMasterchainSeqno {
+            seqno: seqno + 1,
+            timeout_ms: WAIT_MASTERCHAIN_SEQNO_TIMEOUT.as_millis() as i32,
+        };
+
+        let block_id = query_with_prefix(&connection, Some(&prefix), &ton::rpc::lite_server::GetMasterchainInfo)
+            .await?
+            .try_into_data()?
+            .only()
+            .last;
+
+        let response = query_with_prefix(
+            &connection,
+            Some(&prefix),
+            &ton::rpc::lite_server::GetAccountState {
+                id: block_id,
+                account: ton::lite_server::accountid::AccountId {
+                    workchain: account.workchain_id(),
+                    id: ton::int256(account.address().into()),
+                },
+            },
+        )
+        .await?
+        .try_into_data()?
+        .only();
+
+        let (stats, state) = parse_account_state(account, &response, self.trust_mode)?;
+        Ok((sent_message, stats, state))
+    }
+
+    /// Polls account transactions until the one with the specified inbound message appears.
+    ///
+    /// Only transactions with logical time greater than `since_lt` are checked
+    #[cfg_attr(feature = "tracing", tracing::instrument(skip_all))]
+    pub async fn wait_for_transaction<T>(
+        &self,
+        account: &T,
+        message_hash: &UInt256,
+        since_lt: u64,
+        timeout: Duration,
+    ) -> Result<(UInt256, Transaction)>
+    where
+        T: AsStdAddr,
+    {
+        let deadline = Instant::now() + timeout;
+
+        let mut known_lt = since_lt;
+        loop {
+            let (stats, _) = self.get_account_state(account).await?;
+            if stats.last_trans_lt > known_lt {
+                let (mut lt, mut hash) = (stats.last_trans_lt, stats.last_trans_hash);
+
+                'scan: while lt > known_lt {
+                    let transactions = self.get_transactions(account, MAX_TRANSACTIONS_PER_QUERY, lt, hash).await?;
+                    if transactions.is_empty() {
+                        break;
+                    }
+
+                    for (transaction_hash, transaction) in transactions {
+                        if transaction.lt <= known_lt {
+                            break 'scan;
+                        }
+
+                        if transaction.in_msg_cell().map(|cell| cell.repr_hash()).as_ref() == Some(message_hash) {
+                            return Ok((transaction_hash, transaction));
+                        }
+
+                        lt = transaction.prev_trans_lt;
+                        hash = transaction.prev_trans_hash;
+                    }
+                }
+
+                known_lt = stats.last_trans_lt;
+            }
+
+            if Instant::now() + TRANSACTION_POLL_INTERVAL >= deadline {
+                return Err(TonlibError::Timeout.into());
+            }
+            tokio::time::sleep(TRANSACTION_POLL_INTERVAL).await;
+        }
+    }
+
+    /// Executes an arbitrary lite server query using pooled connections.
+    ///
+    /// The query is retried on transport errors according to the retry policy,
+    /// so it must be idempotent
+    #[cfg_attr(feature = "tracing", tracing::instrument(skip_all))]
+    pub async fn query_raw<T>(&self, query: &T) -> Result<T::Reply>
+    where
+        T: ton_api::Function,
+    {
+        Ok(connection::query_with_reconnect(&self.pool, query).await?.try_into_data()?)
+    }
+
+    /// Returns the block specified in the options or the last one
+    async fn resolve_block(&self, options: &QueryOptions) -> TonlibResult<ton::ton_node::blockidext::BlockIdExt> {
+        match &options.block_id {
+            Some(block_id) => Ok(block_id.clone()),
+            None if options.force_fresh_block => self.last_block.get_fresh_last_block(&self.pool).await,
+            None => self.last_block.get_last_block(&self.pool).await,
+        }
+    }
+
+    /// Queries account state at the last block, falling back to at most `max_blocks_back`
+    /// previous cached blocks if the server is not ready yet.
+    ///
+    /// Previous cached blocks are not used if the block is specified in the options
+    async fn query_account_state<T>(
+        &self,
+        account: &T,
+        max_blocks_back: usize,
+        options: &QueryOptions,
+    ) -> Result<ton::lite_server::accountstate::AccountState>
+    where
+        T: AsStdAddr,
+    {
+        let last_block_id = self.resolve_block(options).await?;
+        let max_blocks_back = if options.block_id.is_some() { 0 } else { max_blocks_back };
+        Ok(self.query_account_state_at(None, account, &last_block_id, max_blocks_back).await?)
+    }
+
+    /// Queries account state at the block, falling back to at most `max_blocks_back` previous
+    /// cached blocks if the server is not ready yet.
+    ///
+    /// The first attempt of each query uses the connection if specified
+    async fn query_account_state_at<T>(
+        &self,
+        connection: Option<&AdnlConnection<A>>,
+        account: &T,
+        last_block_id: &ton::ton_node::blockidext::BlockIdExt,
+        max_blocks_back: usize,
+    ) -> TonlibResult<ton::lite_server::accountstate::AccountState>
+    where
+        T: AsStdAddr,
+    {
         let mut account_state_query = ton::rpc::lite_server::GetAccountState {
             id: last_block_id.clone(),
             account: ton::lite_server::accountid::AccountId {
@@ -58,123 +1364,425 @@ impl TonlibClient {
             },
         };
 
-        let response = {
-            match query(&mut connection, &account_state_query).await? {
-                QueryReply::Data(data) => data,
-                QueryReply::NotReady => {
-                    let previous_block_ids = self
-                        .last_block
-                        .last_cached_blocks()
-                        .await
-                        .skip_while(|block| block.seqno < last_block_id.seqno);
-
-                    let mut result = QueryReply::NotReady;
-                    for block_id in previous_block_ids {
-                        account_state_query.id = block_id;
-                        result = query(&mut connection, &account_state_query).await?;
-
-                        if result.has_data() {
-                            break;
-                        }
+        let response = match self.read_query_using(connection, &account_state_query).await? {
+            QueryReply::Data(data) => data,
+            QueryReply::NotReady => {
+                let previous_block_ids = self
+                    .last_block
+                    .last_cached_blocks()
+                    .await
+                    .filter(|block| block.seqno < last_block_id.seqno)
+                    .take(max_blocks_back);
+
+                let mut result = QueryReply::NotReady;
+                for block_id in previous_block_ids {
+                    account_state_query.id = block_id;
+                    result = self.read_query_using(connection, &account_state_query).await?;
+
+                    if result.has_data() {
+                        break;
                     }
+                }
+
+                result.try_into_data()?
+            }
+        };
+
+        Ok(response.only())
+    }
+
+    /// Returns account stats at the masterchain block with the given seqno
+    async fn get_account_stats_at<T>(&self, account: &T, seqno: u32) -> Result<AccountStats>
+    where
+        T: AsStdAddr,
+    {
+        let id = self
+            .lookup_block(ton::ton_node::blockid::BlockId {
+                workchain: ton_block::MASTERCHAIN_ID,
+                shard: ton_block::SHARD_FULL as i64,
+                seqno: seqno as i32,
+            })
+            .await?;
+
+        let query = ton::rpc::lite_server::GetAccountState {
+            id,
+            account: ton::lite_server::accountid::AccountId {
+                workchain: account.workchain_id(),
+                id: ton::int256(account.address().into()),
+            },
+        };
+        let response = self.query_with_archive_fallback(&query).await?.try_into_data()?.only();
+
+        Ok(parse_account_stats(account, &response, self.trust_mode)?.0)
+    }
+
+    /// Fetches states of all accounts at the last block, returning results for each account.
+    ///
+    /// Queries are sent using the same connection and go through the regular read path,
+    /// so the states the server is not ready to return are read from the previous cached blocks
+    async fn query_account_states<T>(&self, accounts: &[T]) -> Result<Vec<TonlibResult<(AccountStats, AccountState)>>>
+    where
+        T: AsStdAddr,
+    {
+        let last_block_id = self.last_block.get_last_block(&self.pool).await?;
+        let last_block_id = &last_block_id;
+
+        let connection = self.acquire_connection().await?;
+        let connection = &connection;
+
+        let queries = accounts.iter().map(|account| async move {
+            let response = self
+                .query_account_state_at(Some(connection), account, last_block_id, ALL_CACHED_BLOCKS)
+                .await?;
+            parse_account_state(account, &response, self.trust_mode)
+        });
+
+        Ok(futures::future::join_all(queries).await)
+    }
+
+    async fn get_transactions_with_blocks<T>(
+        &self,
+        account: &T,
+        count: u8,
+        lt: u64,
+        hash: UInt256,
+    ) -> Result<Vec<(ton::ton_node::blockidext::BlockIdExt, UInt256, Transaction)>>
+    where
+        T: AsStdAddr,
+    {
+        let response = self
+            .query_with_archive_fallback(&ton::rpc::lite_server::GetTransactions {
+                count: count as i32,
+                account: ton::lite_server::accountid::AccountId {
+                    workchain: account.workchain_id(),
+                    id: ton::int256(account.address().into()),
+                },
+                lt: lt as i64,
+                hash: ton::int256(hash.into()),
+            })
+            .await?
+            .try_into_data()?
+            .only();
+
+        let transactions = response.transactions.0;
+        if transactions.is_empty() {
+            return Ok(Vec::new());
+        }
+
+        let transactions = ton_types::deserialize_cells_tree(&mut std::io::Cursor::new(transactions)).map_err(anyhow::Error::msg)?;
+        if transactions.len() != response.ids.len() {
+            return Err(TonlibError::InvalidBlock.into());
+        }
+
+        // Transactions must form a chain starting from the requested one
+        let verify = self.trust_mode != TrustMode::TrustServer;
+        let mut expected_hash = hash;
+
+        let mut result = Vec::with_capacity(transactions.len());
+        for (block_id, data) in response.ids.into_iter().zip(transactions.into_iter()) {
+            let hash = data.repr_hash();
+            if verify && hash != expected_hash {
+                return Err(TonlibError::InvalidTransaction.into());
+            }
+
+            let transaction = Transaction::construct_from_cell(data).map_err(anyhow::Error::msg)?;
+            expected_hash = transaction.prev_trans_hash;
+            result.push((block_id, hash, transaction));
+        }
+        Ok(result)
+    }
+
+    /// Executes idempotent query on the regular servers, hedging it if configured
+    async fn read_query<T>(&self, query: &T) -> TonlibResult<QueryReply<T::Reply>>
+    where
+        T: ton_api::Function,
+    {
+        self.read_query_using(None, query).await
+    }
+
+    /// Same as `read_query`, but the first attempt uses the given connection if specified,
+    /// e.g. to keep batched queries on the same server. Retries and hedged queries use other connections
+    async fn read_query_using<T>(&self, connection: Option<&AdnlConnection<A>>, query: &T) -> TonlibResult<QueryReply<T::Reply>>
+    where
+        T: ton_api::Function,
+    {
+        let prefix = match (self.wait_masterchain_seqno_timeout, self.last_block.latest_seqno()) {
+            (Some(timeout), Some(seqno)) => Some(ton::rpc::lite_server::WaitMasterchainSeqno {
+                seqno: seqno as i32,
+                timeout_ms: timeout.as_millis() as i32,
+            }),
+            _ => None,
+        };
+
+        match (self.hedge_delay, connection) {
+            (Some(delay), Some(connection)) => query_hedged_using(&self.pool, connection, prefix.as_ref(), query, delay).await,
+            (Some(delay), None) => query_hedged(&self.pool, prefix.as_ref(), query, delay).await,
+            (None, connection) => query_with_reconnect_using(&self.pool, connection, prefix.as_ref(), query).await,
+        }
+    }
+
+    /// Executes query on the regular servers. Retries it on the archival servers if
+    /// the regular ones failed to answer, e.g. due to the pruned history
+    async fn query_with_archive_fallback<T>(&self, query: &T) -> TonlibResult<QueryReply<T::Reply>>
+    where
+        T: ton_api::Function,
+    {
+        match (self.read_query(query).await, &self.archive_pool) {
+            (Err(TonlibError::LiteServer(e)), Some(archive_pool)) => {
+                log::debug!("Retrying query on the archival servers: {:?}", e);
+                query_with_reconnect(archive_pool, query).await
+            }
+            (result, _) => result,
+        }
+    }
+
+    async fn acquire_connection(&self) -> TonlibResult<AdnlConnection<A>> {
+        acquire_connection(&self.pool).await
+    }
+}
+
+const MAX_TRANSACTIONS_PER_QUERY: u8 = 16;
+const TRANSACTION_POLL_INTERVAL: Duration = Duration::from_secs(1);
+const WAIT_MASTERCHAIN_SEQNO_TIMEOUT: Duration = Duration::from_secs(10);
+const TON_API_SCHEME: &str = "lite_api (broxus/ton-labs-tl, branch original)";
+const ALL_CACHED_BLOCKS: usize = usize::MAX;
+/// `runSmcMethod` mode with the result stack only
+const RUN_METHOD_MODE_RESULT: i32 = 0x4;
+/// Number of the recent masterchain blocks which are expected to be stored by the regular servers
+const ARCHIVE_SEQNO_DEPTH: i32 = 100_000;
+
+#[derive(Debug, Clone)]
+pub struct AccountStats {
+    pub last_trans_lt: u64,
+    pub last_trans_hash: UInt256,
+    pub gen_lt: u64,
+    pub gen_utime: u32,
+}
+
+/// Transactions around the searched logical time
+#[derive(Debug, Clone, Default)]
+pub struct NearestTransactions {
+    /// Transaction with the searched logical time or the closest older one
+    pub at_or_before: Option<(UInt256, Transaction)>,
+    /// The next transaction after `at_or_before`
+    pub after: Option<(UInt256, Transaction)>,
+}
 
-                    result.try_into_data()?
-                }
-            }
-        }
-        .only();
+#[derive(Debug, Clone)]
+pub enum AccountState {
+    Active(AccountStuff),
+    Frozen {
+        state_hash: UInt256,
+        due: Option<Grams>,
+        balance: CurrencyCollection,
+    },
+    Uninit {
+        balance: CurrencyCollection,
+    },
+    NotExists,
+}
 
-        match ton_block::Account::construct_from_bytes(&response.state.0) {
-            Ok(ton_block::Account::Account(info)) => {
-                let q_roots = ton_types::deserialize_cells_tree(&mut std::io::Cursor::new(&response.proof.0))
-                    .map_err(|_| TonlibError::InvalidAccountStateProof)?;
-                if q_roots.len() != 2 {
-                    return Err(TonlibError::InvalidAccountStateProof.into());
-                }
+/// Code and data hashes of the account. Both are `None` if the account is not active
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Default)]
+pub struct AccountCodeState {
+    pub code_hash: Option<UInt256>,
+    pub data_hash: Option<UInt256>,
+}
 
-                let merkle_proof =
-                    ton_block::MerkleProof::construct_from_cell(q_roots[1].clone()).map_err(|_| TonlibError::InvalidAccountStateProof)?;
-                let proof_root = merkle_proof.proof.virtualize(1);
-
-                let ss = ton_block::ShardStateUnsplit::construct_from(&mut proof_root.into())
-                    .map_err(|_| TonlibError::InvalidAccountStateProof)?;
-
-                let shard_info = ss
-                    .read_accounts()
-                    .and_then(|accounts| accounts.get(&account.address()))
-                    .map_err(|_| TonlibError::InvalidAccountStateProof)?
-                    .ok_or(TonlibError::AccountNotFound)?;
-
-                Ok((
-                    AccountStats {
-                        last_trans_lt: shard_info.last_trans_lt(),
-                        last_trans_hash: *shard_info.last_trans_hash(),
-                        gen_lt: ss.gen_lt(),
-                        gen_utime: ss.gen_time(),
-                    },
-                    info,
-                ))
-            }
-            _ => Err(TonlibError::AccountNotFound.into()),
+impl AccountCodeState {
+    pub fn new(state: &AccountState) -> Self {
+        match state {
+            AccountState::Active(info) => match &info.storage.state {
+                ton_block::AccountState::AccountActive(state_init) => Self {
+                    code_hash: state_init.code.as_ref().map(|code| code.repr_hash()),
+                    data_hash: state_init.data.as_ref().map(|data| data.repr_hash()),
+                },
+                _ => Self::default(),
+            },
+            _ => Self::default(),
         }
     }
+}
 
-    pub async fn get_transactions<T>(&self, account: &T, count: u8, lt: u64, hash: UInt256) -> Result<Vec<(UInt256, Transaction)>>
-    where
-        T: AsStdAddr,
-    {
-        let mut connection = self.acquire_connection().await?;
+/// Account code or data change, detected by `watch_account_code`
+#[derive(Debug, Copy, Clone)]
+pub struct CodeChange {
+    pub previous: AccountCodeState,
+    pub current: AccountCodeState,
+    /// Logical time of the last account transaction at the moment of detection
+    pub last_trans_lt: u64,
+}
 
-        let response = query(
-            &mut connection,
-            &ton::rpc::lite_server::GetTransactions {
-                count: count as i32,
-                account: ton::lite_server::accountid::AccountId {
-                    workchain: account.workchain_id(),
-                    id: ton::int256(account.address().into()),
-                },
-                lt: lt as i64,
-                hash: ton::int256(hash.into()),
-            },
-        )
-        .await?
-        .try_into_data()?;
+impl CodeChange {
+    pub fn is_code_changed(&self) -> bool {
+        self.previous.code_hash != self.current.code_hash
+    }
 
-        let transactions = response.only().transactions.0;
-        if transactions.is_empty() {
-            return Ok(Vec::new());
-        }
+    pub fn is_data_changed(&self) -> bool {
+        self.previous.data_hash != self.current.data_hash
+    }
+}
 
-        let transactions = ton_types::deserialize_cells_tree(&mut std::io::Cursor::new(transactions)).map_err(anyhow::Error::msg)?;
+/// Account change, detected by `watch_account`
+#[derive(Debug, Clone)]
+pub struct AccountStateChanged {
+    /// Masterchain block at which the change was detected
+    pub mc_seqno: u32,
+    pub previous_lt: u64,
+    pub last_trans_lt: u64,
+    /// New transactions from the oldest to the newest
+    pub transactions: Vec<(UInt256, Transaction)>,
+}
 
-        let mut result = Vec::with_capacity(transactions.len());
-        for data in transactions.into_iter() {
-            let hash = data.repr_hash();
-            result.push((hash, Transaction::construct_from_cell(data).map_err(anyhow::Error::msg)?));
+/// Storage usage of the account
+#[derive(Debug, Copy, Clone, Default, Eq, PartialEq)]
+pub struct StorageStats {
+    pub cells: u64,
+    pub bits: u64,
+    pub public_cells: u64,
+    /// Time of the last storage fee payment
+    pub last_paid: u32,
+    /// Unpaid storage fee in nanotons
+    pub due_payment: Option<u128>,
+}
+
+impl StorageStats {
+    pub fn new(account: &AccountStuff) -> Self {
+        let storage_stat = &account.storage_stat;
+        Self {
+            cells: storage_stat.used.cells.0,
+            bits: storage_stat.used.bits.0,
+            public_cells: storage_stat.used.public_cells.0,
+            last_paid: storage_stat.last_paid,
+            due_payment: storage_stat.due_payment.as_ref().map(|due| due.0),
         }
-        Ok(result)
     }
 
-    pub async fn send_message(&self, data: Vec<u8>) -> Result<()> {
-        let mut connection = self.acquire_connection().await?;
+    /// Computes storage fee in nanotons for the period, rounding up like validators do
+    pub fn storage_fee(&self, prices: &ton_block::StoragePrices, is_masterchain: bool, period: Duration) -> u128 {
+        let (bit_price, cell_price) = if is_masterchain {
+            (prices.mc_bit_price_ps, prices.mc_cell_price_ps)
+        } else {
+            (prices.bit_price_ps, prices.cell_price_ps)
+        };
 
-        query(&mut connection, &ton::rpc::lite_server::SendMessage { body: ton::bytes(data) })
-            .await?
-            .try_into_data()?;
-        Ok(())
+        let price = self.bits as u128 * bit_price as u128 + self.cells as u128 * cell_price as u128;
+        let fee = price * period.as_secs() as u128;
+        (fee + 0xffff) >> 16
     }
 
-    async fn acquire_connection(&self) -> TonlibResult<PooledConnection<'_, AdnlManageConnection>> {
-        acquire_connection(&self.pool).await
+    /// Projected storage fee in nanotons for 30 days
+    pub fn monthly_storage_fee(&self, prices: &ton_block::StoragePrices, is_masterchain: bool) -> u128 {
+        self.storage_fee(prices, is_masterchain, Duration::from_secs(30 * 24 * 3600))
+    }
+}
+
+/// Value flow of the block
+#[derive(Debug, Clone, Default, Eq, PartialEq)]
+pub struct BlockValueFlow {
+    /// Total balance of the shard before the block
+    pub from_prev_blk: CurrencyCollection,
+    /// Total balance of the shard after the block
+    pub to_next_blk: CurrencyCollection,
+    /// Value of the imported inbound messages
+    pub imported: CurrencyCollection,
+    /// Value of the exported outbound messages
+    pub exported: CurrencyCollection,
+    /// Fees collected in the block
+    pub fees_collected: CurrencyCollection,
+    /// Fees imported from the shard blocks. Non-zero only in the masterchain
+    pub fees_imported: CurrencyCollection,
+    /// Fees recovered to the config account. Non-zero only in the masterchain
+    pub recovered: CurrencyCollection,
+    /// Block creation reward
+    pub created: CurrencyCollection,
+    /// Newly minted extra currencies. Non-zero only in the masterchain
+    pub minted: CurrencyCollection,
+}
+
+impl BlockValueFlow {
+    pub fn new(block: &ton_block::Block) -> TonlibResult<Self> {
+        let value_flow = block.read_value_flow().map_err(|_| TonlibError::InvalidBlock)?;
+        Ok(Self {
+            from_prev_blk: value_flow.from_prev_blk,
+            to_next_blk: value_flow.to_next_blk,
+            imported: value_flow.imported,
+            exported: value_flow.exported,
+            fees_collected: value_flow.fees_collected,
+            fees_imported: value_flow.fees_imported,
+            recovered: value_flow.recovered,
+            created: value_flow.created,
+            minted: value_flow.minted,
+        })
     }
 }
 
 #[derive(Debug, Clone)]
-pub struct AccountStats {
-    pub last_trans_lt: u64,
-    pub last_trans_hash: UInt256,
-    pub gen_lt: u64,
-    pub gen_utime: u32,
+pub struct RawAccountState {
+    pub block_id: ton::ton_node::blockidext::BlockIdExt,
+    pub shard_block_id: ton::ton_node::blockidext::BlockIdExt,
+    /// Proof of the shard block
+    pub shard_proof: Vec<u8>,
+    /// Block and shard state proofs with `ShardAccount`
+    pub proof: Vec<u8>,
+    /// Serialized `Account`
+    pub state: Vec<u8>,
+}
+
+/// Features available with the connected servers
+#[derive(Debug, Copy, Clone, Default, Eq, PartialEq)]
+pub struct Capabilities {
+    /// At least one server answered
+    pub connected: bool,
+    /// Responses are verified and the servers build block proof chains
+    pub proof_verification: bool,
+    /// Archival servers are configured and not all of them are failing
+    pub archive_data: bool,
+    /// Servers support `liteServer.runSmcMethod`
+    pub run_get_method: bool,
+    /// Capabilities bitmask supported by all answered servers
+    pub server_capabilities: Option<i64>,
+}
+
+/// Lite server builds block proof chains
+const CAPABILITY_PROOF_CHAINS: i64 = 1;
+/// Lite server supports `liteServer.runSmcMethod`
+const CAPABILITY_RUN_SMC_METHOD: i64 = 4;
+
+/// Per-call control over the block used by the query
+#[derive(Debug, Clone, Default)]
+pub struct QueryOptions {
+    /// Request the last block from the server instead of using the cached one
+    pub force_fresh_block: bool,
+    /// Block at which the query is executed. Overrides `force_fresh_block`
+    pub block_id: Option<ton::ton_node::blockidext::BlockIdExt>,
+}
+
+/// Result of a bulk operation which doesn't fail on the first bad item
+#[derive(Debug)]
+pub struct PartialResult<T> {
+    /// Fetched items with their indices in the request
+    pub items: Vec<(usize, T)>,
+    /// Errors with indices of the failed items
+    pub errors: Vec<(usize, anyhow::Error)>,
+}
+
+#[derive(Debug, Clone)]
+pub struct VersionInfo {
+    /// Crate version
+    pub version: &'static str,
+    /// Enabled cargo features
+    pub features: Vec<&'static str>,
+    /// Source of the lite server TL scheme
+    pub ton_api_scheme: &'static str,
+}
+
+#[derive(Debug, Clone)]
+pub struct SentMessage {
+    /// Hash of the message cell. Equals to the hash of the inbound message of the resulting transaction
+    pub hash: UInt256,
+    /// `liteServer.sendMsgStatus` code
+    pub status: i32,
 }
 
 pub trait AsStdAddr {
@@ -204,31 +1812,354 @@ impl AsStdAddr for MsgAddressInt {
 
 #[derive(Debug, Clone)]
 pub struct Config {
-    pub server_address: SocketAddrV4,
-    pub server_key: String,
+    pub servers: Vec<LiteServerConfig>,
+    /// Servers with the full history. Used for the requests which regular servers can't answer
+    pub archival_servers: Vec<LiteServerConfig>,
+    /// Max number of sessions. Each session is shared by concurrent queries,
+    /// see `RateLimitConfig::max_concurrent_queries`
     pub max_connection_count: u32,
     pub min_idle_connection_count: Option<u32>,
     pub socket_read_timeout: Duration,
     pub socket_send_timeout: Duration,
     pub last_block_threshold: Duration,
+    /// Interval of the background last block refresh. The last block is refreshed on demand if `None`
+    pub last_block_refresh_interval: Option<Duration>,
+    /// Maximum age of the last block after which the server is considered out of sync
+    pub max_block_age: Option<Duration>,
+    /// Return the last block older than `last_block_threshold` immediately, refreshing it in background
+    pub stale_while_revalidate: bool,
+    /// Age after which the expired last block is requested synchronously even with `stale_while_revalidate`
+    pub max_stale_age: Duration,
+    /// Number of the recent masterchain blocks to keep. They are used as fallbacks when
+    /// the server is not ready to answer at the last block
+    pub recent_block_count: usize,
     pub ping_timeout: Duration,
+    /// Interval of the idle connections check. Connections are checked only on checkout if `None`
+    pub keepalive_interval: Option<Duration>,
+    /// Keep one spare connection per server outside the pool, so that a broken connection
+    /// is replaced without waiting for the handshake
+    pub warm_standby: bool,
+    /// Zero state of the network. Can be taken from `GlobalConfig::validator`
+    pub zero_state: Option<ton::ton_node::blockidext::BlockIdExt>,
+    pub circuit_breaker: CircuitBreakerConfig,
+    pub trust_mode: TrustMode,
+    /// Delay after which read queries are duplicated using another connection. Disabled if `None`
+    pub hedge_delay: Option<Duration>,
+    /// Timeout of `liteServer.waitMasterchainSeqno` prefix of the read queries. The prefix makes the server
+    /// answer only after it reaches the last block observed by the client. Disabled if `None`
+    pub wait_masterchain_seqno_timeout: Option<Duration>,
+    pub retry_policy: RetryPolicy,
+    pub rate_limit: RateLimitConfig,
+    #[cfg(feature = "fault-injection")]
+    pub fault_injector: Option<Arc<fault_injection::FaultInjector>>,
 }
 
-impl TryFrom<&Config> for AdnlTcpClientConfig {
-    type Error = anyhow::Error;
-
-    fn try_from(c: &Config) -> Result<Self> {
-        let server_key = base64::decode(&c.server_key)?;
+impl Config {
+    /// Creates config with the lite servers and the zero state from the standard global config JSON.
+    ///
+    /// Other fields have the default values
+    pub fn from_global_config(data: &[u8]) -> Result<Self> {
+        let global_config = GlobalConfig::from_slice(data)?;
+        let zero_state = match &global_config.validator {
+            Some(validator) => Some(validator.zero_state.to_block_id()?),
+            None => None,
+        };
 
-        Ok(AdnlTcpClientConfig {
-            server_address: c.server_address,
-            server_key: ed25519_dalek::PublicKey::from_bytes(&server_key)?,
-            socket_read_timeout: c.socket_read_timeout,
-            socket_send_timeout: c.socket_send_timeout,
+        Ok(Self {
+            servers: global_config.lite_servers(),
+            zero_state,
+            ..Default::default()
         })
     }
 }
 
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            servers: Vec::new(),
+            archival_servers: Vec::new(),
+            max_connection_count: 10,
+            min_idle_connection_count: None,
+            socket_read_timeout: Duration::from_secs(5),
+            socket_send_timeout: Duration::from_secs(5),
+            last_block_threshold: Duration::from_secs(1),
+            last_block_refresh_interval: None,
+            max_block_age: None,
+            stale_while_revalidate: false,
+            max_stale_age: Duration::from_secs(30),
+            recent_block_count: 5,
+            ping_timeout: Duration::from_secs(10),
+            keepalive_interval: None,
+            warm_standby: false,
+            zero_state: None,
+            circuit_breaker: Default::default(),
+            trust_mode: Default::default(),
+            hedge_delay: None,
+            wait_masterchain_seqno_timeout: None,
+            retry_policy: Default::default(),
+            rate_limit: Default::default(),
+            #[cfg(feature = "fault-injection")]
+            fault_injector: None,
+        }
+    }
+}
+
+/// Controls how much of the server responses is verified
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum TrustMode {
+    /// Responses are only parsed
+    TrustServer,
+    /// Account states and transactions are checked against the shard block proofs
+    VerifyProofs,
+    /// Additionally shard blocks are checked to be referenced by the masterchain block.
+    ///
+    /// NOTE: the masterchain block itself is received from the server
+    Trustless,
+}
+
+impl Default for TrustMode {
+    fn default() -> Self {
+        Self::VerifyProofs
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct LiteServerConfig {
+    pub address: ServerAddress,
+    /// Base64 encoded ed25519 public key
+    pub key: String,
+}
+
+/// Lite server address. Only IPv4 is supported by the ADNL transport
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub enum ServerAddress {
+    Ip(SocketAddrV4),
+    /// Hostname, which is resolved when the server list is applied and again on each connection
+    Host {
+        host: String,
+        port: u16,
+    },
+}
+
+impl From<SocketAddrV4> for ServerAddress {
+    fn from(address: SocketAddrV4) -> Self {
+        Self::Ip(address)
+    }
+}
+
+impl std::str::FromStr for ServerAddress {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s.parse::<std::net::SocketAddr>() {
+            Ok(std::net::SocketAddr::V4(address)) => Ok(Self::Ip(address)),
+            Ok(std::net::SocketAddr::V6(_)) => Err(anyhow::anyhow!("IPv6 addresses are not supported")),
+            Err(_) => {
+                let (host, port) = s.rsplit_once(':').ok_or_else(|| anyhow::anyhow!("Port is not specified"))?;
+                if host.is_empty() {
+                    return Err(anyhow::anyhow!("Host is not specified"));
+                }
+                Ok(Self::Host {
+                    host: host.to_owned(),
+                    port: port.parse()?,
+                })
+            }
+        }
+    }
+}
+
+/// Computes get-method id from its name: CRC16/XMODEM with the 17th bit set
+fn method_id(name: &str) -> i64 {
+    let mut crc: u16 = 0;
+    for byte in name.bytes() {
+        crc ^= (byte as u16) << 8;
+        for _ in 0..8 {
+            crc = if crc & 0x8000 != 0 { (crc << 1) ^ 0x1021 } else { crc << 1 };
+        }
+    }
+    (crc as i64) | 0x10000
+}
+
+/// Returns the number of masterchain blocks since the commit block, including it
+pub fn confirmations(commit_mc_seqno: u32, current_mc_seqno: u32) -> u32 {
+    if current_mc_seqno < commit_mc_seqno {
+        0
+    } else {
+        current_mc_seqno - commit_mc_seqno + 1
+    }
+}
+
+/// Fails with `Timeout` error if the operation is not completed before the deadline.
+///
+/// The deadline bounds the whole operation, including pool checkout, retries and fallback blocks scan
+pub async fn with_deadline<F, T>(deadline: Instant, f: F) -> Result<T>
+where
+    F: std::future::Future<Output = Result<T>>,
+{
+    match tokio::time::timeout_at(deadline.into(), f).await {
+        Ok(result) => result,
+        Err(_) => Err(TonlibError::Timeout.into()),
+    }
+}
+
+/// Same as `with_deadline`, but with the deadline relative to the current time
+pub async fn with_timeout<F, T>(timeout: Duration, f: F) -> Result<T>
+where
+    F: std::future::Future<Output = Result<T>>,
+{
+    with_deadline(Instant::now() + timeout, f).await
+}
+
+async fn build_pool<A>(
+    servers: Arc<ServerSet>,
+    config: &Config,
+    stats: Arc<ConnectionStats>,
+    rate_limiter: Arc<RateLimiter>,
+    context: Arc<A::Context>,
+    standby: Option<Arc<Standby<A>>>,
+) -> Result<Pool<AdnlManageConnection<A>>>
+where
+    A: AdnlTransport,
+{
+    Ok(Pool::builder()
+        .max_size(config.max_connection_count)
+        .min_idle(config.min_idle_connection_count)
+        .max_lifetime(None)
+        .build(AdnlManageConnection::new(servers, config, stats, rate_limiter, context, standby))
+        .await?)
+}
+
+async fn send_message<A>(connection: &AdnlConnection<A>, data: Vec<u8>) -> Result<SentMessage>
+where
+    A: AdnlTransport,
+{
+    let hash = ton_types::deserialize_tree_of_cells(&mut std::io::Cursor::new(&data))
+        .map_err(anyhow::Error::msg)?
+        .repr_hash();
+
+    let status = query(connection, &ton::rpc::lite_server::SendMessage { body: ton::bytes(data) })
+        .await?
+        .try_into_data()?
+        .only()
+        .status;
+
+    Ok(SentMessage { hash, status })
+}
+
+/// Returns account stats from the state proof and the account cell hash if the account exists
+fn parse_account_stats<T>(
+    account: &T,
+    response: &ton::lite_server::accountstate::AccountState,
+    trust_mode: TrustMode,
+) -> TonlibResult<(AccountStats, Option<UInt256>)>
+where
+    T: AsStdAddr,
+{
+    use ton_block::HashmapAugType;
+
+    let q_roots = ton_types::deserialize_cells_tree(&mut std::io::Cursor::new(&response.proof.0))
+        .map_err(|_| TonlibError::InvalidAccountStateProof)?;
+    if q_roots.len() != 2 {
+        return Err(TonlibError::InvalidAccountStateProof);
+    }
+
+    if trust_mode != TrustMode::TrustServer {
+        proof::check_account_state_proof(&response.shardblk, &q_roots)?;
+    }
+    if trust_mode == TrustMode::Trustless {
+        proof::check_shard_proof(&response.id, &response.shardblk, &response.shard_proof.0)?;
+    }
+
+    let merkle_proof =
+        ton_block::MerkleProof::construct_from_cell(q_roots[1].clone()).map_err(|_| TonlibError::InvalidAccountStateProof)?;
+    let proof_root = merkle_proof.proof.virtualize(1);
+
+    let ss = ton_block::ShardStateUnsplit::construct_from(&mut proof_root.into()).map_err(|_| TonlibError::InvalidAccountStateProof)?;
+
+    let shard_info = ss
+        .read_accounts()
+        .and_then(|accounts| accounts.get(&account.address()))
+        .map_err(|_| TonlibError::InvalidAccountStateProof)?;
+
+    let mut stats = AccountStats {
+        last_trans_lt: 0,
+        last_trans_hash: UInt256::default(),
+        gen_lt: ss.gen_lt(),
+        gen_utime: ss.gen_time(),
+    };
+
+    let account_hash = match shard_info {
+        Some(shard_info) => {
+            stats.last_trans_lt = shard_info.last_trans_lt();
+            stats.last_trans_hash = *shard_info.last_trans_hash();
+            Some(shard_info.account_cell().repr_hash())
+        }
+        None => None,
+    };
+
+    Ok((stats, account_hash))
+}
+
+fn parse_account_state<T>(
+    account: &T,
+    response: &ton::lite_server::accountstate::AccountState,
+    trust_mode: TrustMode,
+) -> TonlibResult<(AccountStats, AccountState)>
+where
+    T: AsStdAddr,
+{
+    let (stats, account_hash) = parse_account_stats(account, response, trust_mode)?;
+
+    let account = if response.state.0.is_empty() {
+        ton_block::Account::AccountNone
+    } else {
+        let cell = ton_types::deserialize_tree_of_cells(&mut std::io::Cursor::new(&response.state.0))
+            .map_err(|_| TonlibError::InvalidAccountData)?;
+        if trust_mode != TrustMode::TrustServer && Some(cell.repr_hash()) != account_hash {
+            return Err(TonlibError::InvalidAccountStateProof);
+        }
+        ton_block::Account::construct_from_cell(cell).map_err(|_| TonlibError::InvalidAccountData)?
+    };
+
+    let info = match (account, account_hash.is_some()) {
+        (ton_block::Account::Account(info), true) => info,
+        (ton_block::Account::AccountNone, false) => return Ok((stats, AccountState::NotExists)),
+        _ => return Err(TonlibError::InvalidAccountStateProof),
+    };
+
+    let state = match &info.storage.state {
+        ton_block::AccountState::AccountActive(_) => AccountState::Active(info),
+        ton_block::AccountState::AccountFrozen(state_hash) => AccountState::Frozen {
+            state_hash: *state_hash,
+            due: info.storage_stat.due_payment.clone(),
+            balance: info.storage.balance.clone(),
+        },
+        ton_block::AccountState::AccountUninit => AccountState::Uninit {
+            balance: info.storage.balance.clone(),
+        },
+    };
+
+    Ok((stats, state))
+}
+
+/// Standard wallets store seqno in the first 32 bits of their data
+fn parse_wallet_seqno(state: &AccountStuff) -> TonlibResult<u32> {
+    match &state.storage.state {
+        ton_block::AccountState::AccountActive(state_init) => {
+            let data = state_init.data.clone().ok_or(TonlibError::InvalidAccountData)?;
+            ton_types::SliceData::from(data)
+                .get_next_u32()
+                .map_err(|_| TonlibError::InvalidAccountData)
+        }
+        _ => Err(TonlibError::AccountNotActive),
+    }
+}
+
+fn parse_server_key(key: &str) -> Result<ed25519_dalek::PublicKey> {
+    let key = base64::decode(key)?;
+    Ok(ed25519_dalek::PublicKey::from_bytes(&key)?)
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -247,14 +2178,33 @@ mod tests {
 
     async fn make_client() -> TonlibClient {
         TonlibClient::new(&Config {
-            server_address: "54.158.97.195:3031".parse().unwrap(),
-            server_key: "uNRRL+6enQjuiZ/s6Z+vO7yxUUR7uxdfzIy+RxkECrc=".to_owned(),
+            servers: vec![LiteServerConfig {
+                address: "54.158.97.195:3031".parse().unwrap(),
+                key: "uNRRL+6enQjuiZ/s6Z+vO7yxUUR7uxdfzIy+RxkECrc=".to_owned(),
+            }],
+            archival_servers: Vec::new(),
             max_connection_count: 1,
             min_idle_connection_count: Some(1),
             socket_read_timeout: Duration::from_secs(5),
             socket_send_timeout: Duration::from_secs(5),
             ping_timeout: Duration::from_secs(10),
+            keepalive_interval: None,
+            warm_standby: false,
+            zero_state: None,
             last_block_threshold: Duration::from_secs(1),
+            last_block_refresh_interval: None,
+            max_block_age: None,
+            stale_while_revalidate: false,
+            max_stale_age: Duration::from_secs(30),
+            recent_block_count: 5,
+            circuit_breaker: Default::default(),
+            trust_mode: TrustMode::Trustless,
+            hedge_delay: None,
+            wait_masterchain_seqno_timeout: None,
+            retry_policy: Default::default(),
+            rate_limit: Default::default(),
+            #[cfg(feature = "fault-injection")]
+            fault_injector: None,
         })
         .await
         .unwrap()
@@ -284,6 +2234,257 @@ mod tests {
         });
     }
 
+    #[test]
+    fn test_masterchain_blocks() {
+        run_test(async {
+            use futures::StreamExt;
+
+            let client = make_client().await;
+
+            let last_seqno = client.last_block().get_last_block(&client.pool).await?.seqno as u32;
+            let blocks = client
+                .masterchain_blocks(last_seqno - 10..last_seqno, 4)
+                .collect::<Vec<_>>()
+                .await
+                .into_iter()
+                .collect::<Result<Vec<_>>>()?;
+
+            assert_eq!(blocks.len(), 10);
+            assert!(blocks.windows(2).all(|b| b[0].0.seqno + 1 == b[1].0.seqno));
+            Ok(())
+        });
+    }
+
+    #[test]
+    fn test_find_transaction_by_lt() {
+        run_test(async {
+            let client = make_client().await;
+
+            let (stats, _) = client.get_account_state(&elector_addr()).await?;
+            let transactions = client
+                .get_transactions(&elector_addr(), 16, stats.last_trans_lt, stats.last_trans_hash)
+                .await?;
+            let (hash, transaction) = transactions.last().unwrap();
+
+            let result = client.find_transaction_by_lt(&elector_addr(), transaction.lt).await?;
+            assert_eq!(result.at_or_before.map(|(hash, _)| hash), Some(*hash));
+            assert!(matches!(result.after, Some((_, after)) if after.prev_trans_lt == transaction.lt));
+            Ok(())
+        });
+    }
+
+    #[test]
+    fn test_filter_transactions_in_range() {
+        run_test(async {
+            let client = make_client().await;
+
+            let last_seqno = client.last_block().get_last_block(&client.pool).await?.seqno as u32;
+            let elector = elector_addr();
+
+            let found = client
+                .filter_transactions_in_range(&[elector.clone()], last_seqno - 3..last_seqno)
+                .await?;
+            for item in found {
+                assert_eq!(item.workchain, elector.workchain_id());
+                assert_eq!(item.account, elector.address());
+                assert!(item.block_id.seqno as u32 >= last_seqno - 3);
+            }
+            Ok(())
+        });
+    }
+
+    #[test]
+    fn test_capabilities() {
+        run_test(async {
+            let client = make_client().await;
+            let capabilities = client.capabilities().await;
+            assert!(capabilities.connected);
+            assert!(capabilities.proof_verification);
+            assert!(!capabilities.archive_data);
+            Ok(())
+        });
+    }
+
+    #[test]
+    fn test_block_stream() {
+        run_test(async {
+            use futures::TryStreamExt;
+
+            let client = make_client().await;
+
+            let last_seqno = client.last_block().get_last_block(&client.pool).await?.seqno as u32;
+            let mut stream = BlockStream::new(&client, last_seqno - 3, Default::default());
+
+            let mut masterchain_seqnos = Vec::new();
+            while masterchain_seqnos.len() < 3 {
+                match stream.try_next().await?.unwrap() {
+                    BlockEvent::Block { id, .. } if id.workchain == ton_block::MASTERCHAIN_ID => masterchain_seqnos.push(id.seqno as u32),
+                    BlockEvent::Reorg { .. } => panic!("Unexpected reorg"),
+                    _ => {}
+                }
+            }
+
+            assert_eq!(masterchain_seqnos, (last_seqno - 3..last_seqno).collect::<Vec<_>>());
+            Ok(())
+        });
+    }
+
+    #[test]
+    fn test_balance() {
+        run_test(async {
+            let client = make_client().await;
+
+            let balance = client.get_balance(&elector_addr()).await?;
+            assert!(balance.grams.0 > 0);
+
+            let balance = client.get_balance(&unknown_addr()).await?;
+            assert_eq!(balance.grams.0, 0);
+            Ok(())
+        });
+    }
+
+    #[test]
+    fn wallet_seqno() {
+        let mut data = ton_types::BuilderData::new();
+        data.append_u32(42).unwrap();
+
+        let mut state = AccountStuff::default();
+        assert!(matches!(parse_wallet_seqno(&state), Err(TonlibError::AccountNotActive)));
+
+        state.storage.state = ton_block::AccountState::AccountActive(Default::default());
+        assert!(matches!(parse_wallet_seqno(&state), Err(TonlibError::InvalidAccountData)));
+
+        state.storage.state = ton_block::AccountState::AccountActive(ton_block::StateInit {
+            data: Some(ton_types::Cell::default()),
+            ..Default::default()
+        });
+        assert!(matches!(parse_wallet_seqno(&state), Err(TonlibError::InvalidAccountData)));
+
+        state.storage.state = ton_block::AccountState::AccountActive(ton_block::StateInit {
+            data: Some(data.into_cell().unwrap()),
+            ..Default::default()
+        });
+        assert_eq!(parse_wallet_seqno(&state).unwrap(), 42);
+    }
+
+    #[test]
+    fn account_code_state() {
+        let mut code = ton_types::BuilderData::new();
+        code.append_u32(1).unwrap();
+        let code = code.into_cell().unwrap();
+
+        let mut info = AccountStuff::default();
+        assert_eq!(
+            AccountCodeState::new(&AccountState::Active(info.clone())),
+            AccountCodeState::default()
+        );
+
+        info.storage.state = ton_block::AccountState::AccountActive(ton_block::StateInit {
+            code: Some(code.clone()),
+            ..Default::default()
+        });
+        let state = AccountCodeState::new(&AccountState::Active(info));
+        assert_eq!(state.code_hash, Some(code.repr_hash()));
+        assert_eq!(state.data_hash, None);
+    }
+
+    #[test]
+    fn parse_server_address() {
+        assert_eq!(
+            "1.2.3.4:3031".parse::<ServerAddress>().unwrap(),
+            ServerAddress::Ip("1.2.3.4:3031".parse().unwrap())
+        );
+        assert_eq!(
+            "lite.example.com:3031".parse::<ServerAddress>().unwrap(),
+            ServerAddress::Host {
+                host: "lite.example.com".to_owned(),
+                port: 3031
+            }
+        );
+        assert!("[::1]:3031".parse::<ServerAddress>().is_err());
+        assert!("lite.example.com".parse::<ServerAddress>().is_err());
+    }
+
+    #[test]
+    fn config_from_global_config() {
+        let config = Config::from_global_config(
+            br#"{
+                "@type": "config.global",
+                "liteservers": [
+                    {
+                        "ip": 908566172,
+                        "port": 7742,
+                        "id": {
+                            "@type": "pub.ed25519",
+                            "key": "peJTw/arlRfssgTuf9BMypJzqOi7SXEqSPSWiEw2U1M="
+                        }
+                    }
+                ],
+                "validator": {
+                    "@type": "validator.config.global",
+                    "zero_state": {
+                        "workchain": -1,
+                        "shard": -9223372036854775808,
+                        "seqno": 0,
+                        "root_hash": "F6OpKZKqvqeFp6CQmFomXNMfMj2EnaUSOXN+Mh+wVWk=",
+                        "file_hash": "XplPz01CXAps5qeSWUtxcyBfdAo5zVb1N979KLSKD24="
+                    }
+                }
+            }"#,
+        )
+        .unwrap();
+
+        assert_eq!(config.servers.len(), 1);
+        assert_eq!(config.servers[0].address, ServerAddress::Ip("54.39.158.156:7742".parse().unwrap()));
+        assert_eq!(config.servers[0].key, "peJTw/arlRfssgTuf9BMypJzqOi7SXEqSPSWiEw2U1M=");
+
+        let zero_state = config.zero_state.unwrap();
+        assert_eq!(zero_state.workchain, -1);
+        assert_eq!(zero_state.shard, ton_block::SHARD_FULL as i64);
+        assert_eq!(zero_state.seqno, 0);
+        assert_eq!(
+            zero_state.root_hash.0.to_vec(),
+            base64::decode("F6OpKZKqvqeFp6CQmFomXNMfMj2EnaUSOXN+Mh+wVWk=").unwrap()
+        );
+
+        assert_eq!(config.max_connection_count, Config::default().max_connection_count);
+        assert!(Config::from_global_config(b"{}").is_err());
+    }
+
+    #[test]
+    fn storage_fee() {
+        let stats = StorageStats {
+            cells: 10,
+            bits: 1000,
+            ..Default::default()
+        };
+        let prices = ton_block::StoragePrices {
+            utime_since: 0,
+            bit_price_ps: 1,
+            cell_price_ps: 500,
+            mc_bit_price_ps: 1000,
+            mc_cell_price_ps: 500000,
+        };
+
+        // (1000 * 1 + 10 * 500) * 65536 / 2^16
+        assert_eq!(stats.storage_fee(&prices, false, Duration::from_secs(65536)), 6000);
+        assert_eq!(stats.storage_fee(&prices, false, Duration::from_secs(1)), 1);
+        assert_eq!(stats.storage_fee(&prices, true, Duration::from_secs(65536)), 6_000_000);
+    }
+
+    #[test]
+    fn get_method_id() {
+        assert_eq!(method_id("seqno"), 85143);
+        assert_eq!(method_id("get_public_key"), 78748);
+    }
+
+    #[test]
+    fn confirmations_count() {
+        assert_eq!(confirmations(10, 9), 0);
+        assert_eq!(confirmations(10, 10), 1);
+        assert_eq!(confirmations(10, 15), 6);
+    }
+
     #[test]
     fn test_unknown() {
         run_test(async {
@@ -0,0 +1,299 @@
+use std::collections::HashMap;
+
+use anyhow::Result;
+use ton_block::{Deserializable, Grams, Message, MsgAddress, MsgAddressInt, Transaction};
+use ton_types::SliceData;
+
+/// Human-readable meaning of the message body
+#[derive(Debug, Clone, PartialEq)]
+pub enum Intent {
+    Comment(String),
+    JettonTransfer {
+        query_id: u64,
+        amount: Grams,
+        destination: MsgAddress,
+        response_destination: MsgAddress,
+    },
+    JettonInternalTransfer {
+        query_id: u64,
+        amount: Grams,
+        from: MsgAddress,
+    },
+    JettonTransferNotification {
+        query_id: u64,
+        amount: Grams,
+        sender: MsgAddress,
+    },
+    JettonBurn {
+        query_id: u64,
+        amount: Grams,
+    },
+    NftTransfer {
+        query_id: u64,
+        new_owner: MsgAddress,
+        response_destination: MsgAddress,
+    },
+    NftOwnershipAssigned {
+        query_id: u64,
+        prev_owner: MsgAddress,
+    },
+    Excesses {
+        query_id: u64,
+    },
+    /// Intent decoded by a custom decoder
+    Custom {
+        name: String,
+        description: String,
+    },
+}
+
+/// Structured description of a message, e.g. to review it before signing or to log it for audit
+#[derive(Debug, Clone, PartialEq)]
+pub struct MessageSummary {
+    pub destination: Option<MsgAddressInt>,
+    /// Attached value for the internal messages
+    pub value: Option<Grams>,
+    pub bounce: Option<bool>,
+    /// Op-code of the internal message body
+    pub op: Option<u32>,
+    /// `None` if the message is external, the op-code is unknown or the body can't be decoded
+    pub intent: Option<Intent>,
+    /// Internal messages stored in the body references, e.g. transfers of a wallet
+    pub actions: Vec<MessageSummary>,
+}
+
+pub type OpDecoder = Box<dyn Fn(&mut SliceData) -> Result<Intent> + Send + Sync>;
+
+/// Maps 32-bit op-codes of the message bodies to decoders.
+///
+/// Decoders receive the body after the op-code
+#[derive(Default)]
+pub struct OpRegistry {
+    decoders: HashMap<u32, OpDecoder>,
+}
+
+impl OpRegistry {
+    /// Creates registry with decoders for comments, jetton and NFT operations
+    pub fn with_known_ops() -> Self {
+        let mut registry = Self::default();
+
+        registry.register(OP_COMMENT, |body| Ok(Intent::Comment(read_snake_string(body)?)));
+        registry.register(OP_JETTON_TRANSFER, |body| {
+            Ok(Intent::JettonTransfer {
+                query_id: read_u64(body)?,
+                amount: read(body)?,
+                destination: read(body)?,
+                response_destination: read(body)?,
+            })
+        });
+        registry.register(OP_JETTON_INTERNAL_TRANSFER, |body| {
+            Ok(Intent::JettonInternalTransfer {
+                query_id: read_u64(body)?,
+                amount: read(body)?,
+                from: read(body)?,
+            })
+        });
+        registry.register(OP_JETTON_TRANSFER_NOTIFICATION, |body| {
+            Ok(Intent::JettonTransferNotification {
+                query_id: read_u64(body)?,
+                amount: read(body)?,
+                sender: read(body)?,
+            })
+        });
+        registry.register(OP_JETTON_BURN, |body| {
+            Ok(Intent::JettonBurn {
+                query_id: read_u64(body)?,
+                amount: read(body)?,
+            })
+        });
+        registry.register(OP_NFT_TRANSFER, |body| {
+            Ok(Intent::NftTransfer {
+                query_id: read_u64(body)?,
+                new_owner: read(body)?,
+                response_destination: read(body)?,
+            })
+        });
+        registry.register(OP_NFT_OWNERSHIP_ASSIGNED, |body| {
+            Ok(Intent::NftOwnershipAssigned {
+                query_id: read_u64(body)?,
+                prev_owner: read(body)?,
+            })
+        });
+        registry.register(OP_EXCESSES, |body| Ok(Intent::Excesses { query_id: read_u64(body)? }));
+
+        registry
+    }
+
+    /// Adds decoder for the op-code, replacing the existing one
+    pub fn register<F>(&mut self, op: u32, decoder: F)
+    where
+        F: Fn(&mut SliceData) -> Result<Intent> + Send + Sync + 'static,
+    {
+        self.decoders.insert(op, Box::new(decoder));
+    }
+
+    /// Returns `None` if the op-code is unknown or the body is too short
+    pub fn decode_body(&self, body: &SliceData) -> Option<Result<Intent>> {
+        let mut body = body.clone();
+        let op = body.get_next_u32().ok()?;
+        let decoder = self.decoders.get(&op)?;
+        Some(decoder(&mut body))
+    }
+
+    /// Returns `None` for the external messages, because their bodies don't start with op-code
+    pub fn decode_message(&self, message: &Message) -> Option<Result<Intent>> {
+        if !message.is_internal() {
+            return None;
+        }
+        self.decode_body(&message.body()?)
+    }
+
+    pub fn summarize_message(&self, message: &Message) -> MessageSummary {
+        let body = message.body();
+        // External inbound bodies start with the signature or the wallet payload
+        let internal_body = body.as_ref().filter(|_| message.is_internal());
+
+        let mut actions = Vec::new();
+        if let Some(body) = &body {
+            for i in 0..body.remaining_references() {
+                let action = body
+                    .reference(i)
+                    .and_then(|cell| Message::construct_from(&mut cell.into()))
+                    .ok()
+                    .filter(Message::is_internal);
+                if let Some(action) = action {
+                    actions.push(self.summarize_message(&action));
+                }
+            }
+        }
+
+        MessageSummary {
+            destination: message.dst(),
+            value: message.get_value().map(|value| value.grams.clone()),
+            bounce: message.int_header().map(|header| header.bounce),
+            op: internal_body.and_then(|body| body.clone().get_next_u32().ok()),
+            intent: internal_body.and_then(|body| self.decode_body(body)?.ok()),
+            actions,
+        }
+    }
+
+    /// Decodes the inbound message of the transaction
+    pub fn decode_transaction(&self, transaction: &Transaction) -> Option<Result<Intent>> {
+        match transaction.read_in_msg() {
+            Ok(message) => self.decode_message(&message?),
+            Err(e) => Some(Err(anyhow::Error::msg(e))),
+        }
+    }
+}
+
+fn read<T: Deserializable>(body: &mut SliceData) -> Result<T> {
+    T::construct_from(body).map_err(anyhow::Error::msg)
+}
+
+fn read_u64(body: &mut SliceData) -> Result<u64> {
+    body.get_next_u64().map_err(anyhow::Error::msg)
+}
+
+/// Reads string which continues in the first reference of each cell
+fn read_snake_string(body: &mut SliceData) -> Result<String> {
+    let mut data = Vec::new();
+    let mut cell = body.clone();
+    loop {
+        data.extend(cell.get_next_bytes(cell.remaining_bits() / 8).map_err(anyhow::Error::msg)?);
+        if cell.remaining_references() == 0 {
+            break;
+        }
+        cell = cell.checked_drain_reference().map_err(anyhow::Error::msg)?.into();
+    }
+    Ok(String::from_utf8(data)?)
+}
+
+const OP_COMMENT: u32 = 0x00000000;
+const OP_JETTON_TRANSFER: u32 = 0x0f8a7ea5;
+const OP_JETTON_INTERNAL_TRANSFER: u32 = 0x178d4519;
+const OP_JETTON_TRANSFER_NOTIFICATION: u32 = 0x7362d09c;
+const OP_JETTON_BURN: u32 = 0x595f07bc;
+const OP_NFT_TRANSFER: u32 = 0x5fcc3d14;
+const OP_NFT_OWNERSHIP_ASSIGNED: u32 = 0x05138d91;
+const OP_EXCESSES: u32 = 0xd53276db;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use ton_types::BuilderData;
+
+    fn make_body(op: u32, data: &[u8]) -> SliceData {
+        let mut builder = BuilderData::new();
+        builder.append_u32(op).unwrap();
+        builder.append_raw(data, data.len() * 8).unwrap();
+        builder.into_cell().unwrap().into()
+    }
+
+    #[test]
+    fn decode_comment() {
+        let registry = OpRegistry::with_known_ops();
+        let intent = registry.decode_body(&make_body(OP_COMMENT, b"hello")).unwrap().unwrap();
+        assert_eq!(intent, Intent::Comment("hello".to_owned()));
+    }
+
+    #[test]
+    fn summarize_message() {
+        let registry = OpRegistry::with_known_ops();
+
+        let destination = MsgAddressInt::with_standart(None, 0, ton_types::UInt256::from([1; 32]).into()).unwrap();
+        let mut transfer = Message::with_int_header(ton_block::InternalMessageHeader::with_addresses_and_bounce(
+            MsgAddressInt::default(),
+            destination.clone(),
+            ton_block::CurrencyCollection::with_grams(100),
+            true,
+        ));
+        transfer.set_body(make_body(OP_COMMENT, b"hi"));
+
+        let mut body = BuilderData::new();
+        body.append_u32(0xdeadbeef).unwrap();
+        body.append_reference_cell(ton_block::Serializable::serialize(&transfer).unwrap());
+        let mut message = Message::with_ext_in_header(ton_block::ExternalInboundMessageHeader::default());
+        message.set_body(body.into_cell().unwrap().into());
+
+        let summary = registry.summarize_message(&message);
+        assert_eq!(summary.value, None);
+        assert_eq!(summary.op, None);
+        assert_eq!(summary.intent, None);
+        assert!(registry.decode_message(&message).is_none());
+        assert_eq!(summary.actions.len(), 1);
+
+        let action = &summary.actions[0];
+        assert_eq!(action.op, Some(OP_COMMENT));
+        assert_eq!(action.destination, Some(destination));
+        assert_eq!(action.value, Some(Grams::from(100)));
+        assert_eq!(action.bounce, Some(true));
+        assert_eq!(action.intent, Some(Intent::Comment("hi".to_owned())));
+    }
+
+    #[test]
+    fn unknown_op() {
+        let registry = OpRegistry::with_known_ops();
+        assert!(registry.decode_body(&make_body(0xdeadbeef, &[])).is_none());
+    }
+
+    #[test]
+    fn custom_op() {
+        let mut registry = OpRegistry::default();
+        registry.register(0xdeadbeef, |body| {
+            Ok(Intent::Custom {
+                name: "ping".to_owned(),
+                description: format!("{} bits", body.remaining_bits()),
+            })
+        });
+
+        let intent = registry.decode_body(&make_body(0xdeadbeef, &[0; 2])).unwrap().unwrap();
+        assert_eq!(
+            intent,
+            Intent::Custom {
+                name: "ping".to_owned(),
+                description: "16 bits".to_owned()
+            }
+        );
+    }
+}
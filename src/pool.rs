@@ -1,44 +1,236 @@
-use std::convert::TryFrom;
-use std::ops::DerefMut;
-use std::sync::atomic::Ordering;
+use std::collections::HashMap;
+use std::net::{SocketAddr, SocketAddrV4};
+use std::ops::Deref;
+use std::sync::atomic::{AtomicUsize, Ordering};
 use std::sync::Arc;
-use std::time::Duration;
+use std::time::{Duration, Instant};
 
 use anyhow::Result;
 use async_trait::async_trait;
 use bb8::PooledConnection;
-use tiny_adnl::{AdnlTcpClient, AdnlTcpClientConfig};
+use tiny_adnl::AdnlTcpClientConfig;
 
-use crate::Config;
+use crate::rate_limit::{QueryPermit, RateLimiter};
+use crate::retry::RetryPolicy;
+use crate::stats::{ConnectionStats, LatencyRecorder, LatencySummary};
+use crate::transport::AdnlTransport;
+use crate::{Config, LiteServerConfig, ServerAddress};
 
-pub struct AdnlManageConnection {
-    config: AdnlTcpClientConfig,
+/// Periodically checks out all idle connections, so that the pool pings each of them
+/// and replaces the dead ones before they are needed
+pub fn spawn_keepalive<A>(pool: bb8::Pool<AdnlManageConnection<A>>, interval: Duration) -> futures::future::AbortHandle
+where
+    A: AdnlTransport,
+{
+    let (task, handle) = futures::future::abortable(async move {
+        loop {
+            tokio::time::sleep(interval).await;
+
+            // Connections are pinged one at a time to avoid the burst of pings.
+            // Checked connections are held until the end, so that each of them is pinged once
+            let idle_connections = pool.state().idle_connections;
+            let mut checked = Vec::with_capacity(idle_connections as usize);
+            let mut failed = 0;
+            for _ in 0..idle_connections {
+                match pool.get().await {
+                    Ok(connection) => checked.push(connection),
+                    Err(_) => failed += 1,
+                }
+            }
+            drop(checked);
+
+            log::trace!("Checked {} idle connections, failed: {}", idle_connections, failed);
+        }
+    });
+
+    tokio::spawn(task);
+    handle
+}
+
+pub struct AdnlManageConnection<A: AdnlTransport> {
+    servers: Arc<ServerSet>,
     ping_timeout: Duration,
+    retry_policy: RetryPolicy,
+    rate_limiter: Arc<RateLimiter>,
+    stats: Arc<ConnectionStats>,
+    #[cfg(feature = "fault-injection")]
+    fault_injector: Option<Arc<crate::fault_injection::FaultInjector>>,
+    context: Arc<A::Context>,
+    standby: Option<Arc<Standby<A>>>,
 }
 
-impl AdnlManageConnection {
-    pub fn new(config: &Config) -> Result<Self> {
-        Ok(Self {
-            config: AdnlTcpClientConfig::try_from(config)?,
+impl<A: AdnlTransport> AdnlManageConnection<A> {
+    pub fn new(
+        servers: Arc<ServerSet>,
+        config: &Config,
+        stats: Arc<ConnectionStats>,
+        rate_limiter: Arc<RateLimiter>,
+        context: Arc<A::Context>,
+        standby: Option<Arc<Standby<A>>>,
+    ) -> Self {
+        Self {
+            servers,
             ping_timeout: config.ping_timeout,
-        })
+            retry_policy: config.retry_policy,
+            rate_limiter,
+            stats,
+            #[cfg(feature = "fault-injection")]
+            fault_injector: config.fault_injector.clone(),
+            context,
+            standby,
+        }
+    }
+
+    fn make_connection(
+        &self,
+        client: Arc<A>,
+        server_address: SocketAddrV4,
+        server_id: usize,
+        handshake_duration: Duration,
+    ) -> AdnlConnection<A> {
+        self.servers.report_success(server_id);
+        self.servers.with_server(server_id, |server| server.connections += 1);
+
+        AdnlConnection {
+            client,
+            _guard: Arc::new(ConnectionGuard {
+                servers: self.servers.clone(),
+                server_id,
+            }),
+            servers: self.servers.clone(),
+            server_address,
+            server_id,
+            handshake_duration,
+            ping_rtt: None,
+            retry_policy: self.retry_policy,
+            rate_limiter: self.rate_limiter.clone(),
+            stats: self.stats.clone(),
+            #[cfg(feature = "fault-injection")]
+            fault_injector: self.fault_injector.clone(),
+        }
+    }
+
+    /// Establishes the spare connection to the server in background
+    fn spawn_standby(&self, standby: &Arc<Standby<A>>, config: AdnlTcpClientConfig, server_id: usize) {
+        if !standby.reserve(server_id) {
+            return;
+        }
+
+        let standby = standby.clone();
+        let servers = self.servers.clone();
+        let context = self.context.clone();
+        tokio::spawn(async move {
+            let server_address = config.server_address;
+            let started_at = Instant::now();
+            match A::connect(config, &context).await {
+                Ok(client) if servers.contains(server_id) => {
+                    log::debug!("Established standby connection to {}", server_address);
+                    standby.put(server_id, client, started_at.elapsed());
+                }
+                Ok(_) => standby.release(server_id),
+                Err(e) => {
+                    log::debug!("Failed to establish standby connection to {}: {:?}", server_address, e);
+                    standby.release(server_id);
+                }
+            }
+        });
+    }
+}
+
+/// Spare connections, at most one per server
+pub struct Standby<A> {
+    /// Server id to the connection and its handshake duration. `None` while connecting
+    clients: parking_lot::Mutex<HashMap<usize, Option<(Arc<A>, Duration)>>>,
+}
+
+impl<A> Default for Standby<A> {
+    fn default() -> Self {
+        Self {
+            clients: Default::default(),
+        }
+    }
+}
+
+impl<A: AdnlTransport> Standby<A> {
+    /// Takes the spare connection if it is still alive
+    fn take(&self, server_id: usize) -> Option<(Arc<A>, Duration)> {
+        let mut clients = self.clients.lock();
+        match clients.get(&server_id) {
+            Some(Some(_)) => clients.remove(&server_id).flatten().filter(|(client, _)| !client.is_broken()),
+            _ => None,
+        }
+    }
+
+    /// Returns `false` if the server already has the spare connection or it is being established
+    fn reserve(&self, server_id: usize) -> bool {
+        let mut clients = self.clients.lock();
+        match clients.entry(server_id) {
+            std::collections::hash_map::Entry::Occupied(_) => false,
+            std::collections::hash_map::Entry::Vacant(entry) => {
+                entry.insert(None);
+                true
+            }
+        }
+    }
+
+    fn put(&self, server_id: usize, client: Arc<A>, handshake_duration: Duration) {
+        self.clients.lock().insert(server_id, Some((client, handshake_duration)));
+    }
+
+    fn release(&self, server_id: usize) {
+        self.clients.lock().remove(&server_id);
+    }
+
+    /// Drops the spare connections to the servers which were removed from the list
+    pub fn retain_servers(&self, servers: &ServerSet) {
+        self.clients.lock().retain(|server_id, _| servers.contains(*server_id));
     }
 }
 
 #[async_trait]
-impl bb8::ManageConnection for AdnlManageConnection {
-    type Connection = Arc<AdnlTcpClient>;
+impl<A> bb8::ManageConnection for AdnlManageConnection<A>
+where
+    A: AdnlTransport,
+{
+    type Connection = AdnlConnection<A>;
     type Error = anyhow::Error;
 
     async fn connect(&self) -> Result<Self::Connection, Self::Error> {
-        log::debug!("Establishing adnl connection...");
-        match AdnlTcpClient::connect(self.config.clone()).await {
-            Ok(connection) => {
-                log::debug!("Established adnl connection");
-                Ok(connection)
+        let (mut config, server_id) = self.servers.next();
+        config.server_address = self.servers.resolve(server_id, config.server_address).await;
+        let server_address = config.server_address;
+
+        if let Some(standby) = &self.standby {
+            let spare = standby.take(server_id);
+            self.spawn_standby(standby, config.clone(), server_id);
+
+            // Spare connection could be closed by the server while it was idle
+            if let Some((client, handshake_duration)) = spare {
+                match client.ping(self.ping_timeout).await {
+                    Ok(_) => {
+                        log::debug!("Using standby connection to {}", server_address);
+                        return Ok(self.make_connection(client, server_address, server_id, handshake_duration));
+                    }
+                    Err(e) => log::debug!("Standby connection to {} is dead: {:?}", server_address, e),
+                }
+            }
+        }
+
+        log::debug!("Establishing adnl connection to {}...", server_address);
+
+        let started_at = Instant::now();
+        match A::connect(config, &self.context).await {
+            Ok(client) => {
+                let handshake_duration = started_at.elapsed();
+                self.stats.handshake.record(handshake_duration);
+
+                log::debug!("Established adnl connection in {:?}", handshake_duration);
+                Ok(self.make_connection(client, server_address, server_id, handshake_duration))
             }
             Err(e) => {
-                log::debug!("Failed to establish adnl connection");
+                log::debug!("Failed to establish adnl connection to {}", server_address);
+                self.stats.errors.lock().connect += 1;
+                self.servers.report_failure(server_id);
                 Err(e)
             }
         }
@@ -46,19 +238,722 @@ impl bb8::ManageConnection for AdnlManageConnection {
 
     async fn is_valid(&self, conn: &mut PooledConnection<'_, Self>) -> Result<(), Self::Error> {
         log::trace!("Check if connection is valid...");
-        match conn.deref_mut().ping(self.ping_timeout).await {
+        if conn.client.is_broken() {
+            log::trace!("Connection is broken");
+            return Err(anyhow::anyhow!("Connection is broken"));
+        }
+        if !self.servers.contains(conn.server_id) {
+            log::trace!("Connection server was removed");
+            return Err(anyhow::anyhow!("Server was removed"));
+        }
+        if self.servers.is_open(conn.server_id) {
+            log::trace!("Connection server circuit is open");
+            return Err(anyhow::anyhow!("Server circuit is open"));
+        }
+
+        let started_at = Instant::now();
+        match conn.client.ping(self.ping_timeout).await {
             Ok(_) => {
+                let ping_rtt = started_at.elapsed();
+                self.stats.ping.record(ping_rtt);
+                conn.ping_rtt = Some(ping_rtt);
+                self.servers.report_success(conn.server_id);
+                self.servers.report_latency(conn.server_id, ping_rtt);
+
                 log::trace!("Connection is valid");
                 Ok(())
             }
             Err(e) => {
                 log::trace!("Connection is invalid");
+                self.servers.report_failure(conn.server_id);
                 Err(e)
             }
         }
     }
 
     fn has_broken(&self, connection: &mut Self::Connection) -> bool {
-        connection.has_broken.load(Ordering::Acquire)
+        connection.client.is_broken() || !self.servers.contains(connection.server_id) || self.servers.is_open(connection.server_id)
+    }
+}
+
+pub struct AdnlConnection<A> {
+    client: Arc<A>,
+    _guard: Arc<ConnectionGuard>,
+    servers: Arc<ServerSet>,
+    server_address: SocketAddrV4,
+    /// Id of the server state, which changes when the server is removed from the list
+    server_id: usize,
+    handshake_duration: Duration,
+    ping_rtt: Option<Duration>,
+    retry_policy: RetryPolicy,
+    rate_limiter: Arc<RateLimiter>,
+    stats: Arc<ConnectionStats>,
+    #[cfg(feature = "fault-injection")]
+    fault_injector: Option<Arc<crate::fault_injection::FaultInjector>>,
+}
+
+impl<A> Clone for AdnlConnection<A> {
+    fn clone(&self) -> Self {
+        Self {
+            client: self.client.clone(),
+            _guard: self._guard.clone(),
+            servers: self.servers.clone(),
+            server_address: self.server_address,
+            server_id: self.server_id,
+            handshake_duration: self.handshake_duration,
+            ping_rtt: self.ping_rtt,
+            retry_policy: self.retry_policy,
+            rate_limiter: self.rate_limiter.clone(),
+            stats: self.stats.clone(),
+            #[cfg(feature = "fault-injection")]
+            fault_injector: self.fault_injector.clone(),
+        }
+    }
+}
+
+impl<A> AdnlConnection<A>
+where
+    A: AdnlTransport,
+{
+    /// Waits for the rate limiter permission
+    pub async fn acquire_permit(&self) -> crate::errors::TonlibResult<QueryPermit<'_>> {
+        self.rate_limiter.acquire(self.server_address).await
+    }
+
+    /// Sends query to the server, applying the injected faults if any.
+    ///
+    /// Returns the response and its size in bytes
+    pub async fn send_query(&self, query: &ton_api::ton::TLObject) -> Result<(ton_api::ton::TLObject, usize)> {
+        #[cfg(feature = "fault-injection")]
+        if let Some(fault_injector) = &self.fault_injector {
+            if let Some(response) = fault_injector.apply().await {
+                return response.map(|response| {
+                    let size = ton_api::BoxedSerialize::boxed_serialized_bytes(&response)
+                        .map(|bytes| bytes.len())
+                        .unwrap_or_default();
+                    (response, size)
+                });
+            }
+        }
+
+        self.client.query_sized(query).await
+    }
+
+    pub fn server_address(&self) -> SocketAddrV4 {
+        self.server_address
+    }
+
+    pub fn server_id(&self) -> usize {
+        self.server_id
+    }
+
+    pub fn handshake_duration(&self) -> Duration {
+        self.handshake_duration
+    }
+
+    /// RTT of the last successful ping
+    pub fn ping_rtt(&self) -> Option<Duration> {
+        self.ping_rtt
+    }
+
+    pub fn retry_policy(&self) -> RetryPolicy {
+        self.retry_policy
+    }
+
+    /// Marks connection as broken and reports the failure of its server
+    pub fn report_failure(&self) {
+        #[cfg(feature = "metrics")]
+        crate::telemetry::transport_error();
+        self.stats.errors.lock().transport += 1;
+        self.client.mark_broken();
+        self.servers.report_failure(self.server_id);
+    }
+
+    pub fn report_latency(&self, latency: Duration) {
+        self.servers.report_latency(self.server_id, latency);
+    }
+
+    pub fn report_checkout(&self, duration: Duration) {
+        self.stats.checkout.record(duration);
+    }
+
+    pub fn report_not_ready(&self) {
+        #[cfg(feature = "metrics")]
+        crate::telemetry::not_ready();
+        self.stats.errors.lock().not_ready += 1;
+    }
+
+    #[cfg_attr(not(feature = "metrics"), allow(unused_variables))]
+    pub fn report_lite_server_error(&self, code: i32) {
+        #[cfg(feature = "metrics")]
+        crate::telemetry::lite_server_error(code);
+        self.stats.errors.lock().lite_server += 1;
+    }
+
+    pub fn report_last_seqno(&self, seqno: u32) {
+        self.servers.report_last_seqno(self.server_id, seqno);
+    }
+
+    /// Records query payload size and the response size reported by the transport
+    pub fn report_traffic(&self, query: &'static str, bytes_sent: usize, bytes_received: usize) {
+        #[cfg(feature = "metrics")]
+        crate::telemetry::query(query, bytes_sent, bytes_received);
+        self.stats.traffic.record(query, bytes_sent, bytes_received);
+    }
+}
+
+/// Tracks the number of the server connections. Shared by all handles of the session
+struct ConnectionGuard {
+    servers: Arc<ServerSet>,
+    server_id: usize,
+}
+
+impl Drop for ConnectionGuard {
+    fn drop(&mut self) {
+        self.servers
+            .with_server(self.server_id, |server| server.connections = server.connections.saturating_sub(1));
+    }
+}
+
+impl<A> Deref for AdnlConnection<A> {
+    type Target = A;
+
+    fn deref(&self) -> &Self::Target {
+        &self.client
+    }
+}
+
+/// Configured lite servers. Connections are distributed across them in round-robin order,
+/// skipping servers with the open circuit and preferring the ones with lower latency.
+///
+/// Servers are identified by the id of their state rather than by the address,
+/// which can change when the hostname is resolved again.
+///
+/// Connections to the servers which were removed from the list are dropped
+/// as soon as they are returned to the pool, so in-flight queries are not interrupted
+pub struct ServerSet {
+    servers: parking_lot::Mutex<Vec<ServerState>>,
+    circuit_breaker: CircuitBreakerConfig,
+    socket_read_timeout: Duration,
+    socket_send_timeout: Duration,
+    next_id: AtomicUsize,
+    next: AtomicUsize,
+}
+
+impl ServerSet {
+    pub async fn new(servers: &[LiteServerConfig], config: &Config) -> Result<Self> {
+        let server_set = Self {
+            servers: Default::default(),
+            circuit_breaker: config.circuit_breaker,
+            socket_read_timeout: config.socket_read_timeout,
+            socket_send_timeout: config.socket_send_timeout,
+            next_id: AtomicUsize::new(0),
+            next: AtomicUsize::new(0),
+        };
+        *server_set.servers.lock() = server_set
+            .make_configs(servers)
+            .await?
+            .into_iter()
+            .map(|(config, host)| server_set.make_state(config, host))
+            .collect();
+        Ok(server_set)
+    }
+
+    /// Picks the next available server. Servers with the open circuit are used only if there are no other.
+    ///
+    /// Of the two next available servers the one with lower median latency is chosen
+    /// Returns config and id of the picked server
+    pub fn next(&self) -> (AdnlTcpClientConfig, usize) {
+        let now = Instant::now();
+
+        let mut servers = self.servers.lock();
+        let start = self.next.fetch_add(1, Ordering::Relaxed) % servers.len();
+        let mut candidates = (0..servers.len())
+            .map(|i| (start + i) % servers.len())
+            .filter(|&i| servers[i].is_available(now));
+
+        let index = match (candidates.next(), candidates.next()) {
+            (Some(first), Some(second)) if servers[second].median_latency() < servers[first].median_latency() => second,
+            (Some(first), _) => first,
+            (None, _) => start,
+        };
+
+        let server = &mut servers[index];
+        if !matches!(server.circuit, Circuit::Closed) {
+            // Allow only one probe during the open duration
+            log::debug!("Probing server {}", server.config.server_address);
+            server.circuit = Circuit::HalfOpen {
+                probe_deadline: now + self.circuit_breaker.open_duration,
+            };
+        }
+
+        (server.config.clone(), server.id)
+    }
+
+    pub fn configs(&self) -> Vec<AdnlTcpClientConfig> {
+        self.servers.lock().iter().map(|server| server.config.clone()).collect()
+    }
+
+    /// Returns configs of the servers with the closed or half-open circuit
+    pub fn available_configs(&self) -> Vec<AdnlTcpClientConfig> {
+        let now = Instant::now();
+        self.servers
+            .lock()
+            .iter()
+            .filter(|server| server.circuit_state(now) != CircuitState::Open)
+            .map(|server| server.config.clone())
+            .collect()
+    }
+
+    pub fn addresses(&self) -> Vec<SocketAddrV4> {
+        self.servers.lock().iter().map(|server| server.config.server_address).collect()
+    }
+
+    /// Returns `false` if the server was removed from the list
+    pub fn contains(&self, id: usize) -> bool {
+        self.servers.lock().iter().any(|server| server.id == id)
+    }
+
+    pub fn health(&self) -> Vec<ServerHealth> {
+        let now = Instant::now();
+        self.servers
+            .lock()
+            .iter()
+            .map(|server| ServerHealth {
+                address: server.config.server_address,
+                successes: server.successes,
+                failures: server.failures,
+                consecutive_failures: server.consecutive_failures,
+                last_seqno: server.last_seqno,
+                connections: server.connections,
+                circuit: server.circuit_state(now),
+                latency: server.latency.summary(),
+            })
+            .collect()
+    }
+
+    pub fn is_open(&self, id: usize) -> bool {
+        let now = Instant::now();
+        self.servers
+            .lock()
+            .iter()
+            .any(|server| server.id == id && server.circuit_state(now) == CircuitState::Open)
+    }
+
+    pub fn report_success(&self, id: usize) {
+        self.with_server(id, |server| {
+            server.successes += 1;
+            server.consecutive_failures = 0;
+            if let Circuit::HalfOpen { .. } = server.circuit {
+                log::info!("Circuit for server {} closed", server.config.server_address);
+                server.circuit = Circuit::Closed;
+            }
+        });
+    }
+
+    pub fn report_failure(&self, id: usize) {
+        let circuit_breaker = self.circuit_breaker;
+        self.with_server(id, |server| {
+            server.failures += 1;
+            server.consecutive_failures += 1;
+
+            let failed_probe = matches!(server.circuit, Circuit::HalfOpen { .. });
+            if failed_probe || server.consecutive_failures >= circuit_breaker.failure_threshold {
+                log::warn!(
+                    "Circuit for server {} opened after {} failures",
+                    server.config.server_address,
+                    server.consecutive_failures
+                );
+                server.open(&circuit_breaker);
+            }
+        });
+    }
+
+    /// Records RTT of a successful query or ping
+    pub fn report_latency(&self, id: usize, latency: Duration) {
+        self.with_server(id, |server| server.latency.record(latency));
+    }
+
+    /// Opens the circuit for the server if it lags behind the others
+    pub fn report_last_seqno(&self, id: usize, seqno: u32) {
+        let mut servers = self.servers.lock();
+
+        let max_seqno = servers.iter().filter_map(|server| server.last_seqno).max().unwrap_or_default();
+
+        if let Some(server) = servers.iter_mut().find(|server| server.id == id) {
+            server.last_seqno = Some(seqno);
+            if seqno.saturating_add(self.circuit_breaker.max_seqno_lag) < max_seqno {
+                log::warn!(
+                    "Circuit for stale server {} opened. Seqno: {}, max: {}",
+                    server.config.server_address,
+                    seqno,
+                    max_seqno
+                );
+                server.open(&self.circuit_breaker);
+            }
+        }
+    }
+
+    /// Returns `false` if the list is already in use
+    pub async fn update(&self, servers: &[LiteServerConfig]) -> Result<bool> {
+        let new_servers = self.make_configs(servers).await?;
+
+        let mut servers = self.servers.lock();
+        let unchanged = servers.len() == new_servers.len()
+            && servers
+                .iter()
+                .zip(new_servers.iter())
+                .all(|(old, (config, host))| old.is_same(config, host));
+        if unchanged {
+            return Ok(false);
+        }
+
+        // Keep health of the remaining servers
+        let mut old_servers = std::mem::take(&mut *servers);
+        *servers = new_servers
+            .into_iter()
+            .map(
+                |(config, host)| match old_servers.iter().position(|server| server.is_same(&config, &host)) {
+                    Some(i) => old_servers.swap_remove(i),
+                    None => self.make_state(config, host),
+                },
+            )
+            .collect();
+
+        Ok(true)
+    }
+
+    fn make_state(&self, config: AdnlTcpClientConfig, host: Option<String>) -> ServerState {
+        ServerState::new(self.next_id.fetch_add(1, Ordering::Relaxed), config, host)
+    }
+
+    fn with_server<F>(&self, id: usize, f: F)
+    where
+        F: FnOnce(&mut ServerState),
+    {
+        if let Some(server) = self.servers.lock().iter_mut().find(|server| server.id == id) {
+            f(server);
+        }
+    }
+
+    /// Resolves the hostname of the server again, so that DNS changes are picked up on reconnect.
+    ///
+    /// Returns the current address of the server
+    pub async fn resolve(&self, id: usize, address: SocketAddrV4) -> SocketAddrV4 {
+        let host = self
+            .servers
+            .lock()
+            .iter()
+            .find(|server| server.id == id)
+            .and_then(|server| server.host.clone());
+        let host = match host {
+            Some(host) => host,
+            None => return address,
+        };
+
+        let resolved = match lookup_v4(&host, address.port()).await {
+            Ok(resolved) => Some(resolved),
+            Err(e) => {
+                log::warn!("Failed to resolve {}: {:?}", host, e);
+                None
+            }
+        };
+
+        match resolved {
+            Some(resolved) if resolved != address => {
+                log::debug!("{} resolved to {}", host, resolved);
+                self.with_server(id, |server| server.config.server_address = resolved);
+                resolved
+            }
+            _ => address,
+        }
+    }
+
+    /// Builds ADNL configs, resolving hostnames
+    async fn make_configs(&self, servers: &[LiteServerConfig]) -> Result<Vec<(AdnlTcpClientConfig, Option<String>)>> {
+        if servers.is_empty() {
+            return Err(anyhow::anyhow!("No lite servers specified"));
+        }
+
+        let mut configs = Vec::with_capacity(servers.len());
+        for server in servers {
+            let (server_address, host) = match &server.address {
+                ServerAddress::Ip(address) => (*address, None),
+                ServerAddress::Host { host, port } => (lookup_v4(host, *port).await?, Some(host.clone())),
+            };
+
+            let config = AdnlTcpClientConfig {
+                server_address,
+                server_key: crate::parse_server_key(&server.key)?,
+                socket_read_timeout: self.socket_read_timeout,
+                socket_send_timeout: self.socket_send_timeout,
+            };
+            configs.push((config, host));
+        }
+        Ok(configs)
+    }
+}
+
+/// Returns the first IPv4 address of the host
+async fn lookup_v4(host: &str, port: u16) -> Result<SocketAddrV4> {
+    tokio::net::lookup_host((host, port))
+        .await?
+        .filter_map(only_v4)
+        .next()
+        .ok_or_else(|| anyhow::anyhow!("No IPv4 addresses found for {}", host))
+}
+
+fn only_v4(address: SocketAddr) -> Option<SocketAddrV4> {
+    match address {
+        SocketAddr::V4(address) => Some(address),
+        SocketAddr::V6(_) => None,
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct ServerHealth {
+    pub address: SocketAddrV4,
+    pub successes: u64,
+    pub failures: u64,
+    pub consecutive_failures: u32,
+    /// Last masterchain block seqno received from the server
+    pub last_seqno: Option<u32>,
+    /// Number of the open connections to the server
+    pub connections: usize,
+    pub circuit: CircuitState,
+    /// RTT of the queries and pings
+    pub latency: LatencySummary,
+}
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum CircuitState {
+    /// Server is used as usual
+    Closed,
+    /// Server is excluded from the selection
+    Open,
+    /// Server is allowed to get a probe connection
+    HalfOpen,
+}
+
+#[derive(Debug, Copy, Clone)]
+pub struct CircuitBreakerConfig {
+    /// Number of consecutive failures after which the circuit is opened
+    pub failure_threshold: u32,
+    /// Duration after which the server is probed again
+    pub open_duration: Duration,
+    /// Number of masterchain blocks by which the server can lag behind the others
+    /// before its circuit is opened
+    pub max_seqno_lag: u32,
+}
+
+impl Default for CircuitBreakerConfig {
+    fn default() -> Self {
+        Self {
+            failure_threshold: 3,
+            open_duration: Duration::from_secs(30),
+            max_seqno_lag: 10,
+        }
+    }
+}
+
+#[derive(Debug, Copy, Clone)]
+enum Circuit {
+    Closed,
+    Open { until: Instant },
+    HalfOpen { probe_deadline: Instant },
+}
+
+struct ServerState {
+    id: usize,
+    config: AdnlTcpClientConfig,
+    /// Hostname from which the address was resolved
+    host: Option<String>,
+    successes: u64,
+    failures: u64,
+    consecutive_failures: u32,
+    last_seqno: Option<u32>,
+    connections: usize,
+    circuit: Circuit,
+    latency: LatencyRecorder,
+}
+
+impl ServerState {
+    fn new(id: usize, config: AdnlTcpClientConfig, host: Option<String>) -> Self {
+        Self {
+            id,
+            config,
+            host,
+            successes: 0,
+            failures: 0,
+            consecutive_failures: 0,
+            last_seqno: None,
+            connections: 0,
+            circuit: Circuit::Closed,
+            latency: LatencyRecorder::default(),
+        }
+    }
+
+    /// Servers without measurements are considered the fastest, so they are probed first
+    fn median_latency(&self) -> Duration {
+        self.latency.summary().p50
+    }
+
+    /// Servers with the same hostname are the same regardless of the resolved address
+    fn is_same(&self, config: &AdnlTcpClientConfig, host: &Option<String>) -> bool {
+        let same_address = match (&self.host, host) {
+            (Some(old), Some(new)) => old == new,
+            (None, None) => self.config.server_address == config.server_address,
+            _ => false,
+        };
+        same_address && self.config.server_key == config.server_key
+    }
+
+    fn circuit_state(&self, now: Instant) -> CircuitState {
+        match self.circuit {
+            Circuit::Closed => CircuitState::Closed,
+            Circuit::Open { until } if now < until => CircuitState::Open,
+            _ => CircuitState::HalfOpen,
+        }
+    }
+
+    /// Server with the half-open circuit is available again if the previous probe was lost
+    fn is_available(&self, now: Instant) -> bool {
+        match self.circuit {
+            Circuit::Closed => true,
+            Circuit::Open { until } => now >= until,
+            Circuit::HalfOpen { probe_deadline } => now >= probe_deadline,
+        }
+    }
+
+    fn open(&mut self, config: &CircuitBreakerConfig) {
+        self.consecutive_failures = 0;
+        self.circuit = Circuit::Open {
+            until: Instant::now() + config.open_duration,
+        };
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::replay::{Fixtures, ReplayContext, ReplayTransport};
+
+    async fn make_servers(count: u16, circuit_breaker: CircuitBreakerConfig) -> ServerSet {
+        let servers = (0..count)
+            .map(|i| LiteServerConfig {
+                address: ServerAddress::Ip(SocketAddrV4::new([127, 0, 0, 1].into(), 3000 + i)),
+                key: "uNRRL+6enQjuiZ/s6Z+vO7yxUUR7uxdfzIy+RxkECrc=".to_owned(),
+            })
+            .collect::<Vec<_>>();
+        let config = Config {
+            circuit_breaker,
+            ..Default::default()
+        };
+        ServerSet::new(&servers, &config).await.unwrap()
+    }
+
+    #[tokio::test]
+    async fn prefers_lower_latency() {
+        let servers = make_servers(2, Default::default()).await;
+        servers.report_latency(0, Duration::from_millis(100));
+        servers.report_latency(1, Duration::from_millis(10));
+
+        for _ in 0..4 {
+            assert_eq!(servers.next().1, 1);
+        }
+    }
+
+    #[tokio::test]
+    async fn stale_server_quarantined() {
+        let servers = make_servers(2, Default::default()).await;
+        servers.report_last_seqno(0, 100);
+        servers.report_last_seqno(1, 95);
+        assert!(!servers.is_open(1));
+
+        servers.report_last_seqno(1, 80);
+        assert!(servers.is_open(1));
+        for _ in 0..4 {
+            assert_eq!(servers.next().1, 0);
+        }
+    }
+
+    #[tokio::test]
+    async fn seqno_lag_overflow() {
+        let servers = make_servers(2, Default::default()).await;
+        servers.report_last_seqno(0, u32::MAX);
+        servers.report_last_seqno(1, u32::MAX - 1);
+        assert!(!servers.is_open(1));
+    }
+
+    #[tokio::test]
+    async fn circuit_opens_after_failures() {
+        let servers = make_servers(2, Default::default()).await;
+        servers.report_failure(0);
+        servers.report_failure(0);
+        assert!(!servers.is_open(0));
+
+        // Success resets the consecutive failures
+        servers.report_success(0);
+        servers.report_failure(0);
+        servers.report_failure(0);
+        assert!(!servers.is_open(0));
+
+        servers.report_failure(0);
+        assert!(servers.is_open(0));
+        for _ in 0..4 {
+            assert_eq!(servers.next().1, 1);
+        }
+    }
+
+    #[tokio::test]
+    async fn half_open_probe() {
+        let servers = make_servers(
+            1,
+            CircuitBreakerConfig {
+                failure_threshold: 1,
+                open_duration: Duration::from_secs(0),
+                ..Default::default()
+            },
+        )
+        .await;
+
+        servers.report_failure(0);
+        assert_eq!(servers.health()[0].circuit, CircuitState::HalfOpen);
+
+        // Failed probe opens the circuit again
+        assert_eq!(servers.next().1, 0);
+        servers.report_failure(0);
+        assert_eq!(servers.health()[0].failures, 2);
+
+        assert_eq!(servers.next().1, 0);
+        servers.report_success(0);
+        assert_eq!(servers.health()[0].circuit, CircuitState::Closed);
+    }
+
+    #[tokio::test]
+    async fn standby_of_removed_servers() {
+        let servers = make_servers(3, Default::default()).await;
+        let context = ReplayContext::new(&Fixtures::default()).unwrap();
+
+        let standby = Standby::<ReplayTransport>::default();
+        for (server_id, config) in servers.configs().into_iter().enumerate() {
+            let client = ReplayTransport::connect(config, &context).await.unwrap();
+            standby.put(server_id, client, Duration::from_millis(1));
+        }
+
+        let retained = (1..3)
+            .map(|i| LiteServerConfig {
+                address: ServerAddress::Ip(SocketAddrV4::new([127, 0, 0, 1].into(), 3000 + i)),
+                key: "uNRRL+6enQjuiZ/s6Z+vO7yxUUR7uxdfzIy+RxkECrc=".to_owned(),
+            })
+            .collect::<Vec<_>>();
+        assert!(servers.update(&retained).await.unwrap());
+        standby.retain_servers(&servers);
+        assert!(standby.take(0).is_none());
+
+        // Broken spare is not used
+        standby.clients.lock()[&1].as_ref().unwrap().0.mark_broken();
+        assert!(standby.take(1).is_none());
+        assert!(standby.take(2).is_some());
     }
 }
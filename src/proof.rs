@@ -0,0 +1,578 @@
+use serde::{Deserialize, Serialize};
+use ton_api::ton;
+use ton_api::ton::ton_node::blockidext::BlockIdExt;
+use ton_block::{Deserializable, HashmapAugType};
+use ton_types::{Cell, UInt256};
+
+use crate::errors::*;
+use crate::TrustMode;
+
+#[derive(Debug, Clone)]
+pub struct ShardBlockProof {
+    pub masterchain_id: BlockIdExt,
+    pub links: Vec<ShardBlockLink>,
+}
+
+#[derive(Debug, Clone)]
+pub struct ShardBlockLink {
+    pub id: BlockIdExt,
+    pub proof: Vec<u8>,
+}
+
+/// Checks that the chain of links leads from the masterchain block to the specified shard block.
+///
+/// The first link proves that the masterchain block references the top shard block,
+/// each next link proves that the block references the previous one
+///
+/// Links are not checked in `TrustMode::TrustServer`
+pub fn check_shard_block_proof(
+    id: &BlockIdExt,
+    proof: ton::lite_server::shardblockproof::ShardBlockProof,
+    trust_mode: TrustMode,
+) -> TonlibResult<ShardBlockProof> {
+    let links = proof
+        .links
+        .into_iter()
+        .map(|link| ShardBlockLink {
+            id: link.id,
+            proof: link.proof.0,
+        })
+        .collect::<Vec<_>>();
+
+    if trust_mode == TrustMode::TrustServer {
+        return Ok(ShardBlockProof {
+            masterchain_id: proof.masterchain_id,
+            links,
+        });
+    }
+
+    match links.first() {
+        Some(first) if first.id == proof.masterchain_id => {}
+        None if &proof.masterchain_id == id => {}
+        _ => return Err(TonlibError::InvalidBlockProof),
+    }
+
+    for (i, link) in links.iter().enumerate() {
+        let next = match links.get(i + 1) {
+            Some(next) => &next.id,
+            None => id,
+        };
+
+        let block = read_block_proof(&link.id, &link.proof)?;
+
+        if link.id.workchain == ton_block::MASTERCHAIN_ID {
+            let shard =
+                ton_block::ShardIdent::with_tagged_prefix(next.workchain, next.shard as u64).map_err(|_| TonlibError::InvalidBlockProof)?;
+
+            let descr = block
+                .read_extra()
+                .and_then(|extra| extra.read_custom())
+                .map_err(|_| TonlibError::InvalidBlockProof)?
+                .ok_or(TonlibError::InvalidBlockProof)?
+                .shards()
+                .get_shard(&shard)
+                .map_err(|_| TonlibError::InvalidBlockProof)?
+                .ok_or(TonlibError::InvalidBlockProof)?
+                .descr;
+
+            if descr.seq_no != next.seqno as u32 || descr.root_hash != UInt256::from(next.root_hash.0) {
+                return Err(TonlibError::InvalidBlockProof);
+            }
+        } else {
+            let prev_ref = block
+                .read_info()
+                .and_then(|info| info.read_prev_ref())
+                .map_err(|_| TonlibError::InvalidBlockProof)?;
+
+            let prev1 = prev_ref.prev1().map_err(|_| TonlibError::InvalidBlockProof)?;
+            let prev2 = prev_ref.prev2().map_err(|_| TonlibError::InvalidBlockProof)?;
+
+            let is_prev =
+                |prev: &ton_block::ExtBlkRef| prev.seq_no == next.seqno as u32 && prev.root_hash == UInt256::from(next.root_hash.0);
+            if !is_prev(&prev1) && !prev2.as_ref().map(is_prev).unwrap_or_default() {
+                return Err(TonlibError::InvalidBlockProof);
+            }
+        }
+    }
+
+    Ok(ShardBlockProof {
+        masterchain_id: proof.masterchain_id,
+        links,
+    })
+}
+
+/// Portable proof that the transaction is included into the block committed by the masterchain block.
+///
+/// The masterchain block itself is not proven, so it must be compared with a trusted one
+#[derive(Debug, Clone)]
+pub struct TransactionProof {
+    /// Block with the transaction
+    pub block_id: BlockIdExt,
+    /// Transaction BOC
+    pub transaction: Vec<u8>,
+    /// Merkle proof of the transaction in the block
+    pub transaction_proof: Vec<u8>,
+    /// Links from the masterchain block to the block with the transaction
+    pub shard_block_proof: ShardBlockProof,
+}
+
+impl TransactionProof {
+    /// Checks all proofs and returns the transaction hash and the transaction
+    pub fn verify(&self) -> TonlibResult<(UInt256, ton_block::Transaction)> {
+        let shard_block_proof = ton::lite_server::shardblockproof::ShardBlockProof {
+            masterchain_id: self.shard_block_proof.masterchain_id.clone(),
+            links: self
+                .shard_block_proof
+                .links
+                .iter()
+                .map(|link| ton::lite_server::shardblocklink::ShardBlockLink {
+                    id: link.id.clone(),
+                    proof: ton::bytes(link.proof.clone()),
+                })
+                .collect(),
+        };
+        check_shard_block_proof(&self.block_id, shard_block_proof, TrustMode::VerifyProofs)?;
+
+        let cell = ton_types::deserialize_tree_of_cells(&mut std::io::Cursor::new(&self.transaction))
+            .map_err(|_| TonlibError::InvalidTransaction)?;
+        let hash = cell.repr_hash();
+        let transaction = ton_block::Transaction::construct_from_cell(cell).map_err(|_| TonlibError::InvalidTransaction)?;
+
+        let block = read_block_proof(&self.block_id, &self.transaction_proof)?;
+        let account_block = block
+            .read_extra()
+            .and_then(|extra| extra.read_account_blocks())
+            .and_then(|account_blocks| account_blocks.get(&transaction.account_addr))
+            .map_err(|_| TonlibError::InvalidBlockProof)?
+            .ok_or(TonlibError::InvalidBlockProof)?;
+
+        // Transaction cell is the last reference of the dictionary leaf. It is compared by hash,
+        // because the transactions are usually pruned in the proof
+        let mut included = false;
+        account_block
+            .transactions()
+            .iterate_slices(|mut key, value| {
+                if key.get_next_u64()? == transaction.lt {
+                    let refs = value.remaining_references();
+                    included = refs > 0 && value.reference(refs - 1)?.repr_hash() == hash;
+                    return Ok(false);
+                }
+                Ok(true)
+            })
+            .map_err(|_| TonlibError::InvalidBlockProof)?;
+
+        if !included {
+            return Err(TonlibError::InvalidBlockProof);
+        }
+        Ok((hash, transaction))
+    }
+
+    pub fn to_json(&self) -> TonlibResult<String> {
+        let repr = TransactionProofRepr {
+            masterchain_id: block_id_to_string(&self.shard_block_proof.masterchain_id),
+            block_id: block_id_to_string(&self.block_id),
+            transaction: base64::encode(&self.transaction),
+            transaction_proof: base64::encode(&self.transaction_proof),
+            links: self
+                .shard_block_proof
+                .links
+                .iter()
+                .map(|link| ShardBlockLinkRepr {
+                    id: block_id_to_string(&link.id),
+                    proof: base64::encode(&link.proof),
+                })
+                .collect(),
+        };
+        serde_json::to_string(&repr).map_err(|_| TonlibError::FailedToSerialize)
+    }
+
+    pub fn from_json(data: &str) -> TonlibResult<Self> {
+        let repr: TransactionProofRepr = serde_json::from_str(data).map_err(|_| TonlibError::InvalidBlockProof)?;
+        let decode = |data: &str| base64::decode(data).map_err(|_| TonlibError::InvalidBlockProof);
+
+        Ok(Self {
+            block_id: block_id_from_str(&repr.block_id)?,
+            transaction: decode(&repr.transaction)?,
+            transaction_proof: decode(&repr.transaction_proof)?,
+            shard_block_proof: ShardBlockProof {
+                masterchain_id: block_id_from_str(&repr.masterchain_id)?,
+                links: repr
+                    .links
+                    .iter()
+                    .map(|link| {
+                        Ok(ShardBlockLink {
+                            id: block_id_from_str(&link.id)?,
+                            proof: decode(&link.proof)?,
+                        })
+                    })
+                    .collect::<TonlibResult<_>>()?,
+            },
+        })
+    }
+}
+
+/// Verifies the exported transaction proof without network access.
+///
+/// The bundle must be committed by exactly the trusted masterchain block, e.g. the one
+/// the auditor received from its own node. The masterchain block is not linked to a key block
+/// and validator signatures are not checked, so the guarantee is only as strong as the source
+/// of `trusted_mc_block`
+pub fn verify_proof_bundle(bundle: &TransactionProof, trusted_mc_block: &BlockIdExt) -> TonlibResult<(UInt256, ton_block::Transaction)> {
+    if &bundle.shard_block_proof.masterchain_id != trusted_mc_block {
+        return Err(TonlibError::InvalidBlockProof);
+    }
+    bundle.verify()
+}
+
+#[derive(Serialize, Deserialize)]
+struct TransactionProofRepr {
+    masterchain_id: String,
+    block_id: String,
+    transaction: String,
+    transaction_proof: String,
+    links: Vec<ShardBlockLinkRepr>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct ShardBlockLinkRepr {
+    id: String,
+    proof: String,
+}
+
+/// Formats block id as `workchain:shard:seqno:root_hash:file_hash`
+fn block_id_to_string(id: &BlockIdExt) -> String {
+    format!(
+        "{}:{:016x}:{}:{}:{}",
+        id.workchain,
+        id.shard as u64,
+        id.seqno,
+        hex::encode(&id.root_hash.0),
+        hex::encode(&id.file_hash.0)
+    )
+}
+
+fn block_id_from_str(id: &str) -> TonlibResult<BlockIdExt> {
+    let parse = || -> Option<BlockIdExt> {
+        let mut parts = id.split(':');
+        let workchain = parts.next()?.parse().ok()?;
+        let shard = u64::from_str_radix(parts.next()?, 16).ok()? as i64;
+        let seqno = parts.next()?.parse().ok()?;
+        let mut parse_hash = || -> Option<[u8; 32]> {
+            let mut hash = [0u8; 32];
+            hex::decode_to_slice(parts.next()?, &mut hash).ok()?;
+            Some(hash)
+        };
+        let root_hash = ton::int256(parse_hash()?);
+        let file_hash = ton::int256(parse_hash()?);
+        if parts.next().is_some() {
+            return None;
+        }
+        Some(BlockIdExt {
+            workchain,
+            shard,
+            seqno,
+            root_hash,
+            file_hash,
+        })
+    };
+    parse().ok_or(TonlibError::InvalidBlockProof)
+}
+
+/// Checks that the shard state proof of `liteServer.accountState` belongs to the shard block.
+///
+/// `q_roots` are the shard block proof and the shard state proof
+pub fn check_account_state_proof(shard_block_id: &BlockIdExt, q_roots: &[Cell]) -> TonlibResult<()> {
+    if q_roots.len() < 2 {
+        return Err(TonlibError::InvalidAccountStateProof);
+    }
+
+    let block = read_block_proof_cell(shard_block_id, q_roots[0].clone())?;
+    check_state_proof(&block, q_roots[1].clone()).map(|_| ())
+}
+
+/// Checks that the shard block is referenced by the masterchain block
+pub fn check_shard_proof(masterchain_id: &BlockIdExt, shard_block_id: &BlockIdExt, shard_proof: &[u8]) -> TonlibResult<()> {
+    if shard_block_id.workchain == ton_block::MASTERCHAIN_ID {
+        return if shard_block_id == masterchain_id {
+            Ok(())
+        } else {
+            Err(TonlibError::InvalidBlockProof)
+        };
+    }
+
+    let roots = ton_types::deserialize_cells_tree(&mut std::io::Cursor::new(shard_proof)).map_err(|_| TonlibError::InvalidBlockProof)?;
+    if roots.len() != 2 {
+        return Err(TonlibError::InvalidBlockProof);
+    }
+
+    let block = read_block_proof_cell(masterchain_id, roots[0].clone())?;
+    let state = check_state_proof(&block, roots[1].clone())?;
+
+    let shard = ton_block::ShardIdent::with_tagged_prefix(shard_block_id.workchain, shard_block_id.shard as u64)
+        .map_err(|_| TonlibError::InvalidBlockProof)?;
+
+    let descr = state
+        .read_custom()
+        .map_err(|_| TonlibError::InvalidBlockProof)?
+        .ok_or(TonlibError::InvalidBlockProof)?
+        .shards()
+        .get_shard(&shard)
+        .map_err(|_| TonlibError::InvalidBlockProof)?
+        .ok_or(TonlibError::InvalidBlockProof)?
+        .descr;
+
+    if descr.seq_no != shard_block_id.seqno as u32 || descr.root_hash != UInt256::from(shard_block_id.root_hash.0) {
+        return Err(TonlibError::InvalidBlockProof);
+    }
+    Ok(())
+}
+
+/// Checks that the state merkle proof corresponds to the new state of the block
+fn check_state_proof(block: &ton_block::Block, proof: Cell) -> TonlibResult<ton_block::ShardStateUnsplit> {
+    let state_update = block.read_state_update().map_err(|_| TonlibError::InvalidBlockProof)?;
+
+    let merkle_proof = ton_block::MerkleProof::construct_from_cell(proof).map_err(|_| TonlibError::InvalidAccountStateProof)?;
+    if merkle_proof.hash != state_update.new_hash {
+        return Err(TonlibError::InvalidAccountStateProof);
+    }
+
+    ton_block::ShardStateUnsplit::construct_from_cell(merkle_proof.proof.virtualize(1)).map_err(|_| TonlibError::InvalidAccountStateProof)
+}
+
+/// Deserializes block merkle proof and checks that it belongs to the specified block
+pub fn read_block_proof(id: &BlockIdExt, proof: &[u8]) -> TonlibResult<ton_block::Block> {
+    let root = ton_types::deserialize_tree_of_cells(&mut std::io::Cursor::new(proof)).map_err(|_| TonlibError::InvalidBlockProof)?;
+    read_block_proof_cell(id, root)
+}
+
+fn read_block_proof_cell(id: &BlockIdExt, root: Cell) -> TonlibResult<ton_block::Block> {
+    let merkle_proof = ton_block::MerkleProof::construct_from_cell(root).map_err(|_| TonlibError::InvalidBlockProof)?;
+    if merkle_proof.hash != UInt256::from(id.root_hash.0) {
+        return Err(TonlibError::InvalidBlockProof);
+    }
+
+    ton_block::Block::construct_from_cell(merkle_proof.proof.virtualize(1)).map_err(|_| TonlibError::InvalidBlockProof)
+}
+
+#[cfg(test)]
+mod tests {
+    use ton_block::{
+        BlkPrevInfo, Block, BlockExtra, BlockInfo, ExtBlkRef, MerkleProof, MerkleUpdate, Serializable, ShardAccountBlocks, ShardIdent,
+        ShardStateUnsplit, Transaction,
+    };
+
+    use super::*;
+
+    fn block_id(workchain: i32, seqno: i32, root: &Cell) -> BlockIdExt {
+        BlockIdExt {
+            workchain,
+            shard: ton_block::SHARD_FULL as i64,
+            seqno,
+            root_hash: ton::int256(root.repr_hash().into()),
+            file_hash: ton::int256([0; 32]),
+        }
+    }
+
+    /// Returns id of the block and its merkle proof, which includes all cells
+    fn prove_block(workchain: i32, seqno: i32, block: &Block) -> (BlockIdExt, Vec<u8>) {
+        let root = block.serialize().unwrap();
+        let proof = MerkleProof::create(&root, |_| true).unwrap();
+        (block_id(workchain, seqno, &root), proof.write_to_bytes().unwrap())
+    }
+
+    fn block_with_prev(prev: &BlockIdExt) -> Block {
+        let prev = ExtBlkRef {
+            end_lt: 0,
+            seq_no: prev.seqno as u32,
+            root_hash: prev.root_hash.0.into(),
+            file_hash: prev.file_hash.0.into(),
+        };
+
+        let mut info = BlockInfo::default();
+        info.set_prev_stuff(false, &BlkPrevInfo::Block { prev }).unwrap();
+
+        let mut block = Block::default();
+        block.write_info(&info).unwrap();
+        block
+    }
+
+    fn make_links(links: Vec<(BlockIdExt, Vec<u8>)>) -> Vec<ton::lite_server::shardblocklink::ShardBlockLink> {
+        links
+            .into_iter()
+            .map(|(id, proof)| ton::lite_server::shardblocklink::ShardBlockLink {
+                id,
+                proof: ton::bytes(proof),
+            })
+            .collect()
+    }
+
+    /// Returns bundle of the transaction in the masterchain block, which is committed by itself
+    fn make_bundle() -> TransactionProof {
+        let transaction = Transaction::default();
+        let transaction_cell = transaction.serialize().unwrap();
+
+        let mut account_blocks = ShardAccountBlocks::default();
+        account_blocks.add_serialized_transaction(&transaction, &transaction_cell).unwrap();
+        let mut extra = BlockExtra::default();
+        extra.write_account_blocks(&account_blocks).unwrap();
+        let mut block = Block::default();
+        block.write_extra(&extra).unwrap();
+
+        let (block_id, transaction_proof) = prove_block(-1, 1, &block);
+        TransactionProof {
+            block_id: block_id.clone(),
+            transaction: ton_types::serialize_toc(&transaction_cell).unwrap(),
+            transaction_proof,
+            shard_block_proof: ShardBlockProof {
+                masterchain_id: block_id,
+                links: Vec::new(),
+            },
+        }
+    }
+
+    #[test]
+    fn block_proof_root_hash() {
+        let (id, proof) = prove_block(0, 1, &Block::default());
+        assert!(read_block_proof(&id, &proof).is_ok());
+
+        let mut other_id = id.clone();
+        other_id.root_hash = ton::int256([1; 32]);
+        assert!(read_block_proof(&other_id, &proof).is_err());
+
+        assert!(read_block_proof(&id, &[1, 2, 3]).is_err());
+    }
+
+    #[test]
+    fn shard_block_links() {
+        let (target_id, _) = prove_block(0, 1, &Block::default());
+        let (link_id, link_proof) = prove_block(0, 2, &block_with_prev(&target_id));
+
+        let proof = ton::lite_server::shardblockproof::ShardBlockProof {
+            masterchain_id: link_id.clone(),
+            links: make_links(vec![(link_id, link_proof)]),
+        };
+        let checked = check_shard_block_proof(&target_id, proof.clone(), TrustMode::VerifyProofs).unwrap();
+        assert_eq!(checked.links.len(), 1);
+
+        let mut other_id = target_id;
+        other_id.seqno += 1;
+        assert!(check_shard_block_proof(&other_id, proof, TrustMode::VerifyProofs).is_err());
+    }
+
+    #[test]
+    fn shard_block_without_links() {
+        let (id, _) = prove_block(-1, 1, &Block::default());
+        let proof = ton::lite_server::shardblockproof::ShardBlockProof {
+            masterchain_id: id.clone(),
+            links: Vec::new(),
+        };
+        assert!(check_shard_block_proof(&id, proof.clone(), TrustMode::VerifyProofs).is_ok());
+
+        let mut other_id = id;
+        other_id.seqno += 1;
+        assert!(check_shard_block_proof(&other_id, proof, TrustMode::VerifyProofs).is_err());
+    }
+
+    #[test]
+    fn trust_server_skips_links() {
+        let (id, _) = prove_block(0, 1, &Block::default());
+        let proof = ton::lite_server::shardblockproof::ShardBlockProof {
+            masterchain_id: id.clone(),
+            links: make_links(vec![(id.clone(), vec![1, 2, 3])]),
+        };
+        assert!(check_shard_block_proof(&id, proof.clone(), TrustMode::TrustServer).is_ok());
+        assert!(check_shard_block_proof(&id, proof.clone(), TrustMode::VerifyProofs).is_err());
+        assert!(check_shard_block_proof(&id, proof, TrustMode::Trustless).is_err());
+    }
+
+    #[test]
+    fn account_state_proof() {
+        let old_state = ShardStateUnsplit::default().serialize().unwrap();
+        let new_state = ShardStateUnsplit::with_ident(ShardIdent::masterchain()).serialize().unwrap();
+
+        let mut block = Block::default();
+        block
+            .write_state_update(&MerkleUpdate::create(&old_state, &new_state).unwrap())
+            .unwrap();
+        let root = block.serialize().unwrap();
+        let id = block_id(0, 1, &root);
+        let block_proof = MerkleProof::create(&root, |_| true).unwrap().serialize().unwrap();
+
+        let state_proof = |state: &Cell| MerkleProof::create(state, |_| true).unwrap().serialize().unwrap();
+
+        assert!(check_account_state_proof(&id, &[block_proof.clone(), state_proof(&new_state)]).is_ok());
+        assert!(matches!(
+            check_account_state_proof(&id, &[block_proof.clone(), state_proof(&old_state)]),
+            Err(TonlibError::InvalidAccountStateProof)
+        ));
+        assert!(check_account_state_proof(&id, &[block_proof]).is_err());
+    }
+
+    #[test]
+    fn masterchain_shard_proof() {
+        let (id, _) = prove_block(-1, 1, &Block::default());
+        assert!(check_shard_proof(&id, &id, &[]).is_ok());
+
+        let mut other_id = id.clone();
+        other_id.seqno += 1;
+        assert!(check_shard_proof(&id, &other_id, &[]).is_err());
+    }
+
+    #[test]
+    fn transaction_proof_json() {
+        let bundle = make_bundle();
+        let (hash, _) = bundle.verify().unwrap();
+
+        let parsed = TransactionProof::from_json(&bundle.to_json().unwrap()).unwrap();
+        assert_eq!(parsed.block_id, bundle.block_id);
+        assert_eq!(parsed.transaction, bundle.transaction);
+        assert_eq!(parsed.transaction_proof, bundle.transaction_proof);
+        assert_eq!(parsed.shard_block_proof.masterchain_id, bundle.shard_block_proof.masterchain_id);
+        assert_eq!(parsed.verify().unwrap().0, hash);
+
+        assert!(TransactionProof::from_json("").is_err());
+        assert!(TransactionProof::from_json("{}").is_err());
+    }
+
+    #[test]
+    fn block_id_string() {
+        let (id, _) = prove_block(0, 1, &Block::default());
+        let string = block_id_to_string(&id);
+        assert_eq!(block_id_from_str(&string).unwrap(), id);
+
+        assert!(block_id_from_str("").is_err());
+        assert!(block_id_from_str(&format!("{}:00", string)).is_err());
+        assert!(block_id_from_str(string.rsplitn(2, ':').last().unwrap()).is_err());
+        assert!(block_id_from_str(&string.replacen("0:", "x:", 1)).is_err());
+    }
+
+    #[test]
+    fn tampered_proof_bundle() {
+        let bundle = make_bundle();
+        let trusted_mc_block = bundle.shard_block_proof.masterchain_id.clone();
+        assert!(verify_proof_bundle(&bundle, &trusted_mc_block).is_ok());
+
+        let mut other_mc_block = trusted_mc_block.clone();
+        other_mc_block.seqno += 1;
+        assert!(verify_proof_bundle(&bundle, &other_mc_block).is_err());
+
+        let mut tampered = bundle.clone();
+        let mut transaction = Transaction::default();
+        transaction.set_logical_time(1);
+        tampered.transaction = ton_types::serialize_toc(&transaction.serialize().unwrap()).unwrap();
+        assert!(verify_proof_bundle(&tampered, &trusted_mc_block).is_err());
+
+        let mut tampered = bundle.clone();
+        tampered.transaction.pop();
+        assert!(verify_proof_bundle(&tampered, &trusted_mc_block).is_err());
+
+        let mut tampered = bundle.clone();
+        tampered.transaction_proof = prove_block(-1, 1, &Block::default()).1;
+        assert!(verify_proof_bundle(&tampered, &trusted_mc_block).is_err());
+
+        let mut tampered = bundle;
+        tampered.block_id.root_hash = ton::int256([1; 32]);
+        tampered.shard_block_proof.masterchain_id = tampered.block_id.clone();
+        assert!(verify_proof_bundle(&tampered, &tampered.block_id.clone()).is_err());
+    }
+}
@@ -0,0 +1,253 @@
+use std::collections::HashMap;
+use std::net::SocketAddrV4;
+use std::time::{Duration, Instant};
+
+use anyhow::Result;
+
+use crate::errors::*;
+
+#[derive(Debug, Copy, Clone, Default)]
+pub struct RateLimitConfig {
+    /// Limit for all queries of the client
+    pub global: Option<RateLimit>,
+    /// Limit for queries to each server
+    pub per_server: Option<RateLimit>,
+    /// Max number of queries in flight for the client.
+    ///
+    /// Connections are shared by concurrent queries, so `Config::max_connection_count`
+    /// limits only the number of sessions, not the number of queries
+    pub max_concurrent_queries: Option<usize>,
+    pub mode: RateLimitMode,
+}
+
+impl RateLimitConfig {
+    /// Checks that the limits allow at least one query
+    pub fn validate(&self) -> Result<()> {
+        for limit in self.global.iter().chain(self.per_server.iter()) {
+            limit.validate()?;
+        }
+        if self.max_concurrent_queries == Some(0) {
+            return Err(anyhow::anyhow!("Max number of concurrent queries must be positive"));
+        }
+        Ok(())
+    }
+}
+
+/// Token bucket parameters
+#[derive(Debug, Copy, Clone)]
+pub struct RateLimit {
+    /// Queries per second. Must be positive
+    pub rate: f64,
+    /// Max number of queries which can be sent at once. Must be at least 1
+    pub burst: u32,
+}
+
+impl RateLimit {
+    pub fn validate(&self) -> Result<()> {
+        if !(self.rate.is_finite() && self.rate > 0.0) {
+            return Err(anyhow::anyhow!("Invalid rate limit: {}", self.rate));
+        }
+        if self.burst == 0 {
+            return Err(anyhow::anyhow!("Rate limit burst must be at least 1"));
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum RateLimitMode {
+    /// Wait until the query is allowed
+    Wait,
+    /// Fail with `RateLimited` error
+    Fail,
+}
+
+impl Default for RateLimitMode {
+    fn default() -> Self {
+        Self::Wait
+    }
+}
+
+pub struct RateLimiter {
+    config: RateLimitConfig,
+    global: parking_lot::Mutex<TokenBucket>,
+    per_server: parking_lot::Mutex<HashMap<SocketAddrV4, TokenBucket>>,
+    in_flight: Option<tokio::sync::Semaphore>,
+}
+
+impl RateLimiter {
+    pub fn new(config: RateLimitConfig) -> Result<Self> {
+        config.validate()?;
+        Ok(Self {
+            config,
+            global: parking_lot::Mutex::new(TokenBucket::new(config.global)),
+            per_server: Default::default(),
+            in_flight: config.max_concurrent_queries.map(tokio::sync::Semaphore::new),
+        })
+    }
+
+    /// Waits for the permission to send query to the server or fails depending on the mode.
+    ///
+    /// The query is counted as in flight until the returned permit is dropped
+    pub async fn acquire(&self, address: SocketAddrV4) -> TonlibResult<QueryPermit<'_>> {
+        let permit = match &self.in_flight {
+            Some(in_flight) => Some(match self.config.mode {
+                RateLimitMode::Wait => in_flight.acquire().await.map_err(|_| TonlibError::RateLimited)?,
+                RateLimitMode::Fail => in_flight.try_acquire().map_err(|_| TonlibError::RateLimited)?,
+            }),
+            None => None,
+        };
+
+        loop {
+            let wait_time = match self.try_acquire(address) {
+                Ok(()) => return Ok(QueryPermit { _permit: permit }),
+                Err(wait_time) => wait_time,
+            };
+
+            match self.config.mode {
+                RateLimitMode::Wait => tokio::time::sleep(wait_time).await,
+                RateLimitMode::Fail => return Err(TonlibError::RateLimited),
+            }
+        }
+    }
+
+    /// Consumes tokens from both buckets or returns time to wait
+    fn try_acquire(&self, address: SocketAddrV4) -> Result<(), Duration> {
+        let now = Instant::now();
+
+        let mut global = self.global.lock();
+        let mut per_server = self.per_server.lock();
+        let server = per_server
+            .entry(address)
+            .or_insert_with(|| TokenBucket::new(self.config.per_server));
+
+        let wait_time = global.wait_time(now).max(server.wait_time(now));
+        if wait_time > Duration::from_secs(0) {
+            return Err(wait_time);
+        }
+
+        global.consume();
+        server.consume();
+        Ok(())
+    }
+}
+
+/// Holds the slot of the query in flight
+pub struct QueryPermit<'a> {
+    _permit: Option<tokio::sync::SemaphorePermit<'a>>,
+}
+
+struct TokenBucket {
+    limit: Option<RateLimit>,
+    tokens: f64,
+    updated_at: Instant,
+}
+
+impl TokenBucket {
+    fn new(limit: Option<RateLimit>) -> Self {
+        Self {
+            limit,
+            tokens: limit.map(|limit| limit.burst as f64).unwrap_or_default(),
+            updated_at: Instant::now(),
+        }
+    }
+
+    /// Refills the bucket and returns time until the next token is available
+    fn wait_time(&mut self, now: Instant) -> Duration {
+        let limit = match self.limit {
+            Some(limit) => limit,
+            None => return Duration::from_secs(0),
+        };
+
+        let elapsed = now.saturating_duration_since(self.updated_at).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * limit.rate).min(limit.burst as f64);
+        self.updated_at = now;
+
+        if self.tokens >= 1.0 {
+            Duration::from_secs(0)
+        } else {
+            Duration::from_secs_f64((1.0 - self.tokens) / limit.rate)
+        }
+    }
+
+    fn consume(&mut self) {
+        if self.limit.is_some() {
+            self.tokens -= 1.0;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn burst_is_limited() {
+        let limiter = RateLimiter::new(RateLimitConfig {
+            global: Some(RateLimit { rate: 1.0, burst: 2 }),
+            per_server: None,
+            max_concurrent_queries: None,
+            mode: RateLimitMode::Fail,
+        })
+        .unwrap();
+        let address = "127.0.0.1:3031".parse().unwrap();
+
+        assert!(limiter.try_acquire(address).is_ok());
+        assert!(limiter.try_acquire(address).is_ok());
+        assert!(limiter.try_acquire(address).is_err());
+    }
+
+    #[test]
+    fn per_server_limit() {
+        let limiter = RateLimiter::new(RateLimitConfig {
+            global: None,
+            per_server: Some(RateLimit { rate: 1.0, burst: 1 }),
+            max_concurrent_queries: None,
+            mode: RateLimitMode::Fail,
+        })
+        .unwrap();
+        let first = "127.0.0.1:3031".parse().unwrap();
+        let second = "127.0.0.1:3032".parse().unwrap();
+
+        assert!(limiter.try_acquire(first).is_ok());
+        assert!(limiter.try_acquire(first).is_err());
+        assert!(limiter.try_acquire(second).is_ok());
+    }
+
+    #[tokio::test]
+    async fn queries_in_flight() {
+        let limiter = RateLimiter::new(RateLimitConfig {
+            global: None,
+            per_server: None,
+            max_concurrent_queries: Some(1),
+            mode: RateLimitMode::Fail,
+        })
+        .unwrap();
+        let address = "127.0.0.1:3031".parse().unwrap();
+
+        let permit = limiter.acquire(address).await.unwrap();
+        assert!(matches!(limiter.acquire(address).await, Err(TonlibError::RateLimited)));
+
+        drop(permit);
+        assert!(limiter.acquire(address).await.is_ok());
+    }
+
+    #[test]
+    fn invalid_limits() {
+        let config = |rate, burst| RateLimitConfig {
+            per_server: Some(RateLimit { rate, burst }),
+            ..Default::default()
+        };
+        assert!(RateLimiter::new(config(1.0, 1)).is_ok());
+        assert!(RateLimiter::new(config(0.0, 1)).is_err());
+        assert!(RateLimiter::new(config(-1.0, 1)).is_err());
+        assert!(RateLimiter::new(config(f64::NAN, 1)).is_err());
+        assert!(RateLimiter::new(config(1.0, 0)).is_err());
+
+        assert!(RateLimiter::new(RateLimitConfig {
+            max_concurrent_queries: Some(0),
+            ..Default::default()
+        })
+        .is_err());
+    }
+}
@@ -0,0 +1,98 @@
+use std::collections::HashMap;
+use std::sync::Arc;
+
+use anyhow::Result;
+
+use crate::{Config, TonlibClient};
+
+/// Network served by the client
+#[derive(Debug, Clone, Eq, PartialEq, Hash)]
+pub enum Network {
+    Mainnet,
+    Testnet,
+    /// Private network with the custom name
+    Private(String),
+}
+
+impl std::fmt::Display for Network {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Mainnet => f.write_str("mainnet"),
+            Self::Testnet => f.write_str("testnet"),
+            Self::Private(name) => write!(f, "private:{}", name),
+        }
+    }
+}
+
+/// Clients for the several networks in one process.
+///
+/// Clients are created on the first request and share the tokio runtime
+pub struct ClientRegistry {
+    networks: HashMap<Network, RegistryEntry>,
+}
+
+struct RegistryEntry {
+    config: Config,
+    client: tokio::sync::OnceCell<Arc<TonlibClient>>,
+}
+
+impl ClientRegistry {
+    pub fn new<I>(networks: I) -> Self
+    where
+        I: IntoIterator<Item = (Network, Config)>,
+    {
+        Self {
+            networks: networks
+                .into_iter()
+                .map(|(network, config)| {
+                    let entry = RegistryEntry {
+                        config,
+                        client: Default::default(),
+                    };
+                    (network, entry)
+                })
+                .collect(),
+        }
+    }
+
+    /// Configured networks
+    pub fn networks(&self) -> impl Iterator<Item = &Network> {
+        self.networks.keys()
+    }
+
+    /// Returns client for the network, creating it if needed.
+    ///
+    /// Failed creation is retried on the next call
+    pub async fn get(&self, network: &Network) -> Result<Arc<TonlibClient>> {
+        let entry = self
+            .networks
+            .get(network)
+            .ok_or_else(|| anyhow::anyhow!("Unknown network: {}", network))?;
+
+        let client = entry
+            .client
+            .get_or_try_init(|| async { TonlibClient::new(&entry.config).await.map(Arc::new) })
+            .await?;
+        Ok(client.clone())
+    }
+
+    /// Returns client for the network if it was already created
+    pub fn get_initialized(&self, network: &Network) -> Option<Arc<TonlibClient>> {
+        self.networks.get(network)?.client.get().cloned()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn unknown_network() {
+        let registry = ClientRegistry::new(vec![(Network::Mainnet, Config::default())]);
+        assert_eq!(registry.networks().collect::<Vec<_>>(), vec![&Network::Mainnet]);
+
+        let local = Network::Private("local".to_owned());
+        assert!(registry.get(&local).await.is_err());
+        assert!(registry.get_initialized(&Network::Mainnet).is_none());
+    }
+}
@@ -0,0 +1,426 @@
+//! Transports which record raw queries to fixtures and serve them back,
+//! so that the client can be tested without network access
+
+use std::collections::{HashMap, VecDeque};
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+use anyhow::Result;
+use async_trait::async_trait;
+use serde::{Deserialize, Serialize};
+use tiny_adnl::{AdnlTcpClient, AdnlTcpClientConfig};
+use ton_api::{ton, BoxedSerialize};
+
+use crate::transport::AdnlTransport;
+
+/// Recorded request/response pairs in the order of the requests
+#[derive(Debug, Default)]
+pub struct Fixtures {
+    entries: parking_lot::Mutex<Vec<Fixture>>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct Fixture {
+    /// Base64 encoded boxed TL request
+    request: String,
+    /// Base64 encoded boxed TL response
+    response: String,
+}
+
+impl Fixtures {
+    /// Reads fixtures from the JSON file
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Self> {
+        let file = std::fs::File::open(path)?;
+        let entries = serde_json::from_reader(std::io::BufReader::new(file))?;
+        Ok(Self {
+            entries: parking_lot::Mutex::new(entries),
+        })
+    }
+
+    /// Writes fixtures to the JSON file
+    pub fn save<P: AsRef<Path>>(&self, path: P) -> Result<()> {
+        let file = std::fs::File::create(path)?;
+        serde_json::to_writer_pretty(file, &*self.entries.lock())?;
+        Ok(())
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.lock().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.lock().is_empty()
+    }
+
+    pub fn record(&self, request: &ton::TLObject, response: &ton::TLObject) -> Result<()> {
+        let fixture = Fixture {
+            request: base64::encode(serialize(request)?),
+            response: base64::encode(serialize(response)?),
+        };
+        self.entries.lock().push(fixture);
+        Ok(())
+    }
+
+    /// Recorded requests in the order they were sent
+    #[cfg(feature = "bench")]
+    pub fn requests(&self) -> Result<Vec<ton::TLObject>> {
+        self.entries
+            .lock()
+            .iter()
+            .map(|fixture| {
+                let request = base64::decode(&fixture.request)?;
+                ton_api::Deserializer::new(&mut std::io::Cursor::new(request))
+                    .read_boxed::<ton::TLObject>()
+                    .map_err(anyhow::Error::msg)
+            })
+            .collect()
+    }
+
+    /// Groups responses by requests, preserving their order
+    fn responses(&self) -> Result<Responses> {
+        let mut responses = HashMap::<_, VecDeque<_>>::new();
+        for fixture in self.entries.lock().iter() {
+            responses
+                .entry(base64::decode(&fixture.request)?)
+                .or_default()
+                .push_back(base64::decode(&fixture.response)?);
+        }
+        Ok(responses)
+    }
+}
+
+/// Serialized responses grouped by serialized requests
+type Responses = HashMap<Vec<u8>, VecDeque<Vec<u8>>>;
+
+/// Context of `RecordingTransport`
+pub struct RecordingContext<A: AdnlTransport = AdnlTcpClient> {
+    pub inner: A::Context,
+    pub fixtures: Arc<Fixtures>,
+}
+
+/// Transport which records all successful queries of the inner transport
+pub struct RecordingTransport<A = AdnlTcpClient> {
+    inner: Arc<A>,
+    fixtures: Arc<Fixtures>,
+}
+
+#[async_trait]
+impl<A> AdnlTransport for RecordingTransport<A>
+where
+    A: AdnlTransport,
+{
+    type Context = RecordingContext<A>;
+
+    async fn connect(config: AdnlTcpClientConfig, context: &Self::Context) -> Result<Arc<Self>> {
+        Ok(Arc::new(Self {
+            inner: A::connect(config, &context.inner).await?,
+            fixtures: context.fixtures.clone(),
+        }))
+    }
+
+    async fn query(&self, query: &ton::TLObject) -> Result<ton::TLObject> {
+        self.query_sized(query).await.map(|(response, _)| response)
+    }
+
+    async fn query_sized(&self, query: &ton::TLObject) -> Result<(ton::TLObject, usize)> {
+        let (response, size) = self.inner.query_sized(query).await?;
+        self.fixtures.record(query, &response)?;
+        Ok((response, size))
+    }
+
+    async fn ping(&self, timeout: Duration) -> Result<()> {
+        self.inner.ping(timeout).await
+    }
+
+    fn is_broken(&self) -> bool {
+        self.inner.is_broken()
+    }
+
+    fn mark_broken(&self) {
+        self.inner.mark_broken()
+    }
+}
+
+/// Transport which serves recorded responses without network access.
+///
+/// Responses to the same request are served in the recorded order, the last one is repeated.
+/// Unknown requests fail with a transport error
+pub struct ReplayTransport {
+    responses: Arc<parking_lot::Mutex<Responses>>,
+    broken: AtomicBool,
+}
+
+/// Context of `ReplayTransport`. All sessions share the recorded responses
+pub struct ReplayContext {
+    responses: Arc<parking_lot::Mutex<Responses>>,
+}
+
+impl ReplayContext {
+    pub fn new(fixtures: &Fixtures) -> Result<Self> {
+        Ok(Self {
+            responses: Arc::new(parking_lot::Mutex::new(fixtures.responses()?)),
+        })
+    }
+}
+
+#[async_trait]
+impl AdnlTransport for ReplayTransport {
+    type Context = ReplayContext;
+
+    async fn connect(_: AdnlTcpClientConfig, context: &Self::Context) -> Result<Arc<Self>> {
+        Ok(Arc::new(Self {
+            responses: context.responses.clone(),
+            broken: AtomicBool::new(false),
+        }))
+    }
+
+    async fn query(&self, query: &ton::TLObject) -> Result<ton::TLObject> {
+        self.query_sized(query).await.map(|(response, _)| response)
+    }
+
+    async fn query_sized(&self, query: &ton::TLObject) -> Result<(ton::TLObject, usize)> {
+        let request = serialize(query)?;
+
+        let response = {
+            let mut responses = self.responses.lock();
+            match responses.get_mut(&request) {
+                Some(queue) if queue.len() > 1 => queue.pop_front(),
+                Some(queue) => queue.front().cloned(),
+                None => None,
+            }
+        }
+        .ok_or_else(|| anyhow::anyhow!("No recorded response"))?;
+
+        let size = response.len();
+        let response = ton_api::Deserializer::new(&mut std::io::Cursor::new(response))
+            .read_boxed::<ton::TLObject>()
+            .map_err(anyhow::Error::msg)?;
+        Ok((response, size))
+    }
+
+    async fn ping(&self, _: Duration) -> Result<()> {
+        Ok(())
+    }
+
+    fn is_broken(&self) -> bool {
+        self.broken.load(Ordering::Acquire)
+    }
+
+    fn mark_broken(&self) {
+        self.broken.store(true, Ordering::Release);
+    }
+}
+
+fn serialize(object: &ton::TLObject) -> Result<Vec<u8>> {
+    object.boxed_serialized_bytes().map_err(anyhow::Error::msg)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::str::FromStr;
+
+    use ton_block::MsgAddressInt;
+    use ton_types::UInt256;
+
+    use super::*;
+    use crate::{
+        AccountState, Config, LastBlock, LiteServerConfig, RateLimit, RateLimitConfig, RateLimitMode, RetryPolicy, StackBuilder,
+        TonlibClient, TonlibError, TrustMode,
+    };
+
+    fn make_error(code: i32) -> ton::TLObject {
+        ton::TLObject::new(ton::lite_server::Error::LiteServer_Error(ton::lite_server::error::Error {
+            code,
+            message: String::new(),
+        }))
+    }
+
+    fn read_code(response: ton::TLObject) -> i32 {
+        *response.downcast::<ton::lite_server::Error>().unwrap().code()
+    }
+
+    fn make_query<T: BoxedSerialize>(query: &T) -> ton::TLObject {
+        ton::TLObject::new(ton::rpc::lite_server::Query {
+            data: query.boxed_serialized_bytes().unwrap().into(),
+        })
+    }
+
+    #[tokio::test]
+    async fn replay_recorded() {
+        let request = ton::TLObject::new(ton::rpc::lite_server::GetMasterchainInfo);
+
+        let fixtures = Fixtures::default();
+        fixtures.record(&request, &make_error(1)).unwrap();
+        fixtures.record(&request, &make_error(2)).unwrap();
+
+        let file = tempfile::NamedTempFile::new().unwrap();
+        fixtures.save(file.path()).unwrap();
+        let fixtures = Fixtures::load(file.path()).unwrap();
+        assert_eq!(fixtures.len(), 2);
+
+        let context = ReplayContext::new(&fixtures).unwrap();
+        let transport = ReplayTransport {
+            responses: context.responses.clone(),
+            broken: AtomicBool::new(false),
+        };
+
+        assert_eq!(read_code(transport.query(&request).await.unwrap()), 1);
+        assert_eq!(read_code(transport.query(&request).await.unwrap()), 2);
+        assert_eq!(read_code(transport.query(&request).await.unwrap()), 2);
+
+        let unknown = ton::TLObject::new(ton::rpc::lite_server::GetTime);
+        assert!(transport.query(&unknown).await.is_err());
+    }
+
+    /// Synthetic responses for a single uninit account. Proofs are not valid, so the server is trusted
+    #[tokio::test]
+    async fn replay_account_state() {
+        let fixtures = Fixtures::load(concat!(env!("CARGO_MANIFEST_DIR"), "/fixtures/account_state.json")).unwrap();
+
+        let config = Config {
+            servers: vec![LiteServerConfig {
+                address: "127.0.0.1:3031".parse().unwrap(),
+                key: "uNRRL+6enQjuiZ/s6Z+vO7yxUUR7uxdfzIy+RxkECrc=".to_owned(),
+            }],
+            trust_mode: TrustMode::TrustServer,
+            ..Default::default()
+        };
+        let last_block = Arc::new(LastBlock::new(&config.last_block_threshold));
+        let client = TonlibClient::<ReplayTransport>::with_transport(&config, last_block, ReplayContext::new(&fixtures).unwrap())
+            .await
+            .unwrap();
+
+        let account = MsgAddressInt::from_str("0:7777777777777777777777777777777777777777777777777777777777777777").unwrap();
+        let (stats, state) = client.get_account_state(&account).await.unwrap();
+        assert_eq!(stats.last_trans_lt, 12_000_001);
+        assert_eq!(stats.last_trans_hash, UInt256::from([0x88; 32]));
+        assert_eq!(stats.gen_utime, 1_600_000_000);
+        assert_eq!(stats.gen_lt, 12_000_100);
+
+        match state {
+            AccountState::Uninit { balance } => assert_eq!(balance.grams.0, 1_000_000_000),
+            state => panic!("Unexpected account state: {:?}", state),
+        }
+
+        // Requests which were not recorded fail
+        let unknown = MsgAddressInt::from_str("0:1111111111111111111111111111111111111111111111111111111111111111").unwrap();
+        assert!(client.get_account_state(&unknown).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn rate_limited_error() {
+        let fixtures = Fixtures::load(concat!(env!("CARGO_MANIFEST_DIR"), "/fixtures/account_state.json")).unwrap();
+
+        // The last block request takes the only token, so the account state request fails
+        let config = Config {
+            servers: vec![LiteServerConfig {
+                address: "127.0.0.1:3031".parse().unwrap(),
+                key: "uNRRL+6enQjuiZ/s6Z+vO7yxUUR7uxdfzIy+RxkECrc=".to_owned(),
+            }],
+            trust_mode: TrustMode::TrustServer,
+            rate_limit: RateLimitConfig {
+                global: Some(RateLimit { rate: 0.001, burst: 1 }),
+                mode: RateLimitMode::Fail,
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+        let last_block = Arc::new(LastBlock::new(&config.last_block_threshold));
+        let client = TonlibClient::<ReplayTransport>::with_transport(&config, last_block, ReplayContext::new(&fixtures).unwrap())
+            .await
+            .unwrap();
+
+        let account = MsgAddressInt::from_str("0:7777777777777777777777777777777777777777777777777777777777777777").unwrap();
+        let error = client.get_account_state(&account).await.unwrap_err();
+        assert!(matches!(error.downcast_ref::<TonlibError>(), Some(TonlibError::RateLimited)));
+    }
+
+    #[tokio::test]
+    async fn unverified_get_method() {
+        let fixtures = Fixtures::default();
+
+        let config = Config {
+            servers: vec![LiteServerConfig {
+                address: "127.0.0.1:3031".parse().unwrap(),
+                key: "uNRRL+6enQjuiZ/s6Z+vO7yxUUR7uxdfzIy+RxkECrc=".to_owned(),
+            }],
+            trust_mode: TrustMode::VerifyProofs,
+            ..Default::default()
+        };
+        let last_block = Arc::new(LastBlock::new(&config.last_block_threshold));
+        let client = TonlibClient::<ReplayTransport>::with_transport(&config, last_block, ReplayContext::new(&fixtures).unwrap())
+            .await
+            .unwrap();
+
+        let account = MsgAddressInt::from_str("0:7777777777777777777777777777777777777777777777777777777777777777").unwrap();
+        let error = client.run_get_method(&account, "seqno", &StackBuilder::new()).await.unwrap_err();
+        assert!(matches!(error.downcast_ref::<TonlibError>(), Some(TonlibError::ProofsRequired)));
+    }
+
+    #[tokio::test]
+    async fn partial_account_states_fallback() {
+        // Masterchain blocks 1000 and 1001
+        let fixtures = Fixtures::load(concat!(env!("CARGO_MANIFEST_DIR"), "/fixtures/last_block.json")).unwrap();
+        // State of the account at the block 1000
+        let account_state = Fixtures::load(concat!(env!("CARGO_MANIFEST_DIR"), "/fixtures/account_state.json")).unwrap();
+        fixtures.entries.lock().push(account_state.entries.lock()[1].clone());
+
+        let last_block_id = ton::ton_node::blockidext::BlockIdExt {
+            workchain: ton_block::MASTERCHAIN_ID,
+            shard: ton_block::SHARD_FULL as i64,
+            seqno: 1001,
+            root_hash: ton::int256([0x12; 32]),
+            file_hash: ton::int256([0x23; 32]),
+        };
+        // The first account is not ready at the last block, the second one fails
+        for (address, code) in [(0x77, crate::connection::ERR_NOT_READY), (0x11, 1)].iter() {
+            let query = ton::rpc::lite_server::GetAccountState {
+                id: last_block_id.clone(),
+                account: ton::lite_server::accountid::AccountId {
+                    workchain: 0,
+                    id: ton::int256([*address; 32]),
+                },
+            };
+            fixtures.record(&make_query(&query), &make_error(*code)).unwrap();
+        }
+
+        let config = Config {
+            servers: vec![LiteServerConfig {
+                address: "127.0.0.1:3031".parse().unwrap(),
+                key: "uNRRL+6enQjuiZ/s6Z+vO7yxUUR7uxdfzIy+RxkECrc=".to_owned(),
+            }],
+            trust_mode: TrustMode::TrustServer,
+            retry_policy: RetryPolicy {
+                retry_not_ready: false,
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+        let last_block = Arc::new(LastBlock::new(&Duration::from_secs(0)));
+        let client = TonlibClient::<ReplayTransport>::with_transport(&config, last_block, ReplayContext::new(&fixtures).unwrap())
+            .await
+            .unwrap();
+
+        // Cache the block 1000, so that the next call uses the block 1001
+        assert_eq!(client.last_block.get_last_block(&client.pool).await.unwrap().seqno, 1000);
+
+        let accounts = [
+            MsgAddressInt::from_str("0:7777777777777777777777777777777777777777777777777777777777777777").unwrap(),
+            MsgAddressInt::from_str("0:1111111111111111111111111111111111111111111111111111111111111111").unwrap(),
+        ];
+        let result = client.get_account_states_partial(&accounts).await.unwrap();
+        assert_eq!(client.last_block.latest_seqno(), Some(1001));
+
+        assert_eq!(result.items.len(), 1);
+        let (index, (stats, _)) = &result.items[0];
+        assert_eq!(*index, 0);
+        assert_eq!(stats.last_trans_lt, 12_000_001);
+
+        assert_eq!(result.errors.len(), 1);
+        let (index, error) = &result.errors[0];
+        assert_eq!(*index, 1);
+        assert!(matches!(error.downcast_ref::<TonlibError>(), Some(TonlibError::LiteServer(_))));
+    }
+}
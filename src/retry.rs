@@ -0,0 +1,79 @@
+use std::time::Duration;
+
+use rand::Rng;
+
+/// Controls how failed queries are retried
+#[derive(Debug, Copy, Clone)]
+pub struct RetryPolicy {
+    /// Max number of retries after the first attempt
+    pub max_retries: u32,
+    /// Delay before the first retry
+    pub initial_backoff: Duration,
+    /// Upper bound of the delay
+    pub max_backoff: Duration,
+    /// Delay multiplier for each next retry
+    pub multiplier: f64,
+    /// Relative random deviation of the delay, from 0.0 to 1.0
+    pub jitter: f64,
+    /// Whether to retry queries which the server is not ready to answer
+    pub retry_not_ready: bool,
+    /// Whether to retry queries using a new connection on transport errors, including socket timeouts
+    pub retry_connection_errors: bool,
+}
+
+impl RetryPolicy {
+    /// Returns delay before the specified retry, starting from 0
+    pub fn backoff(&self, retry: u32) -> Duration {
+        let backoff = self.initial_backoff.as_secs_f64() * self.multiplier.powi(retry as i32);
+        let backoff = backoff.min(self.max_backoff.as_secs_f64());
+
+        let jitter = if self.jitter > 0.0 {
+            rand::thread_rng().gen_range(-self.jitter..=self.jitter)
+        } else {
+            0.0
+        };
+
+        Duration::from_secs_f64((backoff * (1.0 + jitter)).max(0.0))
+    }
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        Self {
+            max_retries: 3,
+            initial_backoff: Duration::from_millis(100),
+            max_backoff: Duration::from_secs(1),
+            multiplier: 2.0,
+            jitter: 0.2,
+            retry_not_ready: true,
+            retry_connection_errors: true,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn exponential_backoff() {
+        let policy = RetryPolicy {
+            jitter: 0.0,
+            ..Default::default()
+        };
+
+        assert_eq!(policy.backoff(0), Duration::from_millis(100));
+        assert_eq!(policy.backoff(1), Duration::from_millis(200));
+        assert_eq!(policy.backoff(2), Duration::from_millis(400));
+        assert_eq!(policy.backoff(10), Duration::from_secs(1));
+    }
+
+    #[test]
+    fn backoff_with_jitter() {
+        let policy = RetryPolicy::default();
+        for _ in 0..100 {
+            let backoff = policy.backoff(0);
+            assert!(backoff >= Duration::from_millis(80) && backoff <= Duration::from_millis(120));
+        }
+    }
+}
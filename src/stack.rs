@@ -0,0 +1,332 @@
+use std::collections::VecDeque;
+use std::convert::TryFrom;
+
+use num_bigint::BigInt;
+use ton_block::{Deserializable, MsgAddressInt, Serializable};
+use ton_types::{BuilderData, Cell, IBitstring, SliceData};
+
+use crate::errors::*;
+
+/// TVM stack entry
+#[derive(Debug, Clone, PartialEq)]
+pub enum StackValue {
+    Null,
+    Int(BigInt),
+    Nan,
+    Cell(Cell),
+    Slice(SliceData),
+    Builder(Cell),
+    Tuple(Vec<StackValue>),
+}
+
+/// Builds get-method arguments. Values are pushed from the bottom to the top of the stack
+#[derive(Debug, Clone, Default)]
+pub struct StackBuilder {
+    values: Vec<StackValue>,
+}
+
+impl StackBuilder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn int<T: Into<BigInt>>(mut self, value: T) -> Self {
+        self.values.push(StackValue::Int(value.into()));
+        self
+    }
+
+    pub fn cell(mut self, cell: Cell) -> Self {
+        self.values.push(StackValue::Cell(cell));
+        self
+    }
+
+    pub fn slice(mut self, slice: SliceData) -> Self {
+        self.values.push(StackValue::Slice(slice));
+        self
+    }
+
+    /// Pushes address as a slice
+    pub fn address(mut self, address: &MsgAddressInt) -> TonlibResult<Self> {
+        let cell = address.serialize().map_err(|_| TonlibError::FailedToSerialize)?;
+        self.values.push(StackValue::Slice(cell.into()));
+        Ok(self)
+    }
+
+    pub fn value(mut self, value: StackValue) -> Self {
+        self.values.push(value);
+        self
+    }
+
+    /// Serializes stack as `VmStack`
+    pub fn build(&self) -> TonlibResult<Cell> {
+        let mut list = BuilderData::new().into_cell().map_err(|_| TonlibError::FailedToSerialize)?;
+        for value in &self.values {
+            let mut cons = BuilderData::new();
+            cons.append_reference_cell(list);
+            write_value(&mut cons, value).map_err(|_| TonlibError::FailedToSerialize)?;
+            list = cons.into_cell().map_err(|_| TonlibError::FailedToSerialize)?;
+        }
+
+        let mut stack = BuilderData::new();
+        let result = (|| {
+            stack.append_bits(self.values.len(), 24)?;
+            stack.append_builder(&BuilderData::from(&list))?;
+            stack.into_cell()
+        })();
+        result.map_err(|_| TonlibError::FailedToSerialize)
+    }
+}
+
+/// Reads get-method results from the top of the stack
+#[derive(Debug, Clone)]
+pub struct StackReader {
+    values: VecDeque<StackValue>,
+}
+
+impl StackReader {
+    /// Parses `VmStack`
+    pub fn new(stack: Cell) -> TonlibResult<Self> {
+        let mut slice = SliceData::from(stack);
+        let depth = slice.get_next_int(24).map_err(|_| TonlibError::InvalidStack)? as usize;
+
+        let mut values = VecDeque::with_capacity(depth);
+        for _ in 0..depth {
+            let rest = slice.checked_drain_reference().map_err(|_| TonlibError::InvalidStack)?;
+            values.push_back(read_value(&mut slice)?);
+            slice = rest.into();
+        }
+
+        Ok(Self { values })
+    }
+
+    /// Number of the remaining values
+    pub fn len(&self) -> usize {
+        self.values.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.values.is_empty()
+    }
+
+    pub fn next_value(&mut self) -> TonlibResult<StackValue> {
+        self.values.pop_front().ok_or(TonlibError::InvalidStack)
+    }
+
+    pub fn next_int(&mut self) -> TonlibResult<BigInt> {
+        match self.next_value()? {
+            StackValue::Int(value) => Ok(value),
+            _ => Err(TonlibError::InvalidStack),
+        }
+    }
+
+    pub fn next_u64(&mut self) -> TonlibResult<u64> {
+        use num_traits::ToPrimitive;
+        self.next_int()?.to_u64().ok_or(TonlibError::InvalidStack)
+    }
+
+    pub fn next_cell(&mut self) -> TonlibResult<Cell> {
+        match self.next_value()? {
+            StackValue::Cell(cell) => Ok(cell),
+            _ => Err(TonlibError::InvalidStack),
+        }
+    }
+
+    pub fn next_slice(&mut self) -> TonlibResult<SliceData> {
+        match self.next_value()? {
+            StackValue::Slice(slice) => Ok(slice),
+            _ => Err(TonlibError::InvalidStack),
+        }
+    }
+
+    pub fn next_address(&mut self) -> TonlibResult<MsgAddressInt> {
+        let mut slice = self.next_slice()?;
+        MsgAddressInt::construct_from(&mut slice).map_err(|_| TonlibError::InvalidAddress)
+    }
+
+    pub fn next_tuple(&mut self) -> TonlibResult<Vec<StackValue>> {
+        match self.next_value()? {
+            StackValue::Tuple(values) => Ok(values),
+            _ => Err(TonlibError::InvalidStack),
+        }
+    }
+}
+
+fn write_value(builder: &mut BuilderData, value: &StackValue) -> ton_types::Result<()> {
+    match value {
+        StackValue::Null => {
+            builder.append_u8(0x00)?;
+        }
+        StackValue::Int(value) => match i64::try_from(value) {
+            Ok(value) => {
+                builder.append_u8(0x01)?;
+                builder.append_i64(value)?;
+            }
+            Err(_) => {
+                // vm_stk_int#0201_ value:int257
+                let bytes = value.to_signed_bytes_be();
+                if bytes.len() > 33 {
+                    ton_types::fail!("Integer overflow");
+                }
+                let mut int257 = [if value.sign() == num_bigint::Sign::Minus { 0xff } else { 0x00 }; 33];
+                int257[33 - bytes.len()..].copy_from_slice(&bytes);
+                if int257[0] != 0x00 && int257[0] != 0xff {
+                    ton_types::fail!("Integer overflow");
+                }
+
+                builder.append_bits(0x0100, 15)?;
+                builder.append_bit_bool(int257[0] != 0)?;
+                builder.append_raw(&int257[1..], 256)?;
+            }
+        },
+        StackValue::Nan => {
+            builder.append_u16(0x02ff)?;
+        }
+        StackValue::Cell(cell) => {
+            builder.append_u8(0x03)?;
+            builder.append_reference_cell(cell.clone());
+        }
+        StackValue::Slice(slice) => {
+            builder.append_u8(0x04)?;
+            builder.append_reference_cell(BuilderData::from_slice(slice).into_cell()?);
+            builder.append_bits(0, 10)?;
+            builder.append_bits(slice.remaining_bits(), 10)?;
+            builder.append_bits(0, 3)?;
+            builder.append_bits(slice.remaining_references(), 3)?;
+        }
+        StackValue::Builder(cell) => {
+            builder.append_u8(0x05)?;
+            builder.append_reference_cell(cell.clone());
+        }
+        StackValue::Tuple(values) => {
+            builder.append_u8(0x07)?;
+            builder.append_bits(values.len(), 16)?;
+            write_tuple(builder, values)?;
+        }
+    }
+    Ok(())
+}
+
+/// Writes `VmTuple n`
+fn write_tuple(builder: &mut BuilderData, values: &[StackValue]) -> ton_types::Result<()> {
+    let (last, head) = match values.split_last() {
+        Some(item) => item,
+        None => return Ok(()),
+    };
+
+    match head.len() {
+        0 => {}
+        1 => {
+            builder.append_reference_cell(value_cell(&head[0])?);
+        }
+        _ => {
+            let mut tuple = BuilderData::new();
+            write_tuple(&mut tuple, head)?;
+            builder.append_reference_cell(tuple.into_cell()?);
+        }
+    }
+    builder.append_reference_cell(value_cell(last)?);
+    Ok(())
+}
+
+fn value_cell(value: &StackValue) -> ton_types::Result<Cell> {
+    let mut builder = BuilderData::new();
+    write_value(&mut builder, value)?;
+    builder.into_cell()
+}
+
+fn read_value(slice: &mut SliceData) -> TonlibResult<StackValue> {
+    let result = (|| {
+        Ok(match slice.get_next_byte()? {
+            0x00 => StackValue::Null,
+            0x01 => StackValue::Int(slice.get_next_i64()?.into()),
+            0x02 => match slice.get_next_byte()? {
+                0xff => StackValue::Nan,
+                tag if tag & 0xfe == 0x00 => {
+                    // Last bit of the second byte is the sign bit of int257
+                    let mut int257 = [if tag & 0x01 != 0 { 0xff } else { 0x00 }; 33];
+                    int257[1..].copy_from_slice(&slice.get_next_bytes(32)?);
+                    StackValue::Int(BigInt::from_signed_bytes_be(&int257))
+                }
+                _ => ton_types::fail!("Unknown stack value"),
+            },
+            0x03 => StackValue::Cell(slice.checked_drain_reference()?),
+            0x04 => {
+                let cell = slice.checked_drain_reference()?;
+                let st_bits = slice.get_next_int(10)? as usize;
+                let end_bits = slice.get_next_int(10)? as usize;
+                let st_ref = slice.get_next_int(3)? as usize;
+                let end_ref = slice.get_next_int(3)? as usize;
+
+                let mut value = SliceData::from(cell);
+                value.shrink_data(st_bits..end_bits);
+                value.shrink_references(st_ref..end_ref);
+                StackValue::Slice(value)
+            }
+            0x05 => StackValue::Builder(slice.checked_drain_reference()?),
+            0x07 => {
+                let len = slice.get_next_int(16)? as usize;
+                StackValue::Tuple(read_tuple(slice, len)?)
+            }
+            _ => ton_types::fail!("Unsupported stack value"),
+        })
+    })();
+    result.map_err(|_: ton_types::Error| TonlibError::InvalidStack)
+}
+
+/// Reads `VmTuple n`
+fn read_tuple(slice: &mut SliceData, len: usize) -> ton_types::Result<Vec<StackValue>> {
+    if len == 0 {
+        return Ok(Vec::new());
+    }
+
+    let mut values = match len - 1 {
+        0 => Vec::new(),
+        1 => vec![read_value_cell(slice.checked_drain_reference()?)?],
+        head_len => read_tuple(&mut slice.checked_drain_reference()?.into(), head_len)?,
+    };
+    values.push(read_value_cell(slice.checked_drain_reference()?)?);
+    Ok(values)
+}
+
+fn read_value_cell(cell: Cell) -> ton_types::Result<StackValue> {
+    read_value(&mut cell.into()).map_err(ton_types::Error::from)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn stack_roundtrip() {
+        let big = BigInt::from(u128::MAX) * 1000;
+
+        let stack = StackBuilder::new()
+            .int(1)
+            .int(-5)
+            .int(big.clone())
+            .value(StackValue::Null)
+            .value(StackValue::Tuple(vec![
+                StackValue::Int(1.into()),
+                StackValue::Int(2.into()),
+                StackValue::Int(3.into()),
+            ]))
+            .build()
+            .unwrap();
+
+        let mut reader = StackReader::new(stack).unwrap();
+        assert_eq!(reader.len(), 5);
+        assert_eq!(reader.next_tuple().unwrap().len(), 3);
+        assert_eq!(reader.next_value().unwrap(), StackValue::Null);
+        assert_eq!(reader.next_int().unwrap(), big);
+        assert_eq!(reader.next_int().unwrap(), BigInt::from(-5));
+        assert_eq!(reader.next_u64().unwrap(), 1);
+        assert!(reader.is_empty());
+    }
+
+    #[test]
+    fn negative_int257() {
+        let value = -(BigInt::from(u128::MAX) * 1000);
+        let stack = StackBuilder::new().int(value.clone()).build().unwrap();
+        assert_eq!(StackReader::new(stack).unwrap().next_int().unwrap(), value);
+    }
+}
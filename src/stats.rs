@@ -0,0 +1,211 @@
+use std::collections::{HashMap, VecDeque};
+use std::time::Duration;
+
+use crate::pool::ServerHealth;
+
+#[derive(Debug, Clone, Default)]
+pub struct Stats {
+    /// Number of the open connections in all pools
+    pub connections: u32,
+    /// Number of the idle connections in all pools
+    pub idle_connections: u32,
+    /// Time spent waiting for a pooled connection
+    pub checkout_latency: LatencySummary,
+    pub handshake_latency: LatencySummary,
+    pub ping_rtt: LatencySummary,
+    pub errors: ErrorCounters,
+    pub servers: Vec<ServerHealth>,
+    /// Traffic by query type, e.g. `GetAccountState`
+    pub traffic: HashMap<&'static str, Traffic>,
+    /// Server time minus local time in seconds
+    pub clock_skew: Option<i64>,
+}
+
+#[derive(Debug, Copy, Clone, Default, Eq, PartialEq)]
+pub struct LatencySummary {
+    pub count: usize,
+    pub p50: Duration,
+    pub p90: Duration,
+    pub p99: Duration,
+    pub max: Duration,
+}
+
+impl LatencySummary {
+    /// Computes percentiles of the measurements. Samples are sorted in place
+    pub fn from_samples(samples: &mut [Duration]) -> Self {
+        if samples.is_empty() {
+            return Self::default();
+        }
+        samples.sort_unstable();
+
+        let percentile = |p: usize| samples[(samples.len() - 1) * p / 100];
+
+        Self {
+            count: samples.len(),
+            p50: percentile(50),
+            p90: percentile(90),
+            p99: percentile(99),
+            max: samples[samples.len() - 1],
+        }
+    }
+}
+
+#[derive(Debug, Copy, Clone, Default, Eq, PartialEq)]
+pub struct ErrorCounters {
+    /// Failed connection attempts
+    pub connect: u64,
+    /// Queries failed due to a transport error
+    pub transport: u64,
+    /// Lite server errors, except for "not ready"
+    pub lite_server: u64,
+    /// "Not ready" replies, including the retried ones
+    pub not_ready: u64,
+}
+
+/// Payload bytes of the queries and their responses, without ADNL framing
+#[derive(Debug, Copy, Clone, Default, Eq, PartialEq)]
+pub struct Traffic {
+    pub queries: u64,
+    pub bytes_sent: u64,
+    pub bytes_received: u64,
+}
+
+#[derive(Default)]
+pub struct ConnectionStats {
+    pub checkout: LatencyRecorder,
+    pub handshake: LatencyRecorder,
+    pub ping: LatencyRecorder,
+    pub traffic: TrafficRecorder,
+    pub errors: parking_lot::Mutex<ErrorCounters>,
+}
+
+impl ConnectionStats {
+    pub fn snapshot(&self) -> Stats {
+        Stats {
+            connections: 0,
+            idle_connections: 0,
+            checkout_latency: self.checkout.summary(),
+            handshake_latency: self.handshake.summary(),
+            ping_rtt: self.ping.summary(),
+            errors: *self.errors.lock(),
+            servers: Vec::new(),
+            traffic: self.traffic.totals(),
+            clock_skew: None,
+        }
+    }
+}
+
+#[derive(Default)]
+pub struct TrafficRecorder {
+    queries: parking_lot::Mutex<HashMap<&'static str, Traffic>>,
+}
+
+impl TrafficRecorder {
+    pub fn record(&self, query: &'static str, bytes_sent: usize, bytes_received: usize) {
+        let mut queries = self.queries.lock();
+        let traffic = queries.entry(query).or_default();
+        traffic.queries += 1;
+        traffic.bytes_sent += bytes_sent as u64;
+        traffic.bytes_received += bytes_received as u64;
+    }
+
+    pub fn totals(&self) -> HashMap<&'static str, Traffic> {
+        self.queries.lock().clone()
+    }
+}
+
+/// Keeps last `MAX_SAMPLES` measurements
+#[derive(Default)]
+pub struct LatencyRecorder {
+    samples: parking_lot::Mutex<VecDeque<Duration>>,
+}
+
+impl LatencyRecorder {
+    pub fn record(&self, sample: Duration) {
+        let mut samples = self.samples.lock();
+        if samples.len() >= MAX_SAMPLES {
+            samples.pop_front();
+        }
+        samples.push_back(sample);
+    }
+
+    pub fn summary(&self) -> LatencySummary {
+        let mut samples = self.samples.lock().iter().copied().collect::<Vec<_>>();
+        LatencySummary::from_samples(&mut samples)
+    }
+}
+
+const MAX_SAMPLES: usize = 1024;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn empty_summary() {
+        assert_eq!(LatencyRecorder::default().summary(), LatencySummary::default());
+    }
+
+    #[test]
+    fn percentiles() {
+        let recorder = LatencyRecorder::default();
+        for i in (1..=100).rev() {
+            recorder.record(Duration::from_millis(i));
+        }
+
+        let summary = recorder.summary();
+        assert_eq!(summary.count, 100);
+        assert_eq!(summary.p50, Duration::from_millis(50));
+        assert_eq!(summary.p90, Duration::from_millis(90));
+        assert_eq!(summary.p99, Duration::from_millis(99));
+        assert_eq!(summary.max, Duration::from_millis(100));
+    }
+
+    #[test]
+    fn summary_from_samples() {
+        let mut samples = vec![Duration::from_millis(30), Duration::from_millis(10), Duration::from_millis(20)];
+        let summary = LatencySummary::from_samples(&mut samples);
+        assert_eq!(summary.count, 3);
+        assert_eq!(summary.p50, Duration::from_millis(20));
+        assert_eq!(summary.p90, Duration::from_millis(20));
+        assert_eq!(summary.p99, Duration::from_millis(20));
+        assert_eq!(summary.max, Duration::from_millis(30));
+
+        let summary = LatencySummary::from_samples(&mut [Duration::from_millis(5)]);
+        assert_eq!(summary.p50, Duration::from_millis(5));
+        assert_eq!(summary.max, Duration::from_millis(5));
+
+        assert_eq!(LatencySummary::from_samples(&mut []), LatencySummary::default());
+    }
+
+    #[test]
+    fn traffic_totals() {
+        let recorder = TrafficRecorder::default();
+        recorder.record("GetAccountState", 10, 100);
+        recorder.record("GetAccountState", 10, 200);
+        recorder.record("GetTransactions", 20, 1000);
+
+        let totals = recorder.totals();
+        assert_eq!(
+            totals["GetAccountState"],
+            Traffic {
+                queries: 2,
+                bytes_sent: 20,
+                bytes_received: 300
+            }
+        );
+        assert_eq!(totals["GetTransactions"].queries, 1);
+    }
+
+    #[test]
+    fn keeps_last_samples() {
+        let recorder = LatencyRecorder::default();
+        for i in 0..(MAX_SAMPLES + 10) {
+            recorder.record(Duration::from_millis(i as u64));
+        }
+
+        let summary = recorder.summary();
+        assert_eq!(summary.count, MAX_SAMPLES);
+        assert_eq!(summary.max, Duration::from_millis((MAX_SAMPLES + 9) as u64));
+    }
+}
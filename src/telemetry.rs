@@ -0,0 +1,31 @@
+//! Metrics reported through the `metrics` facade
+
+use std::time::Duration;
+
+pub fn query(query: &'static str, bytes_sent: usize, bytes_received: usize) {
+    metrics::counter!("tonlib_queries_total", 1, "query" => query);
+    metrics::counter!("tonlib_bytes_sent_total", bytes_sent as u64, "query" => query);
+    metrics::counter!("tonlib_bytes_received_total", bytes_received as u64, "query" => query);
+}
+
+pub fn query_duration(query: &'static str, duration: Duration) {
+    metrics::histogram!("tonlib_query_duration_seconds", duration.as_secs_f64(), "query" => query);
+}
+
+pub fn transport_error() {
+    metrics::counter!("tonlib_transport_errors_total", 1);
+}
+
+pub fn lite_server_error(code: i32) {
+    metrics::counter!("tonlib_lite_server_errors_total", 1, "code" => code.to_string());
+}
+
+pub fn not_ready() {
+    metrics::counter!("tonlib_not_ready_total", 1);
+}
+
+pub fn checkout(duration: Duration, state: bb8::State) {
+    metrics::histogram!("tonlib_checkout_duration_seconds", duration.as_secs_f64());
+    metrics::gauge!("tonlib_pool_connections", state.connections as f64);
+    metrics::gauge!("tonlib_pool_idle_connections", state.idle_connections as f64);
+}
@@ -0,0 +1,63 @@
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+use std::time::Duration;
+
+use anyhow::Result;
+use async_trait::async_trait;
+use tiny_adnl::{AdnlTcpClient, AdnlTcpClientConfig};
+use ton_api::{ton, BoxedSerialize};
+
+/// ADNL session used by the connection pool
+#[async_trait]
+pub trait AdnlTransport: Send + Sync + 'static {
+    /// State shared by all sessions of the pool
+    type Context: Send + Sync + 'static;
+
+    async fn connect(config: AdnlTcpClientConfig, context: &Self::Context) -> Result<Arc<Self>>
+    where
+        Self: Sized;
+
+    async fn query(&self, query: &ton::TLObject) -> Result<ton::TLObject>;
+
+    /// Same as `query`, but also returns the size of the response in bytes.
+    ///
+    /// The default implementation serializes the response again,
+    /// transports with access to the raw answer should override it
+    async fn query_sized(&self, query: &ton::TLObject) -> Result<(ton::TLObject, usize)> {
+        let response = self.query(query).await?;
+        let size = response.boxed_serialized_bytes().map(|bytes| bytes.len()).unwrap_or_default();
+        Ok((response, size))
+    }
+
+    async fn ping(&self, timeout: Duration) -> Result<()>;
+
+    fn is_broken(&self) -> bool;
+
+    /// Marks session as broken, so it is dropped when returned to the pool
+    fn mark_broken(&self);
+}
+
+#[async_trait]
+impl AdnlTransport for AdnlTcpClient {
+    type Context = ();
+
+    async fn connect(config: AdnlTcpClientConfig, _: &Self::Context) -> Result<Arc<Self>> {
+        AdnlTcpClient::connect(config).await
+    }
+
+    async fn query(&self, query: &ton::TLObject) -> Result<ton::TLObject> {
+        AdnlTcpClient::query(self, query).await
+    }
+
+    async fn ping(&self, timeout: Duration) -> Result<()> {
+        AdnlTcpClient::ping(self, timeout).await.map(|_| ())
+    }
+
+    fn is_broken(&self) -> bool {
+        self.has_broken.load(Ordering::Acquire)
+    }
+
+    fn mark_broken(&self) {
+        self.has_broken.store(true, Ordering::Release);
+    }
+}
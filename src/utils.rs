@@ -1,7 +1,35 @@
-use ton_types::UInt256;
+use ton_block::MsgAddressInt;
+use ton_types::{Cell, UInt256};
 
 use crate::errors::*;
 
+/// Parses raw (`workchain:hex`) or user-friendly address.
+///
+/// Workchains which don't fit into `i8` are represented as `addr_var`
+pub fn parse_address(addr: &str) -> TonlibResult<MsgAddressInt> {
+    let (workchain, addr) = match addr.split_once(':') {
+        Some((workchain, addr)) => {
+            let workchain = workchain.parse::<i32>().map_err(|_| TonlibError::InvalidAddress)?;
+            let addr = hex::decode(addr).map_err(|_| TonlibError::InvalidAddress)?;
+            if addr.len() != 32 {
+                return Err(TonlibError::InvalidAddress);
+            }
+            (workchain, UInt256::from(addr.as_slice()))
+        }
+        None => {
+            let (_, workchain, addr) = unpack_address(addr)?;
+            (workchain as i32, addr)
+        }
+    };
+
+    let result = if workchain >= i8::MIN as i32 && workchain <= i8::MAX as i32 {
+        MsgAddressInt::with_standart(None, workchain as i8, addr.into())
+    } else {
+        MsgAddressInt::with_variant(None, workchain, addr.into())
+    };
+    result.map_err(|_| TonlibError::InvalidAddress)
+}
+
 pub fn unpack_address(addr: &str) -> TonlibResult<(bool, i8, UInt256)> {
     let bytes = base64::decode(addr).map_err(|_| TonlibError::InvalidAddress)?;
     if bytes.len() != 36 {
@@ -14,6 +42,106 @@ pub fn unpack_address(addr: &str) -> TonlibResult<(bool, i8, UInt256)> {
     Ok((bounceable, workchain, addr))
 }
 
+/// Packs address into the user-friendly form
+pub fn pack_address(workchain: i8, addr: &UInt256, bounceable: bool, testnet: bool, url_safe: bool) -> String {
+    let mut bytes = Vec::with_capacity(36);
+    let tag = if bounceable { 0x11u8 } else { 0x51u8 };
+    bytes.push(if testnet { tag | 0x80 } else { tag });
+    bytes.push(workchain as u8);
+    bytes.extend_from_slice(addr.as_slice());
+    let crc = crc16(&bytes);
+    bytes.extend_from_slice(&crc.to_be_bytes());
+
+    if url_safe {
+        base64::encode_config(&bytes, base64::URL_SAFE)
+    } else {
+        base64::encode(&bytes)
+    }
+}
+
+/// CRC16-XMODEM used in the user-friendly addresses
+fn crc16(data: &[u8]) -> u16 {
+    data.iter().fold(0u16, |crc, &byte| {
+        (0..8).fold(crc ^ ((byte as u16) << 8), |crc, _| {
+            if crc & 0x8000 != 0 {
+                (crc << 1) ^ 0x1021
+            } else {
+                crc << 1
+            }
+        })
+    })
+}
+
+/// Follows the path of reference indices from the root cell
+pub fn extract_cell(root: &Cell, path: &[usize]) -> TonlibResult<Cell> {
+    path.iter().try_fold(root.clone(), |cell, &index| {
+        cell.reference(index).map_err(|_| TonlibError::InvalidBoc)
+    })
+}
+
+/// Extracts the sub-cell by the path of reference indices and serializes it as a standalone BOC
+pub fn extract_boc(boc: &[u8], path: &[usize]) -> TonlibResult<Vec<u8>> {
+    let root = ton_types::deserialize_tree_of_cells(&mut std::io::Cursor::new(boc)).map_err(|_| TonlibError::InvalidBoc)?;
+    let cell = extract_cell(&root, path)?;
+    ton_types::serialize_toc(&cell).map_err(|_| TonlibError::InvalidBoc)
+}
+
+/// Message from the outbound message queue of the shard
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct QueuedMessage {
+    /// Message hash
+    pub hash: UInt256,
+    /// Workchain of the next hop
+    pub next_workchain: i32,
+    /// Address prefix of the next hop
+    pub next_addr_prefix: u64,
+    /// Logical time of the message creation. `None` for external messages
+    pub created_lt: Option<u64>,
+    /// Logical time at which the message was put into the queue
+    pub enqueued_lt: u64,
+    /// Forwarding fee left for the remaining hops
+    pub fwd_fee_remaining: u128,
+}
+
+/// First 64 bits of the account address, used as its shard prefix
+pub fn account_prefix(addr: &UInt256) -> u64 {
+    let mut prefix = [0; 8];
+    prefix.copy_from_slice(&addr.as_slice()[..8]);
+    u64::from_be_bytes(prefix)
+}
+
+/// Checks whether the shard (tagged prefix) contains the account with the specified prefix
+pub fn shard_contains_account(shard: u64, account_prefix: u64) -> bool {
+    let tag = shard & shard.wrapping_neg();
+    let mask = !tag.wrapping_sub(1) ^ tag;
+    shard & mask == account_prefix & mask
+}
+
+/// Parses the outbound message queue of the shard state
+pub fn parse_out_msg_queue(state: &ton_block::ShardStateUnsplit) -> TonlibResult<Vec<QueuedMessage>> {
+    use ton_block::HashmapAugType;
+
+    let info = state.read_out_msg_queue_info().map_err(|_| TonlibError::InvalidShardState)?;
+
+    let mut messages = Vec::new();
+    info.out_queue()
+        .iterate_with_keys(|key: ton_block::OutMsgQueueKey, enqueued: ton_block::EnqueuedMsg| {
+            let envelope = enqueued.read_out_msg()?;
+            let message = envelope.read_message()?;
+            messages.push(QueuedMessage {
+                hash: key.hash,
+                next_workchain: key.workchain_id,
+                next_addr_prefix: key.prefix,
+                created_lt: message.int_header().map(|header| header.created_lt),
+                enqueued_lt: enqueued.enqueued_lt(),
+                fwd_fee_remaining: envelope.fwd_fee_remaining().0,
+            });
+            Ok(true)
+        })
+        .map_err(|_| TonlibError::InvalidShardState)?;
+    Ok(messages)
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -25,6 +153,45 @@ mod tests {
         ])
     }
 
+    #[test]
+    fn parse_raw_address() {
+        let addr = parse_address("-1:3333333333333333333333333333333333333333333333333333333333333333").unwrap();
+        assert_eq!(addr.get_workchain_id(), -1);
+        assert_eq!(UInt256::from(addr.get_address().get_bytestring(0)), elector_addr());
+
+        let addr = parse_address("1000:3333333333333333333333333333333333333333333333333333333333333333").unwrap();
+        assert_eq!(addr.get_workchain_id(), 1000);
+        assert_eq!(UInt256::from(addr.get_address().get_bytestring(0)), elector_addr());
+
+        assert!(parse_address("0:33").is_err());
+    }
+
+    #[test]
+    fn parse_user_friendly_address() {
+        let addr = parse_address("Ef8zMzMzMzMzMzMzMzMzMzMzMzMzMzMzMzMzMzMzMzMzM0vF").unwrap();
+        assert_eq!(addr.get_workchain_id(), -1);
+    }
+
+    #[test]
+    fn extract_sub_cell() {
+        let mut leaf = ton_types::BuilderData::new();
+        leaf.append_u32(42).unwrap();
+        let leaf = leaf.into_cell().unwrap();
+
+        let mut middle = ton_types::BuilderData::new();
+        middle.append_reference_cell(Cell::default());
+        middle.append_reference_cell(leaf.clone());
+        let mut root = ton_types::BuilderData::new();
+        root.append_reference_cell(middle.into_cell().unwrap());
+        let boc = ton_types::serialize_toc(&root.into_cell().unwrap()).unwrap();
+
+        let extracted = extract_boc(&boc, &[0, 1]).unwrap();
+        let cell = ton_types::deserialize_tree_of_cells(&mut std::io::Cursor::new(&extracted)).unwrap();
+        assert_eq!(cell.repr_hash(), leaf.repr_hash());
+
+        assert!(matches!(extract_boc(&boc, &[0, 2]), Err(TonlibError::InvalidBoc)));
+    }
+
     #[test]
     fn unpack_bounceable() {
         let addr = "Ef8zMzMzMzMzMzMzMzMzMzMzMzMzMzMzMzMzMzMzMzMzM0vF";
@@ -34,6 +201,35 @@ mod tests {
         assert_eq!(addr, elector_addr());
     }
 
+    #[test]
+    fn pack_user_friendly_address() {
+        assert_eq!(
+            pack_address(-1, &elector_addr(), true, false, false),
+            "Ef8zMzMzMzMzMzMzMzMzMzMzMzMzMzMzMzMzMzMzMzMzM0vF"
+        );
+        assert_eq!(
+            pack_address(-1, &elector_addr(), false, false, true),
+            "Uf8zMzMzMzMzMzMzMzMzMzMzMzMzMzMzMzMzMzMzMzMzMxYA"
+        );
+
+        let (bounceable, workchain, addr) = unpack_address(&pack_address(0, &elector_addr(), true, true, false)).unwrap();
+        assert!(bounceable);
+        assert_eq!(workchain, 0);
+        assert_eq!(addr, elector_addr());
+    }
+
+    #[test]
+    fn shard_account_prefix() {
+        let prefix = account_prefix(&elector_addr());
+        assert_eq!(prefix, 0x3333333333333333);
+
+        assert!(shard_contains_account(ton_block::SHARD_FULL, prefix));
+        assert!(shard_contains_account(0x2000000000000000, prefix));
+        assert!(shard_contains_account(0x3800000000000000, prefix));
+        assert!(!shard_contains_account(0xa000000000000000, prefix));
+        assert!(!shard_contains_account(0x2800000000000000, prefix));
+    }
+
     #[test]
     fn unpack_non_bounceable() {
         let addr = "Uf8zMzMzMzMzMzMzMzMzMzMzMzMzMzMzMzMzMzMzMzMzMxYA";